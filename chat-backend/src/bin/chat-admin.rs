@@ -0,0 +1,588 @@
+use std::error::Error;
+use std::path::PathBuf;
+use chat_backend::db::{self, models::*};
+use chat_backend::handlers::api::{CreateRoomRequest, CreateUserRequest, Page};
+use chat_backend::handlers::rooms::audit_state;
+use chat_backend::handlers::validation::{field_errors, ROOM_TYPES};
+use chat_backend::models::audit::{self, Change, RequestOrigin};
+use chat_backend::models::permissions::Role;
+use chat_backend::retention::{self, PruneOutcome};
+use chat_backend::settings::{self, RetentionMode, Settings};
+use chat_backend::storage;
+use chat_backend::utils::password::hash_password;
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+use deadpool_postgres::Object;
+use dotenv::dotenv;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+use serde_json::json;
+use validator::Validate;
+
+type CliResult<T = ()> = Result<T, Box<dyn Error>>;
+
+// Works on the server's database directly, with the same config file and environment
+#[derive(Parser, Debug)]
+#[command(name = "chat-admin", about = "Manage a chat-backend server's users, rooms and data")]
+struct Cli {
+    #[arg(short, long, env = "CHAT_CONFIG", value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+    #[arg(long, value_name = "URL", global = true)]
+    database_url: Option<String>,
+    #[arg(long, global = true, help = "Print results as JSON")]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(subcommand, about = "Create users, reset passwords and manage server admins")]
+    Users(UserCommand),
+    #[command(subcommand, about = "List, create and delete rooms")]
+    Rooms(RoomCommand),
+    #[command(subcommand, about = "Add people to rooms and remove them")]
+    Members(MemberCommand),
+    #[command(about = "Create or update the database tables")]
+    Migrate,
+    #[command(about = "Delete or archive old messages, as retention.mode says")]
+    Prune(PruneArgs),
+    #[command(about = "Print counts of users, rooms, messages and storage")]
+    Stats,
+}
+
+#[derive(Subcommand, Debug)]
+enum UserCommand {
+    List {
+        #[arg(long, help = "Match usernames and display names")]
+        search: Option<String>,
+        #[arg(long, help = "Only server admins")]
+        admins: bool,
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+    },
+    #[command(about = "Create a user; a password is generated unless one is given")]
+    Create {
+        username: String,
+        #[arg(long, env = "CHAT_ADMIN_PASSWORD", hide_env_values = true)]
+        password: Option<String>,
+        #[arg(long, help = "Make the user a server admin")]
+        admin: bool,
+    },
+    #[command(about = "Set a new password and sign the user out everywhere")]
+    ResetPassword {
+        username: String,
+        #[arg(long, env = "CHAT_ADMIN_PASSWORD", hide_env_values = true)]
+        password: Option<String>,
+    },
+    #[command(about = "Make a user a server admin")]
+    Promote { username: String },
+    #[command(about = "Take server admin rights away")]
+    Demote { username: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum RoomCommand {
+    List {
+        #[arg(long, help = "Match room names")]
+        search: Option<String>,
+        #[arg(long = "type", value_parser = ROOM_TYPES)]
+        room_type: Option<String>,
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+    },
+    Create {
+        name: String,
+        #[arg(long, help = "Username of the room's owner")]
+        owner: String,
+        #[arg(long = "type", default_value = "public", value_parser = ROOM_TYPES)]
+        room_type: String,
+        #[arg(long, env = "CHAT_ADMIN_ROOM_PASSWORD", hide_env_values = true, help = "Required for protected rooms")]
+        password: Option<String>,
+    },
+    #[command(about = "Delete a room with its messages and attachments")]
+    Delete { room_id: i32 },
+}
+
+#[derive(Subcommand, Debug)]
+enum MemberCommand {
+    Add {
+        room_id: i32,
+        username: String,
+        #[arg(long, default_value = "member", value_parser = parse_role)]
+        role: Role,
+    },
+    Remove { room_id: i32, username: String },
+}
+
+#[derive(Args, Debug)]
+struct PruneArgs {
+    #[arg(long, value_name = "DAYS", value_parser = clap::value_parser!(u32).range(1..=settings::MAX_RETENTION_DAYS as i64))]
+    older_than: u32,
+    #[arg(long, value_name = "ROOM_ID", help = "Only this room; every room by default")]
+    room: Option<i32>,
+    #[arg(long, help = "Only count what would be deleted")]
+    dry_run: bool,
+}
+
+fn parse_role(value: &str) -> Result<Role, String> {
+    Role::parse(value).ok_or_else(|| {
+        let roles: Vec<&str> = Role::ALL.iter().map(|role| role.as_str()).collect();
+        format!("must be one of: {}", roles.join(", "))
+    })
+}
+
+#[actix_web::main]
+async fn main() {
+    dotenv().ok();
+    // Only problems; the server's log level would bury the output
+    env_logger::Builder::new().parse_filters("warn").init();
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("chat-admin: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> CliResult {
+    let settings = settings::load(&settings::Cli {
+        config: cli.config.clone(),
+        database_url: cli.database_url.clone(),
+        ..Default::default()
+    })?;
+    let pool = db::create_pool(&settings.database)?;
+    let mut client = pool.get().await?;
+    let out = Output { json: cli.json };
+
+    match cli.command {
+        Command::Users(command) => users(&mut client, &settings, out, command).await,
+        Command::Rooms(command) => rooms(&mut client, &settings, out, command).await,
+        Command::Members(command) => members(&mut client, out, command).await,
+        Command::Migrate => {
+            db::schema::create_tables(&client).await?;
+            out.print(&json!({ "migrated": true }), |_| "Database tables created or verified".to_string())
+        }
+        Command::Prune(args) => prune(&client, &settings, out, args).await,
+        Command::Stats => {
+            let stats = ServerStats::collect(&client).await?;
+            out.print(&stats, stats_text)
+        }
+    }
+}
+
+// Changes made here are audited without an actor
+fn origin() -> RequestOrigin {
+    RequestOrigin { ip: None, request_id: None }
+}
+
+#[derive(Clone, Copy)]
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    // Pretty JSON with --json, otherwise whatever `text` makes of the value
+    fn print<T: Serialize>(self, value: &T, text: impl FnOnce(&T) -> String) -> CliResult {
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(value)?);
+        } else {
+            println!("{}", text(value));
+        }
+        Ok(())
+    }
+}
+
+// Left-aligned columns separated by two spaces
+fn table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell)).collect();
+        padded.join("  ").trim_end().to_string()
+    };
+    std::iter::once(line(headers.to_vec()))
+        .chain(rows.iter().map(|row| line(row.iter().map(String::as_str).collect())))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn listing<T>(page: &Page<T>, noun: &str, headers: &[&str], row: impl Fn(&T) -> Vec<String>) -> String {
+    if page.items.is_empty() {
+        return format!("No {}", noun);
+    }
+    let rows: Vec<Vec<String>> = page.items.iter().map(row).collect();
+    let mut text = table(headers, &rows);
+    if page.total > page.items.len() as i64 {
+        text.push_str(&format!("\n({} of {} {}; raise --limit to see more)", page.items.len(), page.total, noun));
+    }
+    text
+}
+
+fn date(time: Option<DateTime<Utc>>) -> String {
+    time.map(|t| t.format("%Y-%m-%d %H:%M").to_string()).unwrap_or_else(|| "-".to_string())
+}
+
+fn bytes(count: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = count as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", count),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+// The API's rules for the same fields, reported one per line
+fn check(request: &impl Validate) -> CliResult {
+    request.validate().map_err(|errors| {
+        field_errors(&errors)
+            .iter()
+            .map(|e| e.message.clone())
+            .collect::<Vec<_>>()
+            .join("\n")
+            .into()
+    })
+}
+
+fn random_password() -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(20).map(char::from).collect()
+}
+
+async fn find_user(client: &Object, username: &str) -> CliResult<User> {
+    User::find_by_username(client, username)
+        .await?
+        .ok_or_else(|| format!("there is no user named {}", username).into())
+}
+
+async fn find_room(client: &Object, room_id: i32) -> CliResult<Room> {
+    Room::find_by_id(client, room_id)
+        .await?
+        .ok_or_else(|| format!("there is no room {}", room_id).into())
+}
+
+// A new or reset password is only shown when it was generated here
+#[derive(Serialize)]
+struct Credentials {
+    id: i32,
+    username: String,
+    is_admin: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+async fn users(client: &mut Object, settings: &Settings, out: Output, command: UserCommand) -> CliResult {
+    match command {
+        UserCommand::List { search, admins, limit } => {
+            let filter = UserFilter { search, admin: admins.then_some(true), suspended: None };
+            let (items, total) = UserAccount::find(client, &filter, limit, 0).await?;
+            let page = Page { items, total, limit, offset: 0 };
+            out.print(&page, |page| {
+                listing(page, "users", &["ID", "USERNAME", "ADMIN", "ROOMS", "LAST SEEN", "STATUS"], |user| {
+                    vec![
+                        user.id.to_string(),
+                        user.username.clone(),
+                        if user.is_admin { "yes" } else { "" }.to_string(),
+                        user.room_count.to_string(),
+                        date(user.last_seen_at),
+                        if user.suspended_at.is_some() { "suspended" } else { "" }.to_string(),
+                    ]
+                })
+            })
+        }
+        UserCommand::Create { username, password, admin } => {
+            let generated = password.is_none();
+            let password = password.unwrap_or_else(random_password);
+            check(&CreateUserRequest { username: username.clone(), password: Some(password.clone()), avatar_url: None })?;
+            if User::find_by_username(client, &username).await?.is_some() {
+                return Err(format!("the username {} is taken", username).into());
+            }
+
+            let new_user = User {
+                id: None,
+                username,
+                password_hash: Some(hash_password(&password)),
+                avatar_url: None,
+                created_at: None,
+                is_admin: false,
+                suspended_at: None,
+            };
+            let user = User::create(client, &new_user).await?;
+            let user_id = user.id.unwrap_or_default();
+            if admin {
+                UserAccount::update(client, user_id, &AccountUpdate { is_admin: Some(true), ..Default::default() }).await?;
+            }
+            let change = Change::new("user.create", "user", user_id)
+                .after(&json!({ "username": user.username, "is_admin": admin }));
+            audit::record(client, &origin(), None, change).await;
+
+            let created = Credentials {
+                id: user_id,
+                username: user.username,
+                is_admin: admin,
+                password: generated.then_some(password),
+            };
+            out.print(&created, |created| {
+                let mut text = format!("Created user {} (id {})", created.username, created.id);
+                if created.is_admin {
+                    text.push_str(" as a server admin");
+                }
+                if let Some(password) = &created.password {
+                    text.push_str(&format!("\nPassword: {}", password));
+                }
+                text
+            })
+        }
+        UserCommand::ResetPassword { username, password } => {
+            let generated = password.is_none();
+            let password = password.unwrap_or_else(random_password);
+            check(&CreateUserRequest { username: username.clone(), password: Some(password.clone()), avatar_url: None })?;
+            let user = find_user(client, &username).await?;
+            let user_id = user.id.unwrap_or_default();
+            User::set_password(client, user_id, &hash_password(&password)).await?;
+            audit::record(client, &origin(), None, Change::new("user.reset_password", "user", user_id)).await;
+
+            let reset = Credentials {
+                id: user_id,
+                username: user.username,
+                is_admin: user.is_admin,
+                password: generated.then_some(password),
+            };
+            out.print(&reset, |reset| {
+                let mut text = format!("Reset the password of {}; their sessions were signed out", reset.username);
+                if let Some(password) = &reset.password {
+                    text.push_str(&format!("\nPassword: {}", password));
+                }
+                text
+            })
+        }
+        UserCommand::Promote { username } => set_admin(client, out, &username, true).await.map(|_| ()),
+        UserCommand::Demote { username } => {
+            let user_id = set_admin(client, out, &username, false).await?;
+            // The server grants these again when it starts
+            if settings.admin.user_ids.contains(&user_id) {
+                eprintln!("warning: {} is listed in admin.user_ids and will be promoted again at startup", username);
+            }
+            Ok(())
+        }
+    }
+}
+
+// Returns the user's id
+async fn set_admin(client: &Object, out: Output, username: &str, is_admin: bool) -> CliResult<i32> {
+    let user = find_user(client, username).await?;
+    let user_id = user.id.unwrap_or_default();
+    let account = UserAccount::update(client, user_id, &AccountUpdate { is_admin: Some(is_admin), ..Default::default() })
+        .await?
+        .ok_or_else(|| format!("there is no user named {}", username))?;
+    if user.is_admin != is_admin {
+        let action = if is_admin { "user.grant_admin" } else { "user.revoke_admin" };
+        let change = Change::new(action, "user", user_id)
+            .before(&json!({ "is_admin": user.is_admin }))
+            .after(&json!({ "is_admin": is_admin }));
+        audit::record(client, &origin(), None, change).await;
+    }
+
+    out.print(&account, |account| match (user.is_admin == is_admin, is_admin) {
+        (true, true) => format!("{} is already a server admin", account.username),
+        (true, false) => format!("{} is not a server admin", account.username),
+        (false, true) => format!("{} is now a server admin", account.username),
+        (false, false) => format!("{} is no longer a server admin", account.username),
+    })?;
+    Ok(user_id)
+}
+
+async fn rooms(client: &mut Object, settings: &Settings, out: Output, command: RoomCommand) -> CliResult {
+    match command {
+        RoomCommand::List { search, room_type, limit } => {
+            let filter = RoomFilter { viewer_id: None, search, room_type, sort: RoomSort::Name, include_private: true };
+            let (items, total) = RoomSummary::find(client, &filter, limit, 0).await?;
+            let page = Page { items, total, limit, offset: 0 };
+            out.print(&page, |page| {
+                listing(page, "rooms", &["ID", "NAME", "TYPE", "MEMBERS", "LAST ACTIVITY", "STATUS"], |room| {
+                    vec![
+                        room.id.to_string(),
+                        room.name.clone(),
+                        room.room_type.clone(),
+                        room.member_count.to_string(),
+                        date(room.last_activity_at),
+                        if room.archived_at.is_some() { "archived" } else { "" }.to_string(),
+                    ]
+                })
+            })
+        }
+        RoomCommand::Create { name, owner, room_type, password } => {
+            let request = CreateRoomRequest { name: name.trim().to_string(), room_type, password };
+            check(&request)?;
+            let owner = find_user(client, &owner).await?;
+            let owner_id = owner.id.unwrap_or_default();
+
+            let new_room = Room {
+                id: None,
+                name: request.name.clone(),
+                type_: request.room_type.clone(),
+                password_hash: request.password.as_deref().map(hash_password),
+                created_by: Some(owner_id),
+                created_at: None,
+                description: None,
+                topic: None,
+                archived_at: None,
+                retention_days: None,
+            };
+            let room = Room::create(client, &new_room).await?;
+            let room_id = room.id.unwrap_or_default();
+            let change = Change::new("room.create", "room", room_id).room(room_id).after(&audit_state(&room));
+            audit::record(client, &origin(), None, change).await;
+
+            let created = PublicRoom::from(&room);
+            out.print(&created, |room| {
+                format!("Created {} room {} (id {}) owned by {}", room.room_type, room.name, room_id, owner.username)
+            })
+        }
+        RoomCommand::Delete { room_id } => {
+            let room = find_room(client, room_id).await?;
+            let storage = storage::from_settings(&settings.storage)?;
+            // Collect blob keys first; the rows disappear with the room
+            let storage_keys = Attachment::storage_keys_for_room(client, room_id).await?;
+            Room::delete(client, room_id).await?;
+            let change = Change::new("room.delete", "room", room_id).room(room_id).before(&audit_state(&room));
+            audit::record(client, &origin(), None, change).await;
+
+            let failed = delete_blobs(storage.as_ref(), &storage_keys).await;
+            let deleted = json!({ "id": room_id, "name": room.name, "files_deleted": storage_keys.len() - failed });
+            out.print(&deleted, |_| format!("Deleted room {} (id {})", room.name, room_id))
+        }
+    }
+}
+
+// Returns how many could not be deleted; they are logged and left behind
+async fn delete_blobs(storage: &dyn storage::Storage, keys: &[String]) -> usize {
+    let mut failed = 0;
+    for key in keys {
+        if let Err(e) = storage.delete(key).await {
+            log::error!("Failed to delete attachment {}: {}", key, e);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+async fn members(client: &mut Object, out: Output, command: MemberCommand) -> CliResult {
+    match command {
+        MemberCommand::Add { room_id, username, role } => {
+            let room = find_room(client, room_id).await?;
+            let user = find_user(client, &username).await?;
+            let user_id = user.id.unwrap_or_default();
+            if !Room::join_room(client, user_id, room_id, role.as_str()).await? {
+                return Err(format!("{} is already a member of {}", username, room.name).into());
+            }
+            let change = Change::new("member.add", "user", user_id).room(room_id).after(&json!({ "role": role }));
+            audit::record(client, &origin(), None, change).await;
+
+            let member = Room::member(client, room_id, user_id).await?;
+            out.print(&member, |_| format!("Added {} to {} as {}", username, room.name, role.as_str()))
+        }
+        MemberCommand::Remove { room_id, username } => {
+            let room = find_room(client, room_id).await?;
+            let user = find_user(client, &username).await?;
+            let user_id = user.id.unwrap_or_default();
+            let not_member = || format!("{} is not a member of {}", username, room.name);
+            let member = Room::member(client, room_id, user_id).await?.ok_or_else(not_member)?;
+            match Room::remove_member(client, room_id, user_id).await? {
+                MemberChange::Done => {}
+                MemberChange::NotMember => return Err(not_member().into()),
+                MemberChange::LastOwner => {
+                    return Err(format!("{} is the only owner of {}; make someone else owner first", username, room.name).into())
+                }
+            }
+            let change = Change::new("member.remove", "user", user_id).room(room_id).before(&json!({ "role": member.role }));
+            audit::record(client, &origin(), None, change).await;
+
+            out.print(&member, |_| format!("Removed {} from {}", username, room.name))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PruneReport {
+    before: DateTime<Utc>,
+    room_id: Option<i32>,
+    mode: RetentionMode,
+    dry_run: bool,
+    #[serde(flatten)]
+    outcome: PruneOutcome,
+}
+
+async fn prune(client: &Object, settings: &Settings, out: Output, args: PruneArgs) -> CliResult {
+    if let Some(room_id) = args.room {
+        find_room(client, room_id).await?;
+    }
+    let before = retention::cutoff(args.older_than.into()).ok_or("--older-than reaches too far back")?;
+    let mode = settings.retention.mode;
+    let mut report = PruneReport { before, room_id: args.room, mode, dry_run: args.dry_run, outcome: PruneOutcome::default() };
+
+    if args.dry_run {
+        report.outcome.messages = Message::count_older_than(client, before, args.room).await? as u64;
+    } else {
+        let storage = storage::from_settings(&settings.storage)?;
+        report.outcome = retention::prune(client, storage.as_ref(), before, args.room, &settings.retention).await?;
+        if report.outcome.messages > 0 {
+            let target = args.room.map_or("all".to_string(), |room_id| room_id.to_string());
+            let mut change = Change::new("message.prune", "room", target)
+                .after(&json!({ "before": before, "mode": mode, "messages": report.outcome.messages }));
+            change.room_id = args.room;
+            audit::record(client, &origin(), None, change).await;
+        }
+    }
+
+    out.print(&report, |report| {
+        let scope = report.room_id.map_or("all rooms".to_string(), |room_id| format!("room {}", room_id));
+        let verb = match (report.dry_run, report.mode) {
+            (true, RetentionMode::Delete) => "Would delete",
+            (true, RetentionMode::Archive) => "Would archive",
+            (false, RetentionMode::Delete) => "Deleted",
+            (false, RetentionMode::Archive) => "Archived",
+        };
+        format!("{} {} messages sent before {} in {}", verb, report.outcome.messages, date(Some(report.before)), scope)
+    })
+}
+
+fn stats_text(stats: &ServerStats) -> String {
+    let rows = [
+        ("Users", format!("{} ({} admins, {} suspended)", stats.users, stats.admins, stats.suspended_users)),
+        ("Rooms", format!("{} ({} archived)", stats.rooms, stats.archived_rooms)),
+        ("Memberships", stats.memberships.to_string()),
+        ("Messages", format!("{} ({} in the last day)", stats.messages, stats.messages_last_day)),
+        ("Oldest message", date(stats.oldest_message_at)),
+        ("Attachments", format!("{} ({})", stats.attachments, bytes(stats.attachment_bytes))),
+        ("Open reports", stats.open_reports.to_string()),
+        ("Database size", bytes(stats.database_bytes)),
+    ];
+    rows.iter().map(|(label, value)| format!("{:<16}{}", label, value)).collect::<Vec<_>>().join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_line_up_columns() {
+        let row = |cells: [&str; 3]| cells.map(str::to_string).to_vec();
+        let rows = vec![row(["1", "alice", ""]), row(["23", "bo", "yes"])];
+        assert_eq!(table(&["ID", "NAME", "ADMIN"], &rows), "ID  NAME   ADMIN\n1   alice\n23  bo     yes");
+    }
+
+    #[test]
+    fn sizes_are_readable() {
+        assert_eq!(bytes(512), "512 B");
+        assert_eq!(bytes(3366), "3.3 KiB");
+        assert_eq!(bytes(8 * 1024 * 1024 + 300 * 1024), "8.3 MiB");
+    }
+}
@@ -1,19 +1,25 @@
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc, NaiveDateTime};
-use tokio_postgres::{Client, Error};
+use tokio_postgres::{types::ToSql, Client, Error, Row, RowStream, Transaction};
+use rand::Rng;
+use std::collections::HashMap;
 
-// User Model
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// User Model; holds the password hash, so it is never serialized directly
+#[derive(Debug, Clone)]
 pub struct User {
     pub id: Option<i32>,
     pub username: String,
     pub password_hash: Option<String>,
     pub avatar_url: Option<String>,
     pub created_at: Option<DateTime<Utc>>,
+    // Server-wide administrator, not tied to any room
+    pub is_admin: bool,
+    // Suspended accounts can't sign in or use their tokens
+    pub suspended_at: Option<DateTime<Utc>>,
 }
 
-// Room Model
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// Room Model; holds the password hash, so it is never serialized directly
+#[derive(Debug, Clone)]
 pub struct Room {
     pub id: Option<i32>,
     pub name: String,
@@ -21,6 +27,74 @@ pub struct Room {
     pub password_hash: Option<String>,
     pub created_by: Option<i32>,
     pub created_at: Option<DateTime<Utc>>,
+    pub description: Option<String>,
+    pub topic: Option<String>,
+    // Archived rooms are read-only
+    pub archived_at: Option<DateTime<Utc>>,
+    // Days messages are kept: None follows retention.default_days, 0 keeps them forever
+    pub retention_days: Option<i32>,
+}
+
+// A user as returned by the API
+#[derive(Serialize, Debug, Clone)]
+pub struct PublicUser {
+    pub id: Option<i32>,
+    pub username: String,
+    pub avatar_url: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl From<&User> for PublicUser {
+    fn from(user: &User) -> Self {
+        PublicUser {
+            id: user.id,
+            username: user.username.clone(),
+            avatar_url: user.avatar_url.clone(),
+            created_at: user.created_at,
+        }
+    }
+}
+
+// A room as returned by the API and pushed to clients
+#[derive(Serialize, Debug, Clone)]
+pub struct PublicRoom {
+    pub id: Option<i32>,
+    pub name: String,
+    pub room_type: String,
+    pub description: Option<String>,
+    pub topic: Option<String>,
+    pub created_by: Option<i32>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub archived_at: Option<DateTime<Utc>>,
+    pub retention_days: Option<i32>,
+}
+
+impl From<&Room> for PublicRoom {
+    fn from(room: &Room) -> Self {
+        PublicRoom {
+            id: room.id,
+            name: room.name.clone(),
+            room_type: room.type_.clone(),
+            description: room.description.clone(),
+            topic: room.topic.clone(),
+            created_by: room.created_by,
+            created_at: room.created_at,
+            archived_at: room.archived_at,
+            retention_days: room.retention_days,
+        }
+    }
+}
+
+// Changes to a room's settings; `None` leaves a field alone, `Some(None)` clears it
+#[derive(Debug, Clone, Default)]
+pub struct RoomUpdate {
+    pub name: Option<String>,
+    pub description: Option<Option<String>>,
+    pub topic: Option<Option<String>>,
+    pub type_: Option<String>,
+    pub password_hash: Option<Option<String>>,
+    pub archived: Option<bool>,
+    pub retention_days: Option<Option<i32>>,
 }
 
 // Message Model
@@ -28,19 +102,386 @@ pub struct Room {
 pub struct Message {
     pub id: Option<i32>,
     pub room_id: i32,
-    pub sender_id: i32,
+    // None once the sender's account is deleted and their messages anonymized
+    pub sender_id: Option<i32>,
+    pub content: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// Public profile of a user
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserProfile {
+    pub id: i32,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
+    pub status_text: Option<String>,
+    pub status_expires_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// A user as seen by server admins
+#[derive(Serialize, Debug, Clone)]
+pub struct UserAccount {
+    pub id: i32,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub is_admin: bool,
+    pub suspended_at: Option<DateTime<Utc>>,
+    pub suspension_reason: Option<String>,
+    pub room_count: i64,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// Narrows the account listing; `None` fields match everyone
+#[derive(Debug, Clone, Default)]
+pub struct UserFilter {
+    // Matched against the username and display name
+    pub search: Option<String>,
+    pub admin: Option<bool>,
+    pub suspended: Option<bool>,
+}
+
+// Changes an admin makes to an account; `None` leaves a field alone
+#[derive(Debug, Clone, Default)]
+pub struct AccountUpdate {
+    pub is_admin: Option<bool>,
+    pub suspended: Option<bool>,
+    // Stored when suspending, cleared when lifting a suspension
+    pub suspension_reason: Option<String>,
+}
+
+// A room read from another chat service's export, ready to be written in one transaction
+#[derive(Debug, Clone)]
+pub struct RoomImport {
+    pub name: String,
+    pub room_type: &'static str,
+    pub topic: Option<String>,
+    // Author ids from the export; senders become members whether listed here or not
+    pub members: Vec<String>,
+    // Oldest first
+    pub messages: Vec<ImportedMessage>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportedMessage {
+    // The author's id in the export
+    pub sender: String,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// Who wrote an export's messages, keyed by the ids the export gives them
+#[derive(Debug, Clone, Default)]
+pub struct ImportAuthors {
+    // e.g. "slack"; placeholder accounts are named after it
+    pub source: &'static str,
+    // A name unique within the export, used for placeholder accounts and author mappings
+    pub names: HashMap<String, String>,
+    // Authors an admin mapped to an existing account
+    pub mapped: HashMap<String, i32>,
+}
+
+// What importing a room wrote, or would have written on a dry run
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RoomImportResult {
+    // None on a dry run
+    pub room_id: Option<i32>,
+    // Placeholder users for authors that were not mapped to an account here
+    pub users_created: Vec<String>,
+    pub members_added: u64,
+    pub messages_imported: u64,
+}
+
+// What went with a deleted user
+#[derive(Debug, Clone, Default)]
+pub struct DeletedUser {
+    pub username: String,
+    // Rooms the user owned alone and shared with nobody
+    pub deleted_rooms: Vec<i32>,
+    // (room, new owner) for rooms the user owned alone
+    pub new_owners: Vec<(i32, i32)>,
+    // Attachment blobs to remove from storage
+    pub storage_keys: Vec<String>,
+}
+
+// What one round of message pruning removed
+#[derive(Debug, Clone, Default)]
+pub struct PrunedMessages {
+    pub messages: u64,
+    // Blobs of the attachments that went with them, to remove from storage
+    pub storage_keys: Vec<String>,
+}
+
+// Server-wide counts for operators
+#[derive(Serialize, Debug, Clone)]
+pub struct ServerStats {
+    pub users: i64,
+    pub admins: i64,
+    pub suspended_users: i64,
+    pub rooms: i64,
+    pub archived_rooms: i64,
+    pub memberships: i64,
+    pub messages: i64,
+    pub messages_last_day: i64,
+    pub oldest_message_at: Option<DateTime<Utc>>,
+    pub attachments: i64,
+    pub attachment_bytes: i64,
+    pub open_reports: i64,
+    pub database_bytes: i64,
+}
+
+// Everything a user can download about themselves
+#[derive(Serialize, Debug, Clone)]
+pub struct UserExport {
+    pub exported_at: DateTime<Utc>,
+    pub profile: UserProfile,
+    pub memberships: Vec<ExportedMembership>,
+    pub messages: Vec<ExportedMessage>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ExportedMembership {
+    pub room_id: i32,
+    pub room_name: String,
+    pub role: String,
+    pub joined_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ExportedMessage {
+    pub id: i32,
+    pub room_id: i32,
+    pub room_name: String,
+    pub content: String,
+    pub created_at: Option<DateTime<Utc>>,
+    // Moved to messages_archive by retention; no longer in the room's history
+    pub archived: bool,
+}
+
+// Changes to a profile; `None` leaves a field alone, `Some(None)` clears it
+#[derive(Debug, Clone, Default)]
+pub struct ProfileUpdate {
+    pub display_name: Option<Option<String>>,
+    pub bio: Option<Option<String>>,
+    pub avatar_url: Option<Option<String>>,
+    pub status_text: Option<Option<String>>,
+    pub status_expires_at: Option<Option<DateTime<Utc>>>,
+}
+
+// A user's membership in a room, with enough profile data to render a member list
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomMember {
+    pub user_id: i32,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub role: String,
+    pub joined_at: Option<DateTime<Utc>>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+}
+
+impl RoomMember {
+    fn from_row(row: &Row) -> RoomMember {
+        RoomMember {
+            user_id: row.get(0),
+            username: row.get(1),
+            display_name: row.get(2),
+            avatar_url: row.get(3),
+            role: row.get(4),
+            joined_at: to_utc(row.get(5)),
+            last_seen_at: to_utc(row.get(6)),
+        }
+    }
+}
+
+// Outcome of a membership change that must keep at least one owner
+#[derive(Debug, PartialEq, Eq)]
+pub enum MemberChange {
+    Done,
+    NotMember,
+    LastOwner,
+}
+
+// Which kind of room sanction a row describes; each kind has its own table
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SanctionKind {
+    Ban,
+    Mute,
+}
+
+// A ban or mute of a user in a room, permanent when `expires_at` is None
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sanction {
+    pub kind: SanctionKind,
+    pub room_id: i32,
+    pub user_id: i32,
+    pub username: String,
+    pub created_by: Option<i32>,
+    pub reason: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// A reported message in the moderation queue
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    pub id: i32,
+    pub room_id: i32,
+    // None once the message has been deleted
+    pub message_id: Option<i32>,
+    pub sender_id: Option<i32>,
+    pub sender_username: Option<String>,
+    // The message as it was when reported
+    pub content: String,
+    // None for messages flagged by a filter
+    pub reporter_id: Option<i32>,
+    pub reporter_username: Option<String>,
+    pub reason: String,
+    // open, dismissed or resolved
+    pub status: String,
+    pub resolution: Option<String>,
+    pub resolved_by: Option<i32>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// Which reports a reviewer sees: those in rooms where they hold one of `roles`
+#[derive(Debug, Clone)]
+pub struct ReportFilter {
+    pub reviewer_id: i32,
+    pub roles: Vec<&'static str>,
+    pub room_id: Option<i32>,
+    // None for every status
+    pub status: Option<String>,
+}
+
+// A message around a reported one, shown to reviewers
+#[derive(Serialize, Debug, Clone)]
+pub struct ContextMessage {
+    pub id: i32,
+    pub sender_id: Option<i32>,
+    pub sender_username: Option<String>,
     pub content: String,
     pub created_at: Option<DateTime<Utc>>,
 }
 
+// One message in a room transcript
+#[derive(Serialize, Debug, Clone)]
+pub struct TranscriptLine {
+    pub id: i32,
+    // None once the sender's account is deleted
+    pub sender: Option<String>,
+    pub content: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// A row to append to the audit log
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub actor_id: Option<i32>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<String>,
+    pub room_id: Option<i32>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+}
+
+// An audit log entry as read back for review
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub actor_id: Option<i32>,
+    pub actor_username: Option<String>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<String>,
+    pub room_id: Option<i32>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// Which entries a reviewer sees: those in rooms where they hold one of `roles`, or every entry
+// for server admins, narrowed by the optional fields
+#[derive(Debug, Clone)]
+pub struct AuditFilter {
+    // None for server admins
+    pub viewer_id: Option<i32>,
+    pub roles: Vec<&'static str>,
+    pub room_id: Option<i32>,
+    pub actor_id: Option<i32>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+// Auth Token Model
+#[derive(Debug, Clone)]
+pub struct AuthToken {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+// Attachment Model
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attachment {
+    pub id: Option<i32>,
+    pub room_id: i32,
+    pub uploader_id: i32,
+    pub message_id: Option<i32>,
+    pub file_name: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    #[serde(skip_serializing)]
+    pub storage_key: String,
+    #[serde(skip_serializing)]
+    pub thumbnail_key: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// Timestamps are stored as naive UTC `TIMESTAMP` columns
+fn to_utc(timestamp: Option<NaiveDateTime>) -> Option<DateTime<Utc>> {
+    timestamp.map(|ndt| DateTime::<Utc>::from_naive_utc_and_offset(ndt, Utc))
+}
+
 // Database operations for users
 impl User {
+    const COLUMNS: &'static str = "id, username, password_hash, avatar_url, created_at, is_admin, suspended_at";
+
+    fn from_row(row: &Row) -> User {
+        User {
+            id: Some(row.get(0)),
+            username: row.get(1),
+            password_hash: row.get(2),
+            avatar_url: row.get(3),
+            created_at: to_utc(row.get(4)),
+            is_admin: row.get(5),
+            suspended_at: to_utc(row.get(6)),
+        }
+    }
+
     pub async fn create(client: &Client, user: &User) -> Result<User, Error> {
+        let query = format!(
+            "INSERT INTO users (username, password_hash, avatar_url) 
+             VALUES ($1, $2, $3) 
+             RETURNING {}",
+            User::COLUMNS
+        );
         let row = client
             .query_one(
-                "INSERT INTO users (username, password_hash, avatar_url) 
-                 VALUES ($1, $2, $3) 
-                 RETURNING id, username, password_hash, avatar_url, created_at",
+                query.as_str(),
                 &[
                     &user.username,
                     &user.password_hash,
@@ -49,110 +490,778 @@ impl User {
             )
             .await?;
         
-        let created_at: Option<NaiveDateTime> = row.get(4);
-        let utc_created_at = created_at.map(|ndt| DateTime::<Utc>::from_utc(ndt, Utc));
-        
-        Ok(User {
-            id: Some(row.get(0)),
+        Ok(User::from_row(&row))
+    }
+    
+    pub async fn find_by_username(client: &Client, username: &str) -> Result<Option<User>, Error> {
+        let query = format!("SELECT {} FROM users WHERE username = $1", User::COLUMNS);
+        let result = client.query_opt(query.as_str(), &[&username]).await?;
+        Ok(result.as_ref().map(User::from_row))
+    }
+
+    // Whether a registered user goes by this name, as username or display name (case-insensitive)
+    pub async fn name_taken(client: &Client, name: &str) -> Result<bool, Error> {
+        let row = client
+            .query_one(
+                "SELECT EXISTS (
+                     SELECT 1 FROM users WHERE LOWER(username) = LOWER($1) OR LOWER(display_name) = LOWER($1)
+                 )",
+                &[&name],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    // Makes the given users server admins; returns the ids that exist
+    pub async fn grant_admin(client: &Client, user_ids: &[i32]) -> Result<Vec<i32>, Error> {
+        let rows = client
+            .query(
+                "UPDATE users SET is_admin = TRUE WHERE id = ANY($1) RETURNING id",
+                &[&user_ids],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    // Replaces the password and signs the user out everywhere; false if there is no such user
+    pub async fn set_password(client: &mut Client, user_id: i32, password_hash: &str) -> Result<bool, Error> {
+        let transaction = client.transaction().await?;
+        let updated = transaction
+            .execute("UPDATE users SET password_hash = $2 WHERE id = $1", &[&user_id, &password_hash])
+            .await?;
+        transaction.execute("DELETE FROM auth_tokens WHERE user_id = $1", &[&user_id]).await?;
+        transaction.commit().await?;
+        Ok(updated > 0)
+    }
+
+    // Deletes the user with their attachments, memberships and sanctions, and their messages
+    // unless `keep_messages`, which leaves them without a sender. Rooms they owned alone pass to
+    // the longest-standing of the highest-ranked remaining members, or are deleted when nobody
+    // else is in them. None if there is no such user.
+    pub async fn delete(client: &mut Client, user_id: i32, keep_messages: bool) -> Result<Option<DeletedUser>, Error> {
+        let transaction = client.transaction().await?;
+        let Some(row) = transaction
+            .query_opt("SELECT username FROM users WHERE id = $1 FOR UPDATE", &[&user_id])
+            .await?
+        else {
+            return Ok(None);
+        };
+        let mut deleted = DeletedUser { username: row.get(0), ..DeletedUser::default() };
+
+        let sole_owned: Vec<i32> = transaction
+            .query(
+                "SELECT rm.room_id FROM room_members rm
+                 WHERE rm.user_id = $1 AND rm.role = 'owner'
+                   AND NOT EXISTS (
+                       SELECT 1 FROM room_members o
+                       WHERE o.room_id = rm.room_id AND o.role = 'owner' AND o.user_id <> $1
+                   )
+                 FOR UPDATE",
+                &[&user_id],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        for room_id in sole_owned {
+            let successor = transaction
+                .query_opt(
+                    "SELECT user_id FROM room_members
+                     WHERE room_id = $1 AND user_id <> $2
+                     ORDER BY CASE role WHEN 'admin' THEN 0 WHEN 'moderator' THEN 1 WHEN 'member' THEN 2 ELSE 3 END,
+                              joined_at, user_id
+                     LIMIT 1",
+                    &[&room_id, &user_id],
+                )
+                .await?;
+            match successor {
+                Some(row) => {
+                    let new_owner: i32 = row.get(0);
+                    transaction
+                        .execute(
+                            "UPDATE room_members SET role = 'owner' WHERE room_id = $1 AND user_id = $2",
+                            &[&room_id, &new_owner],
+                        )
+                        .await?;
+                    deleted.new_owners.push((room_id, new_owner));
+                }
+                None => deleted.deleted_rooms.push(room_id),
+            }
+        }
+
+        // Blob keys first; the rows go with the rooms and the user
+        let rows = transaction
+            .query(
+                "SELECT storage_key, thumbnail_key FROM attachments WHERE uploader_id = $1 OR room_id = ANY($2)",
+                &[&user_id, &deleted.deleted_rooms],
+            )
+            .await?;
+        deleted.storage_keys = rows
+            .iter()
+            .flat_map(|row| {
+                let thumbnail: Option<String> = row.get(1);
+                std::iter::once(row.get(0)).chain(thumbnail)
+            })
+            .collect();
+
+        transaction.execute("DELETE FROM rooms WHERE id = ANY($1)", &[&deleted.deleted_rooms]).await?;
+        let (messages, archived) = if keep_messages {
+            (
+                "UPDATE messages SET sender_id = NULL WHERE sender_id = $1",
+                "UPDATE messages_archive SET sender_id = NULL WHERE sender_id = $1",
+            )
+        } else {
+            ("DELETE FROM messages WHERE sender_id = $1", "DELETE FROM messages_archive WHERE sender_id = $1")
+        };
+        for statement in [
+            "DELETE FROM attachments WHERE uploader_id = $1",
+            messages,
+            archived,
+            "DELETE FROM room_members WHERE user_id = $1",
+            "DELETE FROM room_bans WHERE user_id = $1",
+            "DELETE FROM room_mutes WHERE user_id = $1",
+            "UPDATE room_bans SET created_by = NULL WHERE created_by = $1",
+            "UPDATE room_mutes SET created_by = NULL WHERE created_by = $1",
+            "UPDATE rooms SET created_by = NULL WHERE created_by = $1",
+            "DELETE FROM users WHERE id = $1",
+        ] {
+            transaction.execute(statement, &[&user_id]).await?;
+        }
+        transaction.commit().await?;
+
+        Ok(Some(deleted))
+    }
+
+    pub async fn update_last_seen(client: &Client, user_id: i32, last_seen: DateTime<Utc>) -> Result<(), Error> {
+        client
+            .execute(
+                "UPDATE users SET last_seen_at = $2 WHERE id = $1",
+                &[&user_id, &last_seen.naive_utc()],
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+// Account queries for server admins
+impl UserAccount {
+    const SELECT: &'static str = "SELECT u.id, u.username, u.display_name, u.avatar_url, u.is_admin, u.suspended_at,
+            u.suspension_reason, (SELECT COUNT(*) FROM room_members rm WHERE rm.user_id = u.id),
+            u.last_seen_at, u.created_at
+         FROM users u";
+    const MATCHES: &'static str = "($1::TEXT IS NULL
+            OR STRPOS(LOWER(u.username), LOWER($1)) > 0 OR STRPOS(LOWER(u.display_name), LOWER($1)) > 0)
+        AND ($2::BOOLEAN IS NULL OR u.is_admin = $2)
+        AND ($3::BOOLEAN IS NULL OR (u.suspended_at IS NOT NULL) = $3)";
+
+    fn from_row(row: &Row) -> UserAccount {
+        UserAccount {
+            id: row.get(0),
             username: row.get(1),
-            password_hash: row.get(2),
+            display_name: row.get(2),
             avatar_url: row.get(3),
-            created_at: utc_created_at,
+            is_admin: row.get(4),
+            suspended_at: to_utc(row.get(5)),
+            suspension_reason: row.get(6),
+            room_count: row.get(7),
+            last_seen_at: to_utc(row.get(8)),
+            created_at: to_utc(row.get(9)),
+        }
+    }
+
+    pub async fn find_by_id(client: &Client, user_id: i32) -> Result<Option<UserAccount>, Error> {
+        let query = format!("{} WHERE u.id = $1", UserAccount::SELECT);
+        let row = client.query_opt(query.as_str(), &[&user_id]).await?;
+        Ok(row.as_ref().map(UserAccount::from_row))
+    }
+
+    // Oldest accounts first
+    pub async fn find(client: &Client, filter: &UserFilter, limit: i64, offset: i64) -> Result<(Vec<UserAccount>, i64), Error> {
+        let query = format!(
+            "{} WHERE {} ORDER BY u.id LIMIT $4 OFFSET $5",
+            UserAccount::SELECT,
+            UserAccount::MATCHES
+        );
+        let rows = client
+            .query(query.as_str(), &[&filter.search, &filter.admin, &filter.suspended, &limit, &offset])
+            .await?;
+
+        let count_query = format!("SELECT COUNT(*) FROM users u WHERE {}", UserAccount::MATCHES);
+        let total: i64 = client
+            .query_one(count_query.as_str(), &[&filter.search, &filter.admin, &filter.suspended])
+            .await?
+            .get(0);
+
+        Ok((rows.iter().map(UserAccount::from_row).collect(), total))
+    }
+
+    // Suspending keeps the original suspension time if the account is already suspended
+    pub async fn update(client: &Client, user_id: i32, update: &AccountUpdate) -> Result<Option<UserAccount>, Error> {
+        let updated = client
+            .execute(
+                "UPDATE users SET
+                     is_admin = COALESCE($2, is_admin),
+                     suspended_at = CASE $3::BOOLEAN
+                         WHEN TRUE THEN COALESCE(suspended_at, NOW() AT TIME ZONE 'UTC')
+                         WHEN FALSE THEN NULL
+                         ELSE suspended_at END,
+                     suspension_reason = CASE $3::BOOLEAN
+                         WHEN TRUE THEN $4
+                         WHEN FALSE THEN NULL
+                         ELSE suspension_reason END
+                 WHERE id = $1",
+                &[&user_id, &update.is_admin, &update.suspended, &update.suspension_reason],
+            )
+            .await?;
+        if updated == 0 {
+            return Ok(None);
+        }
+        UserAccount::find_by_id(client, user_id).await
+    }
+}
+
+impl UserExport {
+    // Every message the user wrote, shadow-hidden and archived ones included, oldest first. None
+    // if there is no such user.
+    pub async fn collect(client: &Client, user_id: i32) -> Result<Option<UserExport>, Error> {
+        let Some(profile) = UserProfile::find_by_id(client, user_id).await? else {
+            return Ok(None);
+        };
+
+        let memberships = client
+            .query(
+                "SELECT r.id, r.name, rm.role, rm.joined_at
+                 FROM room_members rm JOIN rooms r ON r.id = rm.room_id
+                 WHERE rm.user_id = $1
+                 ORDER BY rm.joined_at, r.id",
+                &[&user_id],
+            )
+            .await?
+            .iter()
+            .map(|row| ExportedMembership {
+                room_id: row.get(0),
+                room_name: row.get(1),
+                role: row.get(2),
+                joined_at: to_utc(row.get(3)),
+            })
+            .collect();
+
+        let messages = client
+            .query(
+                "SELECT m.id, r.id, r.name, m.content, m.created_at, m.archived
+                 FROM (
+                     SELECT id, room_id, content, created_at, FALSE AS archived FROM messages WHERE sender_id = $1
+                     UNION ALL
+                     SELECT id, room_id, content, created_at, TRUE FROM messages_archive WHERE sender_id = $1
+                 ) m JOIN rooms r ON r.id = m.room_id
+                 ORDER BY m.created_at, m.id",
+                &[&user_id],
+            )
+            .await?
+            .iter()
+            .map(|row| ExportedMessage {
+                id: row.get(0),
+                room_id: row.get(1),
+                room_name: row.get(2),
+                content: row.get(3),
+                created_at: to_utc(row.get(4)),
+                archived: row.get(5),
+            })
+            .collect();
+
+        Ok(Some(UserExport { exported_at: Utc::now(), profile, memberships, messages }))
+    }
+}
+
+// Database operations for user profiles
+impl UserProfile {
+    // Expired custom statuses are hidden rather than returned stale
+    const SELECT: &'static str = "SELECT id, username, display_name, bio, avatar_url,
+                CASE WHEN status_expires_at IS NULL OR status_expires_at > NOW() AT TIME ZONE 'UTC'
+                     THEN status_text END,
+                CASE WHEN status_expires_at > NOW() AT TIME ZONE 'UTC'
+                     THEN status_expires_at END,
+                created_at
+         FROM users";
+
+    fn from_row(row: &Row) -> UserProfile {
+        UserProfile {
+            id: row.get(0),
+            username: row.get(1),
+            display_name: row.get(2),
+            bio: row.get(3),
+            avatar_url: row.get(4),
+            status_text: row.get(5),
+            status_expires_at: to_utc(row.get(6)),
+            created_at: to_utc(row.get(7)),
+        }
+    }
+
+    pub async fn find_by_id(client: &Client, user_id: i32) -> Result<Option<UserProfile>, Error> {
+        let query = format!("{} WHERE id = $1", UserProfile::SELECT);
+        let result = client.query_opt(query.as_str(), &[&user_id]).await?;
+        Ok(result.as_ref().map(UserProfile::from_row))
+    }
+
+    pub async fn find_by_username(client: &Client, username: &str) -> Result<Option<UserProfile>, Error> {
+        let query = format!("{} WHERE username = $1", UserProfile::SELECT);
+        let result = client.query_opt(query.as_str(), &[&username]).await?;
+        Ok(result.as_ref().map(UserProfile::from_row))
+    }
+
+    // Whether another user already uses this display name, or has it as their username, so
+    // nobody can pass for someone else (case-insensitive)
+    pub async fn display_name_taken(client: &Client, display_name: &str, user_id: i32) -> Result<bool, Error> {
+        let row = client
+            .query_one(
+                "SELECT EXISTS (
+                     SELECT 1 FROM users
+                     WHERE (LOWER(display_name) = LOWER($1) OR LOWER(username) = LOWER($1)) AND id <> $2
+                 )",
+                &[&display_name, &user_id],
+            )
+            .await?;
+
+        Ok(row.get(0))
+    }
+
+    pub async fn update(client: &Client, user_id: i32, update: &ProfileUpdate) -> Result<Option<UserProfile>, Error> {
+        // Each column is only overwritten when its "set" flag is true
+        let expires_at = update
+            .status_expires_at
+            .flatten()
+            .map(|at| at.naive_utc());
+        client
+            .execute(
+                "UPDATE users SET
+                     display_name = CASE WHEN $2 THEN $3 ELSE display_name END,
+                     bio = CASE WHEN $4 THEN $5 ELSE bio END,
+                     avatar_url = CASE WHEN $6 THEN $7 ELSE avatar_url END,
+                     status_text = CASE WHEN $8 THEN $9 ELSE status_text END,
+                     status_expires_at = CASE WHEN $10 THEN $11 ELSE status_expires_at END
+                 WHERE id = $1",
+                &[
+                    &user_id,
+                    &update.display_name.is_some(),
+                    &update.display_name.clone().flatten(),
+                    &update.bio.is_some(),
+                    &update.bio.clone().flatten(),
+                    &update.avatar_url.is_some(),
+                    &update.avatar_url.clone().flatten(),
+                    &update.status_text.is_some(),
+                    &update.status_text.clone().flatten(),
+                    &update.status_expires_at.is_some(),
+                    &expires_at,
+                ],
+            )
+            .await?;
+
+        UserProfile::find_by_id(client, user_id).await
+    }
+}
+
+// Database operations for auth tokens
+impl AuthToken {
+    pub async fn create(client: &Client, user_id: i32, ttl: chrono::Duration) -> Result<AuthToken, Error> {
+        let token = generate_token();
+        let expires_at = Utc::now() + ttl;
+
+        client
+            .execute(
+                "INSERT INTO auth_tokens (token, user_id, expires_at) 
+                 VALUES ($1, $2, $3)",
+                &[&token, &user_id, &expires_at.naive_utc()],
+            )
+            .await?;
+
+        Ok(AuthToken {
+            token,
+            expires_at,
         })
     }
+
+    // Resolves a bearer token to its user, ignoring expired tokens
+    pub async fn find_user(client: &Client, token: &str) -> Result<Option<User>, Error> {
+        let result = client
+            .query_opt(
+                "SELECT u.id, u.username, u.password_hash, u.avatar_url, u.created_at, u.is_admin, u.suspended_at
+                 FROM auth_tokens t
+                 JOIN users u ON u.id = t.user_id
+                 WHERE t.token = $1 AND t.expires_at > NOW() AT TIME ZONE 'UTC'",
+                &[&token],
+            )
+            .await?;
+
+        Ok(result.as_ref().map(User::from_row))
+    }
+}
+
+fn generate_token() -> String {
+    let mut rng = rand::thread_rng();
+    (0..32).map(|_| format!("{:02x}", rng.gen::<u8>())).collect()
+}
+
+// Database operations for rooms
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomSort {
+    Activity,
+    Members,
+    Name,
+    Newest,
+}
+
+impl RoomSort {
+    fn order_by(self) -> &'static str {
+        match self {
+            RoomSort::Activity => "last_activity_at DESC, r.id DESC",
+            RoomSort::Members => "member_count DESC, r.id",
+            RoomSort::Name => "LOWER(r.name), r.id",
+            RoomSort::Newest => "r.created_at DESC, r.id DESC",
+        }
+    }
+}
+
+// Filters for the room directory
+pub struct RoomFilter {
+    pub viewer_id: Option<i32>,
+    pub search: Option<String>,
+    pub room_type: Option<String>,
+    pub sort: RoomSort,
+    // Lists private rooms to non-members too, for server admins
+    pub include_private: bool,
+}
+
+// A room as listed in the directory; never includes the password hash
+#[derive(Debug, Serialize)]
+pub struct RoomSummary {
+    pub id: i32,
+    pub name: String,
+    pub room_type: String,
+    pub description: Option<String>,
+    pub topic: Option<String>,
+    pub created_by: Option<i32>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub archived_at: Option<DateTime<Utc>>,
+    pub last_activity_at: Option<DateTime<Utc>>,
+    pub member_count: i64,
+    pub is_member: bool,
+}
+
+impl RoomSummary {
+    // Private rooms are only listed to their members
+    const VISIBLE: &'static str = "($4 OR r.\"type\" <> 'private'
+             OR EXISTS (SELECT 1 FROM room_members vm WHERE vm.room_id = r.id AND vm.user_id = $1))
+         AND ($2::TEXT IS NULL OR STRPOS(LOWER(r.name), LOWER($2)) > 0)
+         AND ($3::TEXT IS NULL OR r.\"type\" = $3)";
+
+    // One page of the directory plus the total number of matching rooms
+    pub async fn find(client: &Client, filter: &RoomFilter, limit: i64, offset: i64) -> Result<(Vec<RoomSummary>, i64), Error> {
+        let query = format!(
+            "SELECT r.id, r.name, r.\"type\", r.description, r.topic, r.created_by, r.created_at, r.archived_at,
+                    COALESCE((SELECT MAX(m.created_at) FROM messages m WHERE m.room_id = r.id), r.created_at) AS last_activity_at,
+                    (SELECT COUNT(*) FROM room_members rm WHERE rm.room_id = r.id) AS member_count,
+                    EXISTS (SELECT 1 FROM room_members rm WHERE rm.room_id = r.id AND rm.user_id = $1) AS is_member
+             FROM rooms r
+             WHERE {}
+             ORDER BY {}
+             LIMIT $5 OFFSET $6",
+            RoomSummary::VISIBLE,
+            filter.sort.order_by()
+        );
+        let rows = client
+            .query(
+                query.as_str(),
+                &[&filter.viewer_id, &filter.search, &filter.room_type, &filter.include_private, &limit, &offset],
+            )
+            .await?;
+
+        let count_query = format!("SELECT COUNT(*) FROM rooms r WHERE {}", RoomSummary::VISIBLE);
+        let total: i64 = client
+            .query_one(
+                count_query.as_str(),
+                &[&filter.viewer_id, &filter.search, &filter.room_type, &filter.include_private],
+            )
+            .await?
+            .get(0);
+
+        let rooms = rows
+            .iter()
+            .map(|row| RoomSummary {
+                id: row.get(0),
+                name: row.get(1),
+                room_type: row.get(2),
+                description: row.get(3),
+                topic: row.get(4),
+                created_by: row.get(5),
+                created_at: to_utc(row.get(6)),
+                archived_at: to_utc(row.get(7)),
+                last_activity_at: to_utc(row.get(8)),
+                member_count: row.get(9),
+                is_member: row.get(10),
+            })
+            .collect();
+
+        Ok((rooms, total))
+    }
+}
+
+impl Room {
+    const COLUMNS: &'static str =
+        "id, name, \"type\", password_hash, created_by, created_at, description, topic, archived_at, retention_days";
+
+    fn from_row(row: &Row) -> Room {
+        Room {
+            id: Some(row.get(0)),
+            name: row.get(1),
+            type_: row.get(2),
+            password_hash: row.get(3),
+            created_by: row.get(4),
+            created_at: to_utc(row.get(5)),
+            description: row.get(6),
+            topic: row.get(7),
+            archived_at: to_utc(row.get(8)),
+            retention_days: row.get(9),
+        }
+    }
+
+    // Creates the room with its creator, if any, as owner, in one transaction so a room never
+    // exists without its owner
+    pub async fn create(client: &mut Client, room: &Room) -> Result<Room, Error> {
+        let transaction = client.transaction().await?;
+        let query = format!(
+            "INSERT INTO rooms (name, \"type\", password_hash, created_by, description, topic, retention_days) 
+             VALUES ($1, $2, $3, $4, $5, $6, $7) 
+             RETURNING {}",
+            Room::COLUMNS
+        );
+        let row = transaction
+            .query_one(
+                query.as_str(),
+                &[
+                    &room.name,
+                    &room.type_,
+                    &room.password_hash,
+                    &room.created_by,
+                    &room.description,
+                    &room.topic,
+                    &room.retention_days,
+                ],
+            )
+            .await?;
+        let created = Room::from_row(&row);
+        if let (Some(room_id), Some(owner_id)) = (created.id, room.created_by) {
+            transaction
+                .execute(
+                    "INSERT INTO room_members (room_id, user_id, role) VALUES ($1, $2, 'owner')",
+                    &[&room_id, &owner_id],
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+
+        Ok(created)
+    }
     
-    pub async fn find_by_username(client: &Client, username: &str) -> Result<Option<User>, Error> {
+
+    pub async fn find_by_id(client: &Client, room_id: i32) -> Result<Option<Room>, Error> {
+        let query = format!("SELECT {} FROM rooms WHERE id = $1", Room::COLUMNS);
+        let result = client.query_opt(query.as_str(), &[&room_id]).await?;
+
+        Ok(result.as_ref().map(Room::from_row))
+    }
+
+    pub async fn update(client: &Client, room_id: i32, update: &RoomUpdate) -> Result<Option<Room>, Error> {
+        // Each column is only overwritten when its "set" flag is true
+        let query = format!(
+            "UPDATE rooms SET
+                 name = COALESCE($2, name),
+                 description = CASE WHEN $3 THEN $4 ELSE description END,
+                 topic = CASE WHEN $5 THEN $6 ELSE topic END,
+                 \"type\" = COALESCE($7, \"type\"),
+                 password_hash = CASE WHEN $8 THEN $9 ELSE password_hash END,
+                 archived_at = CASE
+                     WHEN $10::BOOLEAN IS NULL THEN archived_at
+                     WHEN $10 THEN COALESCE(archived_at, NOW() AT TIME ZONE 'UTC')
+                     ELSE NULL
+                 END,
+                 retention_days = CASE WHEN $11 THEN $12 ELSE retention_days END
+             WHERE id = $1
+             RETURNING {}",
+            Room::COLUMNS
+        );
+        let result = client
+            .query_opt(
+                query.as_str(),
+                &[
+                    &room_id,
+                    &update.name,
+                    &update.description.is_some(),
+                    &update.description.clone().flatten(),
+                    &update.topic.is_some(),
+                    &update.topic.clone().flatten(),
+                    &update.type_,
+                    &update.password_hash.is_some(),
+                    &update.password_hash.clone().flatten(),
+                    &update.archived,
+                    &update.retention_days.is_some(),
+                    &update.retention_days.flatten(),
+                ],
+            )
+            .await?;
+
+        Ok(result.as_ref().map(Room::from_row))
+    }
+
+    // Deletes the room; memberships, messages, attachments, bans and mutes go with it
+    pub async fn delete(client: &Client, room_id: i32) -> Result<bool, Error> {
+        let deleted = client.execute("DELETE FROM rooms WHERE id = $1", &[&room_id]).await?;
+        Ok(deleted > 0)
+    }
+
+    // (room, days) for every room whose messages expire; `default_days` applies to rooms that
+    // don't set their own
+    pub async fn retention_periods(client: &Client, default_days: Option<i32>) -> Result<Vec<(i32, i32)>, Error> {
+        let rows = client
+            .query(
+                "SELECT id, COALESCE(retention_days, $1::INTEGER) AS days FROM rooms
+                 WHERE COALESCE(retention_days, $1::INTEGER) > 0
+                 ORDER BY id",
+                &[&default_days],
+            )
+            .await?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    // Ids of every room the user is a member of
+    pub async fn ids_for_user(client: &Client, user_id: i32) -> Result<Vec<i32>, Error> {
+        let rows = client
+            .query("SELECT room_id FROM room_members WHERE user_id = $1", &[&user_id])
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    // One page of a room's members, oldest first, plus the total member count
+    pub async fn members(client: &Client, room_id: i32, limit: i64, offset: i64) -> Result<(Vec<RoomMember>, i64), Error> {
+        let rows = client
+            .query(
+                "SELECT u.id, u.username, u.display_name, u.avatar_url, rm.role, rm.joined_at, u.last_seen_at 
+                 FROM room_members rm
+                 JOIN users u ON u.id = rm.user_id
+                 WHERE rm.room_id = $1
+                 ORDER BY rm.joined_at, u.id
+                 LIMIT $2 OFFSET $3",
+                &[&room_id, &limit, &offset],
+            )
+            .await?;
+        let total: i64 = client
+            .query_one("SELECT COUNT(*) FROM room_members WHERE room_id = $1", &[&room_id])
+            .await?
+            .get(0);
+
+        Ok((rows.iter().map(RoomMember::from_row).collect(), total))
+    }
+
+    pub async fn member(client: &Client, room_id: i32, user_id: i32) -> Result<Option<RoomMember>, Error> {
         let result = client
             .query_opt(
-                "SELECT id, username, password_hash, avatar_url, created_at 
-                 FROM users WHERE username = $1",
-                &[&username],
+                "SELECT u.id, u.username, u.display_name, u.avatar_url, rm.role, rm.joined_at, u.last_seen_at 
+                 FROM room_members rm
+                 JOIN users u ON u.id = rm.user_id
+                 WHERE rm.room_id = $1 AND rm.user_id = $2",
+                &[&room_id, &user_id],
+            )
+            .await?;
+
+        Ok(result.as_ref().map(RoomMember::from_row))
+    }
+
+    // Changes a member's role, refusing to demote the room's last owner
+    pub async fn change_role(client: &mut Client, room_id: i32, user_id: i32, role: &str) -> Result<MemberChange, Error> {
+        let transaction = client.transaction().await?;
+        // Lock the owner rows so concurrent demotions can't both pass the check
+        let owners: Vec<i32> = transaction
+            .query(
+                "SELECT user_id FROM room_members WHERE room_id = $1 AND role = 'owner' FOR UPDATE",
+                &[&room_id],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+
+        if role != "owner" && owners == [user_id] {
+            return Ok(MemberChange::LastOwner);
+        }
+
+        let updated = transaction
+            .execute(
+                "UPDATE room_members SET role = $3 WHERE room_id = $1 AND user_id = $2",
+                &[&room_id, &user_id, &role],
             )
             .await?;
-        
-        if let Some(row) = result {
-            let created_at: Option<NaiveDateTime> = row.get(4);
-            let utc_created_at = created_at.map(|ndt| DateTime::<Utc>::from_utc(ndt, Utc));
-            
-            Ok(Some(User {
-                id: Some(row.get(0)),
-                username: row.get(1),
-                password_hash: row.get(2),
-                avatar_url: row.get(3),
-                created_at: utc_created_at,
-            }))
-        } else {
-            Ok(None)
-        }
+        transaction.commit().await?;
+
+        Ok(if updated == 0 { MemberChange::NotMember } else { MemberChange::Done })
     }
-}
 
-// Database operations for rooms
-impl Room {
-    pub async fn create(client: &Client, room: &Room) -> Result<Room, Error> {
-        let row = client
-            .query_one(
-                "INSERT INTO rooms (name, \"type\", password_hash, created_by) 
-                 VALUES ($1, $2, $3, $4) 
-                 RETURNING id, name, \"type\", password_hash, created_by, created_at",
-                &[
-                    &room.name,
-                    &room.type_,
-                    &room.password_hash,
-                    &room.created_by,
-                ],
+    // Removes a member (kick or leave). The last owner can only go if nobody else is left.
+    pub async fn remove_member(client: &mut Client, room_id: i32, user_id: i32) -> Result<MemberChange, Error> {
+        let transaction = client.transaction().await?;
+        let members: Vec<(i32, String)> = transaction
+            .query(
+                "SELECT user_id, role FROM room_members WHERE room_id = $1 FOR UPDATE",
+                &[&room_id],
+            )
+            .await?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+
+        let owners: Vec<i32> = members
+            .iter()
+            .filter(|(_, role)| role == "owner")
+            .map(|(id, _)| *id)
+            .collect();
+        if owners == [user_id] && members.len() > 1 {
+            return Ok(MemberChange::LastOwner);
+        }
+
+        let removed = transaction
+            .execute(
+                "DELETE FROM room_members WHERE room_id = $1 AND user_id = $2",
+                &[&room_id, &user_id],
             )
             .await?;
-        
-        let created_at: Option<NaiveDateTime> = row.get(5);
-        let utc_created_at = created_at.map(|ndt| DateTime::<Utc>::from_utc(ndt, Utc));
-        
-        Ok(Room {
-            id: Some(row.get(0)),
-            name: row.get(1),
-            type_: row.get(2),
-            password_hash: row.get(3),
-            created_by: row.get(4),
-            created_at: utc_created_at,
-        })
+        transaction.commit().await?;
+
+        Ok(if removed == 0 { MemberChange::NotMember } else { MemberChange::Done })
     }
-    
-    pub async fn find_all(client: &Client) -> Result<Vec<Room>, Error> {
-        let rows = client
-            .query(
-                "SELECT id, name, \"type\", password_hash, created_by, created_at 
-                 FROM rooms",
-                &[],
+
+    pub async fn is_member(client: &Client, room_id: i32, user_id: i32) -> Result<bool, Error> {
+        let row = client
+            .query_one(
+                "SELECT EXISTS (
+                     SELECT 1 FROM room_members WHERE room_id = $1 AND user_id = $2
+                 )",
+                &[&room_id, &user_id],
             )
             .await?;
-        
-        let rooms = rows
-            .into_iter()
-            .map(|row| {
-                let created_at: Option<NaiveDateTime> = row.get(5);
-                let utc_created_at = created_at.map(|ndt| DateTime::<Utc>::from_utc(ndt, Utc));
-                
-                Room {
-                    id: Some(row.get(0)),
-                    name: row.get(1),
-                    type_: row.get(2),
-                    password_hash: row.get(3),
-                    created_by: row.get(4),
-                    created_at: utc_created_at,
-                }
-            })
-            .collect();
-        
-        Ok(rooms)
+
+        Ok(row.get(0))
     }
     
+    // False when the user was already a member
     pub async fn join_room(
         client: &Client, 
         user_id: i32, 
         room_id: i32, 
         role: &str
-    ) -> Result<(), Error> {
-        client
+    ) -> Result<bool, Error> {
+        let inserted = client
             .execute(
                 "INSERT INTO room_members (room_id, user_id, role) 
                  VALUES ($1, $2, $3)
@@ -161,28 +1270,29 @@ impl Room {
             )
             .await?;
         
-        Ok(())
+        Ok(inserted > 0)
     }
 }
 
 // Database operations for messages
 impl Message {
-    pub async fn create(client: &Client, message: &Message) -> Result<Message, Error> {
+    // Shadow-hidden messages are stored but left out of the room's history
+    pub async fn create(client: &Client, message: &Message, shadow_hidden: bool) -> Result<Message, Error> {
         let row = client
             .query_one(
-                "INSERT INTO messages (room_id, sender_id, content) 
-                 VALUES ($1, $2, $3) 
+                "INSERT INTO messages (room_id, sender_id, content, shadow_hidden) 
+                 VALUES ($1, $2, $3, $4) 
                  RETURNING id, room_id, sender_id, content, created_at",
                 &[
                     &message.room_id,
                     &message.sender_id,
                     &message.content,
+                    &shadow_hidden,
                 ],
             )
             .await?;
         
-        let created_at: Option<NaiveDateTime> = row.get(4);
-        let utc_created_at = created_at.map(|ndt| DateTime::<Utc>::from_utc(ndt, Utc));
+        let utc_created_at = to_utc(row.get(4));
         
         Ok(Message {
             id: Some(row.get(0)),
@@ -193,12 +1303,152 @@ impl Message {
         })
     }
     
+    pub async fn find_by_id(client: &Client, message_id: i32) -> Result<Option<Message>, Error> {
+        let row = client
+            .query_opt(
+                "SELECT id, room_id, sender_id, content, created_at FROM messages WHERE id = $1",
+                &[&message_id],
+            )
+            .await?;
+        Ok(row.map(|row| Message {
+            id: Some(row.get(0)),
+            room_id: row.get(1),
+            sender_id: row.get(2),
+            content: row.get(3),
+            created_at: to_utc(row.get(4)),
+        }))
+    }
+
+    // Replaces the text of a message; a hidden edit hides the message from then on.
+    // None when the message is gone.
+    pub async fn edit(
+        client: &Client,
+        message_id: i32,
+        content: &str,
+        shadow_hidden: bool,
+    ) -> Result<Option<Message>, Error> {
+        let row = client
+            .query_opt(
+                "UPDATE messages SET content = $2, shadow_hidden = shadow_hidden OR $3 WHERE id = $1
+                 RETURNING id, room_id, sender_id, content, created_at",
+                &[&message_id, &content, &shadow_hidden],
+            )
+            .await?;
+        Ok(row.map(|row| Message {
+            id: Some(row.get(0)),
+            room_id: row.get(1),
+            sender_id: row.get(2),
+            content: row.get(3),
+            created_at: to_utc(row.get(4)),
+        }))
+    }
+
+    // Attachments stay stored but are no longer linked to the message
+    pub async fn delete(client: &Client, message_id: i32) -> Result<bool, Error> {
+        let deleted = client.execute("DELETE FROM messages WHERE id = $1", &[&message_id]).await?;
+        Ok(deleted > 0)
+    }
+
+    // The room's history oldest first, from `from` up to but not including `to`, as rows for
+    // TranscriptLine::from_row. Rows arrive as they are read, so exports of any size stay small
+    // in memory. Shadow-hidden messages are left out.
+    pub async fn transcript(
+        client: &Client,
+        room_id: i32,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<RowStream, Error> {
+        let from = from.map(|t| t.naive_utc());
+        let to = to.map(|t| t.naive_utc());
+        let params: [&(dyn ToSql + Sync); 3] = [&room_id, &from, &to];
+        client
+            .query_raw(
+                "SELECT m.id, u.username, m.content, m.created_at
+                 FROM messages m LEFT JOIN users u ON u.id = m.sender_id
+                 WHERE m.room_id = $1 AND NOT m.shadow_hidden
+                   AND ($2::TIMESTAMP IS NULL OR m.created_at >= $2)
+                   AND ($3::TIMESTAMP IS NULL OR m.created_at < $3)
+                 ORDER BY m.created_at, m.id",
+                params,
+            )
+            .await
+    }
+
+    // How many messages `prune` would delete
+    pub async fn count_older_than(client: &Client, before: DateTime<Utc>, room_id: Option<i32>) -> Result<i64, Error> {
+        let row = client
+            .query_one(
+                "SELECT COUNT(*) FROM messages WHERE created_at < $1 AND ($2::INTEGER IS NULL OR room_id = $2)",
+                &[&before.naive_utc(), &room_id],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    // Deletes up to `limit` messages sent before `before`, in one room or everywhere, together
+    // with their attachments; with `archive` they are moved to messages_archive instead and their
+    // attachments stay, linked to the archived copy by `archived_message_id`. A message already in
+    // the archive, e.g. restored and pruned again, replaces the archived copy, and only messages
+    // that made it into the archive are deleted. Each call is
+    // one short statement that skips rows other sessions hold, so large histories are pruned by
+    // calling it until fewer than `limit` come back.
+    pub async fn prune(
+        client: &Client,
+        before: DateTime<Utc>,
+        room_id: Option<i32>,
+        limit: i64,
+        archive: bool,
+    ) -> Result<PrunedMessages, Error> {
+        let removal = if archive {
+            "archived AS (
+                 INSERT INTO messages_archive (id, room_id, sender_id, content, shadow_hidden, created_at)
+                 SELECT id, room_id, sender_id, content, shadow_hidden, created_at
+                 FROM messages WHERE id IN (SELECT id FROM expired)
+                 ON CONFLICT (id) DO UPDATE SET
+                     room_id = EXCLUDED.room_id, sender_id = EXCLUDED.sender_id, content = EXCLUDED.content,
+                     shadow_hidden = EXCLUDED.shadow_hidden, created_at = EXCLUDED.created_at,
+                     archived_at = CURRENT_TIMESTAMP
+                 RETURNING id
+             ), relinked AS (
+                 UPDATE attachments SET archived_message_id = message_id, message_id = NULL
+                 WHERE message_id IN (SELECT id FROM archived)
+             ), moved AS (
+                 DELETE FROM messages WHERE id IN (SELECT id FROM archived) RETURNING id
+             )
+             SELECT (SELECT COUNT(*) FROM moved), '{}'::VARCHAR[]"
+        } else {
+            "files AS (
+                 DELETE FROM attachments WHERE message_id IN (SELECT id FROM expired)
+                 RETURNING storage_key, thumbnail_key
+             ), deleted AS (
+                 DELETE FROM messages WHERE id IN (SELECT id FROM expired) RETURNING id
+             )
+             SELECT (SELECT COUNT(*) FROM deleted),
+                    ARRAY(SELECT storage_key FROM files
+                          UNION ALL SELECT thumbnail_key FROM files WHERE thumbnail_key IS NOT NULL)"
+        };
+        let query = format!(
+            "WITH expired AS (
+                 SELECT id FROM messages
+                 WHERE created_at < $1 AND ($2::INTEGER IS NULL OR room_id = $2)
+                 LIMIT $3
+                 FOR UPDATE SKIP LOCKED
+             ), {}",
+            removal
+        );
+        let row = client
+            .query_one(query.as_str(), &[&before.naive_utc(), &room_id, &limit])
+            .await?;
+        let messages: i64 = row.get(0);
+        Ok(PrunedMessages { messages: messages as u64, storage_keys: row.get(1) })
+    }
+
     pub async fn find_by_room(client: &Client, room_id: i32, limit: i64) -> Result<Vec<Message>, Error> {
         let rows = client
             .query(
                 "SELECT m.id, m.room_id, m.sender_id, m.content, m.created_at 
                  FROM messages m
-                 WHERE m.room_id = $1
+                 WHERE m.room_id = $1 AND NOT m.shadow_hidden
                  ORDER BY m.created_at DESC
                  LIMIT $2",
                 &[&room_id, &limit],
@@ -208,8 +1458,7 @@ impl Message {
         let messages = rows
             .into_iter()
             .map(|row| {
-                let created_at: Option<NaiveDateTime> = row.get(4);
-                let utc_created_at = created_at.map(|ndt| DateTime::<Utc>::from_utc(ndt, Utc));
+                let utc_created_at = to_utc(row.get(4));
                 
                 Message {
                     id: Some(row.get(0)),
@@ -223,4 +1472,611 @@ impl Message {
         
         Ok(messages)
     }
-}
\ No newline at end of file
+}
+
+impl RoomImport {
+    // Rows per INSERT when copying messages
+    const BATCH: usize = 5000;
+
+    // Writes a new room, its memberships and messages in one transaction. Mapped authors post as
+    // the account they were mapped to; everyone else posts as a passwordless placeholder account,
+    // which later imports from the same source reuse for the same author id. On a dry run the
+    // transaction is rolled back, so the result is exact but nothing is kept.
+    pub async fn apply(
+        &self,
+        client: &mut Client,
+        owner_id: i32,
+        authors: &ImportAuthors,
+        dry_run: bool,
+    ) -> Result<RoomImportResult, Error> {
+        let transaction = client.transaction().await?;
+        let mut result = RoomImportResult::default();
+
+        let mut author_ids: Vec<&str> = self
+            .members
+            .iter()
+            .chain(self.messages.iter().map(|m| &m.sender))
+            .map(String::as_str)
+            .collect();
+        author_ids.sort_unstable();
+        author_ids.dedup();
+        let mut user_ids: HashMap<&str, i32> = HashMap::new();
+        for author in author_ids {
+            let user_id = match authors.mapped.get(author) {
+                Some(user_id) => *user_id,
+                None => {
+                    let name = authors.names.get(author).map_or(author, String::as_str);
+                    let (user_id, created) = RoomImport::placeholder(&transaction, authors.source, author, name).await?;
+                    result.users_created.extend(created);
+                    user_id
+                }
+            };
+            user_ids.insert(author, user_id);
+        }
+
+        let row = transaction
+            .query_one(
+                "INSERT INTO rooms (name, \"type\", created_by, topic) VALUES ($1, $2, $3, $4) RETURNING id",
+                &[&self.name, &self.room_type, &owner_id, &self.topic],
+            )
+            .await?;
+        let room_id: i32 = row.get(0);
+        transaction
+            .execute(
+                "INSERT INTO room_members (room_id, user_id, role) VALUES ($1, $2, 'owner')",
+                &[&room_id, &owner_id],
+            )
+            .await?;
+
+        let member_ids: Vec<i32> = user_ids.values().copied().collect();
+        result.members_added = transaction
+            .execute(
+                "INSERT INTO room_members (room_id, user_id, role) SELECT $1, UNNEST($2::INTEGER[]), 'member'
+                 ON CONFLICT (room_id, user_id) DO NOTHING",
+                &[&room_id, &member_ids],
+            )
+            .await?;
+
+        for batch in self.messages.chunks(RoomImport::BATCH) {
+            let senders: Vec<i32> = batch.iter().map(|m| user_ids[m.sender.as_str()]).collect();
+            let contents: Vec<&str> = batch.iter().map(|m| m.content.as_str()).collect();
+            let times: Vec<NaiveDateTime> = batch.iter().map(|m| m.created_at.naive_utc()).collect();
+            result.messages_imported += transaction
+                .execute(
+                    "INSERT INTO messages (room_id, sender_id, content, created_at)
+                     SELECT $1, m.sender_id, m.content, m.created_at
+                     FROM UNNEST($2::INTEGER[], $3::TEXT[], $4::TIMESTAMP[]) AS m (sender_id, content, created_at)",
+                    &[&room_id, &senders, &contents, &times],
+                )
+                .await?;
+        }
+
+        if dry_run {
+            transaction.rollback().await?;
+        } else {
+            transaction.commit().await?;
+            result.room_id = Some(room_id);
+        }
+        Ok(result)
+    }
+
+    // The placeholder account for an export's author, created on first use as `name@source`,
+    // or `name2@source` and so on when that is taken. Registration never accepts '@', so nobody
+    // can sign up as one. Also returns the username when the account is new.
+    async fn placeholder(
+        transaction: &Transaction<'_>,
+        source: &str,
+        author: &str,
+        name: &str,
+    ) -> Result<(i32, Option<String>), Error> {
+        let existing = transaction
+            .query_opt(
+                "SELECT user_id FROM import_authors WHERE source = $1 AND external_id = $2",
+                &[&source, &author],
+            )
+            .await?;
+        if let Some(row) = existing {
+            return Ok((row.get(0), None));
+        }
+        let mut n = 1;
+        loop {
+            let username = match n {
+                1 => format!("{}@{}", name, source),
+                n => format!("{}{}@{}", name, n, source),
+            };
+            let created = transaction
+                .query_opt(
+                    "INSERT INTO users (username) VALUES ($1) ON CONFLICT (username) DO NOTHING RETURNING id",
+                    &[&username],
+                )
+                .await?;
+            if let Some(row) = created {
+                let user_id: i32 = row.get(0);
+                transaction
+                    .execute(
+                        "INSERT INTO import_authors (source, external_id, user_id) VALUES ($1, $2, $3)",
+                        &[&source, &author, &user_id],
+                    )
+                    .await?;
+                return Ok((user_id, Some(username)));
+            }
+            n += 1;
+        }
+    }
+}
+
+impl TranscriptLine {
+    pub fn from_row(row: &Row) -> TranscriptLine {
+        TranscriptLine {
+            id: row.get(0),
+            sender: row.get(1),
+            content: row.get(2),
+            created_at: to_utc(row.get(3)),
+        }
+    }
+}
+
+// Database operations for attachments
+impl Attachment {
+    fn from_row(row: &Row) -> Attachment {
+        Attachment {
+            id: Some(row.get(0)),
+            room_id: row.get(1),
+            uploader_id: row.get(2),
+            message_id: row.get(3),
+            file_name: row.get(4),
+            content_type: row.get(5),
+            size_bytes: row.get(6),
+            storage_key: row.get(7),
+            thumbnail_key: row.get(8),
+            created_at: to_utc(row.get(9)),
+        }
+    }
+
+    pub async fn create(client: &Client, attachment: &Attachment) -> Result<Attachment, Error> {
+        let row = client
+            .query_one(
+                "INSERT INTO attachments 
+                     (room_id, uploader_id, file_name, content_type, size_bytes, storage_key, thumbnail_key) 
+                 VALUES ($1, $2, $3, $4, $5, $6, $7) 
+                 RETURNING id, room_id, uploader_id, message_id, file_name, content_type, 
+                           size_bytes, storage_key, thumbnail_key, created_at",
+                &[
+                    &attachment.room_id,
+                    &attachment.uploader_id,
+                    &attachment.file_name,
+                    &attachment.content_type,
+                    &attachment.size_bytes,
+                    &attachment.storage_key,
+                    &attachment.thumbnail_key,
+                ],
+            )
+            .await?;
+
+        Ok(Attachment::from_row(&row))
+    }
+
+    pub async fn find_by_id(client: &Client, attachment_id: i32) -> Result<Option<Attachment>, Error> {
+        let result = client
+            .query_opt(
+                "SELECT id, room_id, uploader_id, message_id, file_name, content_type, 
+                        size_bytes, storage_key, thumbnail_key, created_at 
+                 FROM attachments WHERE id = $1",
+                &[&attachment_id],
+            )
+            .await?;
+
+        Ok(result.as_ref().map(Attachment::from_row))
+    }
+
+    // Storage keys of every blob belonging to the room's attachments
+    pub async fn storage_keys_for_room(client: &Client, room_id: i32) -> Result<Vec<String>, Error> {
+        let rows = client
+            .query(
+                "SELECT storage_key, thumbnail_key FROM attachments WHERE room_id = $1",
+                &[&room_id],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .flat_map(|row| {
+                let thumbnail: Option<String> = row.get(1);
+                std::iter::once(row.get(0)).chain(thumbnail)
+            })
+            .collect())
+    }
+
+    // Links the uploader's own, not yet linked attachments in the room to a message; those of
+    // archived messages stay with them. Returns the attachments that were actually linked.
+    pub async fn link_to_message(
+        client: &Client,
+        attachment_ids: &[i32],
+        message_id: i32,
+        room_id: i32,
+        uploader_id: i32,
+    ) -> Result<Vec<Attachment>, Error> {
+        let rows = client
+            .query(
+                "UPDATE attachments SET message_id = $1 
+                 WHERE id = ANY($2) AND room_id = $3 AND uploader_id = $4
+                   AND message_id IS NULL AND archived_message_id IS NULL
+                 RETURNING id, room_id, uploader_id, message_id, file_name, content_type, 
+                           size_bytes, storage_key, thumbnail_key, created_at",
+                &[&message_id, &attachment_ids, &room_id, &uploader_id],
+            )
+            .await?;
+
+        Ok(rows.iter().map(Attachment::from_row).collect())
+    }
+}
+
+// Database operations for bans and mutes
+impl SanctionKind {
+    fn table(self) -> &'static str {
+        match self {
+            SanctionKind::Ban => "room_bans",
+            SanctionKind::Mute => "room_mutes",
+        }
+    }
+}
+
+impl Sanction {
+    fn from_row(kind: SanctionKind, row: &Row) -> Sanction {
+        Sanction {
+            kind,
+            room_id: row.get(0),
+            user_id: row.get(1),
+            username: row.get(2),
+            created_by: row.get(3),
+            reason: row.get(4),
+            expires_at: to_utc(row.get(5)),
+            created_at: to_utc(row.get(6)),
+        }
+    }
+
+    // Creates or replaces the user's ban/mute in the room. A ban also ends the membership,
+    // in the same transaction so a failure leaves neither in place.
+    pub async fn upsert(client: &mut Client, sanction: &Sanction) -> Result<Sanction, Error> {
+        let transaction = client.transaction().await?;
+        let query = format!(
+            "WITH s AS (
+                 INSERT INTO {table} (room_id, user_id, created_by, reason, expires_at)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (room_id, user_id) DO UPDATE
+                 SET created_by = EXCLUDED.created_by, reason = EXCLUDED.reason,
+                     expires_at = EXCLUDED.expires_at, created_at = CURRENT_TIMESTAMP
+                 RETURNING room_id, user_id, created_by, reason, expires_at, created_at
+             )
+             SELECT s.room_id, s.user_id, u.username, s.created_by, s.reason, s.expires_at, s.created_at
+             FROM s JOIN users u ON u.id = s.user_id",
+            table = sanction.kind.table()
+        );
+        let expires_at = sanction.expires_at.map(|at| at.naive_utc());
+        let row = transaction
+            .query_one(
+                query.as_str(),
+                &[&sanction.room_id, &sanction.user_id, &sanction.created_by, &sanction.reason, &expires_at],
+            )
+            .await?;
+        if sanction.kind == SanctionKind::Ban {
+            transaction
+                .execute(
+                    "DELETE FROM room_members WHERE room_id = $1 AND user_id = $2",
+                    &[&sanction.room_id, &sanction.user_id],
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+
+        Ok(Sanction::from_row(sanction.kind, &row))
+    }
+
+    // Lifts a ban/mute; returns whether one existed
+    pub async fn remove(client: &Client, kind: SanctionKind, room_id: i32, user_id: i32) -> Result<bool, Error> {
+        let query = format!("DELETE FROM {} WHERE room_id = $1 AND user_id = $2", kind.table());
+        let removed = client.execute(query.as_str(), &[&room_id, &user_id]).await?;
+        Ok(removed > 0)
+    }
+
+    // The user's ban/mute in the room if it has not expired
+    pub async fn find_active(client: &Client, kind: SanctionKind, room_id: i32, user_id: i32) -> Result<Option<Sanction>, Error> {
+        let query = format!(
+            "SELECT s.room_id, s.user_id, u.username, s.created_by, s.reason, s.expires_at, s.created_at
+             FROM {} s JOIN users u ON u.id = s.user_id
+             WHERE s.room_id = $1 AND s.user_id = $2
+               AND (s.expires_at IS NULL OR s.expires_at > NOW() AT TIME ZONE 'UTC')",
+            kind.table()
+        );
+        let result = client.query_opt(query.as_str(), &[&room_id, &user_id]).await?;
+        Ok(result.map(|row| Sanction::from_row(kind, &row)))
+    }
+
+    pub async fn find_active_by_room(client: &Client, kind: SanctionKind, room_id: i32) -> Result<Vec<Sanction>, Error> {
+        let query = format!(
+            "SELECT s.room_id, s.user_id, u.username, s.created_by, s.reason, s.expires_at, s.created_at
+             FROM {} s JOIN users u ON u.id = s.user_id
+             WHERE s.room_id = $1
+               AND (s.expires_at IS NULL OR s.expires_at > NOW() AT TIME ZONE 'UTC')
+             ORDER BY s.created_at DESC",
+            kind.table()
+        );
+        let rows = client.query(query.as_str(), &[&room_id]).await?;
+        Ok(rows.iter().map(|row| Sanction::from_row(kind, row)).collect())
+    }
+}
+
+impl Report {
+    const COLUMNS: &'static str = "r.id, r.room_id, r.message_id, r.sender_id, s.username, r.content,
+        r.reporter_id, rp.username, r.reason, r.status, r.resolution, r.resolved_by, r.resolved_at, r.created_at";
+    const JOINS: &'static str = "LEFT JOIN users s ON s.id = r.sender_id LEFT JOIN users rp ON rp.id = r.reporter_id";
+    const VISIBLE: &'static str = "r.room_id IN (SELECT room_id FROM room_members WHERE user_id = $1 AND role = ANY($2))
+        AND ($3::INTEGER IS NULL OR r.room_id = $3)
+        AND ($4::VARCHAR IS NULL OR r.status = $4)";
+
+    fn from_row(row: &Row) -> Report {
+        Report {
+            id: row.get(0),
+            room_id: row.get(1),
+            message_id: row.get(2),
+            sender_id: row.get(3),
+            sender_username: row.get(4),
+            content: row.get(5),
+            reporter_id: row.get(6),
+            reporter_username: row.get(7),
+            reason: row.get(8),
+            status: row.get(9),
+            resolution: row.get(10),
+            resolved_by: row.get(11),
+            resolved_at: to_utc(row.get(12)),
+            created_at: to_utc(row.get(13)),
+        }
+    }
+
+    // Files a report on a message; `reporter_id` is None when a filter flagged it
+    pub async fn create(client: &Client, message: &Message, reporter_id: Option<i32>, reason: &str) -> Result<Report, Error> {
+        let query = format!(
+            "WITH r AS (
+                 INSERT INTO reports (room_id, message_id, sender_id, content, reporter_id, reason)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 RETURNING *
+             )
+             SELECT {} FROM r {}",
+            Report::COLUMNS,
+            Report::JOINS
+        );
+        let row = client
+            .query_one(
+                query.as_str(),
+                &[&message.room_id, &message.id, &message.sender_id, &message.content, &reporter_id, &reason],
+            )
+            .await?;
+        Ok(Report::from_row(&row))
+    }
+
+    pub async fn find_by_id(client: &Client, report_id: i32) -> Result<Option<Report>, Error> {
+        let query = format!("SELECT {} FROM reports r {} WHERE r.id = $1", Report::COLUMNS, Report::JOINS);
+        let row = client.query_opt(query.as_str(), &[&report_id]).await?;
+        Ok(row.as_ref().map(Report::from_row))
+    }
+
+    // Oldest first, so the queue is worked through in order
+    pub async fn find(client: &Client, filter: &ReportFilter, limit: i64, offset: i64) -> Result<(Vec<Report>, i64), Error> {
+        let query = format!(
+            "SELECT {} FROM reports r {} WHERE {} ORDER BY r.created_at, r.id LIMIT $5 OFFSET $6",
+            Report::COLUMNS,
+            Report::JOINS,
+            Report::VISIBLE
+        );
+        let rows = client
+            .query(
+                query.as_str(),
+                &[&filter.reviewer_id, &filter.roles, &filter.room_id, &filter.status, &limit, &offset],
+            )
+            .await?;
+
+        let count_query = format!("SELECT COUNT(*) FROM reports r WHERE {}", Report::VISIBLE);
+        let total: i64 = client
+            .query_one(count_query.as_str(), &[&filter.reviewer_id, &filter.roles, &filter.room_id, &filter.status])
+            .await?
+            .get(0);
+
+        Ok((rows.iter().map(Report::from_row).collect(), total))
+    }
+
+    // Up to `around` messages before the reported one, the message itself and up to `around` after it
+    pub async fn context(&self, client: &Client, around: i64) -> Result<Vec<ContextMessage>, Error> {
+        let Some(message_id) = self.message_id else {
+            return Ok(Vec::new());
+        };
+        let rows = client
+            .query(
+                "SELECT id, sender_id, username, content, created_at FROM (
+                     (SELECT m.id, m.sender_id, u.username, m.content, m.created_at
+                      FROM messages m LEFT JOIN users u ON u.id = m.sender_id
+                      WHERE m.room_id = $1
+                        AND (m.created_at, m.id) < (SELECT created_at, id FROM messages WHERE id = $2)
+                      ORDER BY m.created_at DESC, m.id DESC
+                      LIMIT $3)
+                     UNION ALL
+                     (SELECT m.id, m.sender_id, u.username, m.content, m.created_at
+                      FROM messages m LEFT JOIN users u ON u.id = m.sender_id
+                      WHERE m.room_id = $1
+                        AND (m.created_at, m.id) >= (SELECT created_at, id FROM messages WHERE id = $2)
+                      ORDER BY m.created_at, m.id
+                      LIMIT $3 + 1)
+                 ) c
+                 ORDER BY created_at, id",
+                &[&self.room_id, &message_id, &around],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| ContextMessage {
+                id: row.get(0),
+                sender_id: row.get(1),
+                sender_username: row.get(2),
+                content: row.get(3),
+                created_at: to_utc(row.get(4)),
+            })
+            .collect())
+    }
+
+    // Closes this report and every other open report on the same message, then deletes the
+    // message when asked to. Both happen in one transaction, and in that order because deleting
+    // the message clears `message_id` on its reports. Returns the updated report and whether the
+    // message was deleted.
+    pub async fn resolve(
+        &self,
+        client: &mut Client,
+        status: &str,
+        resolution: &str,
+        resolved_by: i32,
+        delete_message: bool,
+    ) -> Result<Option<(Report, bool)>, Error> {
+        let transaction = client.transaction().await?;
+        transaction
+            .execute(
+                "UPDATE reports SET status = $1, resolution = $2, resolved_by = $3, resolved_at = NOW() AT TIME ZONE 'UTC'
+                 WHERE status = 'open' AND (id = $4 OR message_id = $5)",
+                &[&status, &resolution, &resolved_by, &self.id, &self.message_id],
+            )
+            .await?;
+        let deleted = match (delete_message, self.message_id) {
+            (true, Some(message_id)) => transaction.execute("DELETE FROM messages WHERE id = $1", &[&message_id]).await? > 0,
+            _ => false,
+        };
+        let query = format!("SELECT {} FROM reports r {} WHERE r.id = $1", Report::COLUMNS, Report::JOINS);
+        let row = transaction.query_opt(query.as_str(), &[&self.id]).await?;
+        transaction.commit().await?;
+
+        Ok(row.as_ref().map(|row| (Report::from_row(row), deleted)))
+    }
+}
+
+impl AuditEntry {
+    pub async fn create(client: &Client, entry: &AuditEntry) -> Result<(), Error> {
+        client
+            .execute(
+                "INSERT INTO audit_log (actor_id, action, target_type, target_id, room_id, before, after, ip, request_id)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+                &[
+                    &entry.actor_id,
+                    &entry.action,
+                    &entry.target_type,
+                    &entry.target_id,
+                    &entry.room_id,
+                    &entry.before,
+                    &entry.after,
+                    &entry.ip,
+                    &entry.request_id,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+impl AuditRecord {
+    const VISIBLE: &'static str = "($1::INTEGER IS NULL
+            OR a.room_id IN (SELECT room_id FROM room_members WHERE user_id = $1 AND role = ANY($2)))
+        AND ($3::INTEGER IS NULL OR a.room_id = $3)
+        AND ($4::INTEGER IS NULL OR a.actor_id = $4)
+        AND ($5::VARCHAR IS NULL OR a.action = $5)
+        AND ($6::VARCHAR IS NULL OR a.target_type = $6)
+        AND ($7::VARCHAR IS NULL OR a.target_id = $7)
+        AND ($8::TIMESTAMP IS NULL OR a.created_at >= $8)
+        AND ($9::TIMESTAMP IS NULL OR a.created_at < $9)";
+
+    // Newest first
+    pub async fn find(client: &Client, filter: &AuditFilter, limit: i64, offset: i64) -> Result<(Vec<AuditRecord>, i64), Error> {
+        let from = filter.from.map(|at| at.naive_utc());
+        let to = filter.to.map(|at| at.naive_utc());
+        let params: [&(dyn ToSql + Sync); 9] = [
+            &filter.viewer_id,
+            &filter.roles,
+            &filter.room_id,
+            &filter.actor_id,
+            &filter.action,
+            &filter.target_type,
+            &filter.target_id,
+            &from,
+            &to,
+        ];
+
+        let query = format!(
+            "SELECT a.id, a.actor_id, u.username, a.action, a.target_type, a.target_id, a.room_id,
+                    a.before, a.after, a.ip, a.request_id, a.created_at
+             FROM audit_log a LEFT JOIN users u ON u.id = a.actor_id
+             WHERE {}
+             ORDER BY a.created_at DESC, a.id DESC
+             LIMIT $10 OFFSET $11",
+            AuditRecord::VISIBLE
+        );
+        let mut page_params = params.to_vec();
+        page_params.extend_from_slice(&[&limit, &offset]);
+        let rows = client.query(query.as_str(), &page_params).await?;
+
+        let count_query = format!("SELECT COUNT(*) FROM audit_log a WHERE {}", AuditRecord::VISIBLE);
+        let total: i64 = client.query_one(count_query.as_str(), &params).await?.get(0);
+
+        let records = rows
+            .iter()
+            .map(|row| AuditRecord {
+                id: row.get(0),
+                actor_id: row.get(1),
+                actor_username: row.get(2),
+                action: row.get(3),
+                target_type: row.get(4),
+                target_id: row.get(5),
+                room_id: row.get(6),
+                before: row.get(7),
+                after: row.get(8),
+                ip: row.get(9),
+                request_id: row.get(10),
+                created_at: to_utc(row.get(11)),
+            })
+            .collect();
+        Ok((records, total))
+    }
+}
+
+impl ServerStats {
+    pub async fn collect(client: &Client) -> Result<ServerStats, Error> {
+        let row = client
+            .query_one(
+                "SELECT
+                     (SELECT COUNT(*) FROM users),
+                     (SELECT COUNT(*) FROM users WHERE is_admin),
+                     (SELECT COUNT(*) FROM users WHERE suspended_at IS NOT NULL),
+                     (SELECT COUNT(*) FROM rooms),
+                     (SELECT COUNT(*) FROM rooms WHERE archived_at IS NOT NULL),
+                     (SELECT COUNT(*) FROM room_members),
+                     (SELECT COUNT(*) FROM messages),
+                     (SELECT COUNT(*) FROM messages WHERE created_at > NOW() AT TIME ZONE 'UTC' - INTERVAL '1 day'),
+                     (SELECT MIN(created_at) FROM messages),
+                     (SELECT COUNT(*) FROM attachments),
+                     (SELECT COALESCE(SUM(size_bytes), 0)::BIGINT FROM attachments),
+                     (SELECT COUNT(*) FROM reports WHERE status = 'open'),
+                     pg_database_size(current_database())",
+                &[],
+            )
+            .await?;
+
+        Ok(ServerStats {
+            users: row.get(0),
+            admins: row.get(1),
+            suspended_users: row.get(2),
+            rooms: row.get(3),
+            archived_rooms: row.get(4),
+            memberships: row.get(5),
+            messages: row.get(6),
+            messages_last_day: row.get(7),
+            oldest_message_at: to_utc(row.get(8)),
+            attachments: row.get(9),
+            attachment_bytes: row.get(10),
+            open_reports: row.get(11),
+            database_bytes: row.get(12),
+        })
+    }
+}
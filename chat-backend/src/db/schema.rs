@@ -1,5 +1,49 @@
 use tokio_postgres::Client;
 
+// Changes to existing tables that lock and scan them. Each runs once, in its own transaction, and
+// is recorded in schema_migrations so later starts skip it.
+const MIGRATIONS: [(&str, &str); 4] = [
+    (
+        "room_members_role_check",
+        "-- Widen the original admin/member role check to the full role hierarchy
+        ALTER TABLE room_members DROP CONSTRAINT IF EXISTS room_members_role_check;
+        ALTER TABLE room_members ADD CONSTRAINT room_members_role_check
+            CHECK (role IN ('owner', 'admin', 'moderator', 'member', 'read_only'));",
+    ),
+    (
+        "messages_content_length_check",
+        "-- Hard ceiling for message length (messages.max_length is enforced by the server); NOT VALID
+        -- leaves any longer messages stored before the limit existed alone
+        ALTER TABLE messages DROP CONSTRAINT IF EXISTS messages_content_length_check;
+        ALTER TABLE messages ADD CONSTRAINT messages_content_length_check
+            CHECK (char_length(content) <= 65536) NOT VALID;",
+    ),
+    (
+        "room_id_cascade_fkeys",
+        "-- Deleting a room takes its members and messages with it
+        ALTER TABLE room_members DROP CONSTRAINT IF EXISTS room_members_room_id_fkey;
+        ALTER TABLE room_members ADD CONSTRAINT room_members_room_id_fkey
+            FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE;
+        ALTER TABLE messages DROP CONSTRAINT IF EXISTS messages_room_id_fkey;
+        ALTER TABLE messages ADD CONSTRAINT messages_room_id_fkey
+            FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE;
+        ALTER TABLE attachments DROP CONSTRAINT IF EXISTS attachments_room_id_fkey;
+        ALTER TABLE attachments ADD CONSTRAINT attachments_room_id_fkey
+            FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE;
+        ALTER TABLE room_bans DROP CONSTRAINT IF EXISTS room_bans_room_id_fkey;
+        ALTER TABLE room_bans ADD CONSTRAINT room_bans_room_id_fkey
+            FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE;
+        ALTER TABLE room_mutes DROP CONSTRAINT IF EXISTS room_mutes_room_id_fkey;
+        ALTER TABLE room_mutes ADD CONSTRAINT room_mutes_room_id_fkey
+            FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE;",
+    ),
+    (
+        "rooms_retention_days_check",
+        "ALTER TABLE rooms DROP CONSTRAINT IF EXISTS rooms_retention_days_check;
+        ALTER TABLE rooms ADD CONSTRAINT rooms_retention_days_check CHECK (retention_days >= 0);",
+    ),
+];
+
 pub async fn create_tables(client: &Client) -> Result<(), tokio_postgres::Error> {
     client.batch_execute("
         CREATE TABLE IF NOT EXISTS users (
@@ -20,19 +64,233 @@ pub async fn create_tables(client: &Client) -> Result<(), tokio_postgres::Error>
         );
 
         CREATE TABLE IF NOT EXISTS room_members (
-            room_id INTEGER REFERENCES rooms(id),
+            room_id INTEGER REFERENCES rooms(id) ON DELETE CASCADE,
             user_id INTEGER REFERENCES users(id),
-            role VARCHAR(50) DEFAULT 'member' CHECK (role IN ('admin', 'member')),
+            role VARCHAR(50) DEFAULT 'member'
+                CONSTRAINT room_members_role_check
+                CHECK (role IN ('owner', 'admin', 'moderator', 'member', 'read_only')),
             joined_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
             PRIMARY KEY (room_id, user_id)
         );
 
         CREATE TABLE IF NOT EXISTS messages (
             id SERIAL PRIMARY KEY,
-            room_id INTEGER REFERENCES rooms(id),
+            room_id INTEGER REFERENCES rooms(id) ON DELETE CASCADE,
             sender_id INTEGER REFERENCES users(id),
             content TEXT NOT NULL,
             created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
         );
-    ").await
-}
\ No newline at end of file
+
+        CREATE TABLE IF NOT EXISTS auth_tokens (
+            token VARCHAR(64) PRIMARY KEY,
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            expires_at TIMESTAMP NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS attachments (
+            id SERIAL PRIMARY KEY,
+            room_id INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+            uploader_id INTEGER NOT NULL REFERENCES users(id),
+            message_id INTEGER REFERENCES messages(id) ON DELETE SET NULL,
+            file_name VARCHAR(255) NOT NULL,
+            content_type VARCHAR(255) NOT NULL,
+            size_bytes BIGINT NOT NULL,
+            storage_key VARCHAR(255) NOT NULL,
+            thumbnail_key VARCHAR(255),
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+
+        CREATE INDEX IF NOT EXISTS attachments_message_id_idx ON attachments (message_id);
+        CREATE INDEX IF NOT EXISTS messages_room_id_created_at_idx ON messages (room_id, created_at);
+
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS display_name VARCHAR(64);
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS bio VARCHAR(500);
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS status_text VARCHAR(128);
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS status_expires_at TIMESTAMP;
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS last_seen_at TIMESTAMP;
+
+        CREATE UNIQUE INDEX IF NOT EXISTS users_display_name_key ON users (LOWER(display_name));
+
+        CREATE TABLE IF NOT EXISTS room_bans (
+            room_id INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+            user_id INTEGER NOT NULL REFERENCES users(id),
+            created_by INTEGER REFERENCES users(id),
+            reason VARCHAR(500),
+            expires_at TIMESTAMP,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (room_id, user_id)
+        );
+
+        CREATE TABLE IF NOT EXISTS room_mutes (
+            room_id INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+            user_id INTEGER NOT NULL REFERENCES users(id),
+            created_by INTEGER REFERENCES users(id),
+            reason VARCHAR(500),
+            expires_at TIMESTAMP,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (room_id, user_id)
+        );
+
+        ALTER TABLE rooms ADD COLUMN IF NOT EXISTS description VARCHAR(1000);
+        ALTER TABLE rooms ADD COLUMN IF NOT EXISTS topic VARCHAR(255);
+        ALTER TABLE rooms ADD COLUMN IF NOT EXISTS archived_at TIMESTAMP;
+
+        -- Shadow-hidden messages were only ever shown to their sender
+        ALTER TABLE messages ADD COLUMN IF NOT EXISTS shadow_hidden BOOLEAN NOT NULL DEFAULT FALSE;
+
+        -- Reported messages awaiting review. The sender and content are copied so a report
+        -- still makes sense once the message is deleted; filter flags have no reporter.
+        CREATE TABLE IF NOT EXISTS reports (
+            id SERIAL PRIMARY KEY,
+            room_id INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+            message_id INTEGER REFERENCES messages(id) ON DELETE SET NULL,
+            sender_id INTEGER REFERENCES users(id) ON DELETE SET NULL,
+            content TEXT NOT NULL,
+            reporter_id INTEGER REFERENCES users(id) ON DELETE SET NULL,
+            reason VARCHAR(500) NOT NULL,
+            status VARCHAR(16) NOT NULL DEFAULT 'open' CHECK (status IN ('open', 'dismissed', 'resolved')),
+            resolution VARCHAR(32),
+            resolved_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+            resolved_at TIMESTAMP,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE INDEX IF NOT EXISTS reports_room_id_status_idx ON reports (room_id, status);
+        CREATE INDEX IF NOT EXISTS reports_message_id_idx ON reports (message_id);
+        -- One open report per message and reporter
+        CREATE UNIQUE INDEX IF NOT EXISTS reports_open_reporter_key ON reports (message_id, reporter_id)
+            WHERE status = 'open';
+
+        -- Who did what to which object. Actors and targets are plain ids, not foreign keys,
+        -- so entries outlive the rows they describe.
+        CREATE TABLE IF NOT EXISTS audit_log (
+            id BIGSERIAL PRIMARY KEY,
+            actor_id INTEGER,
+            action VARCHAR(64) NOT NULL,
+            target_type VARCHAR(32) NOT NULL,
+            target_id VARCHAR(64),
+            room_id INTEGER,
+            before JSONB,
+            after JSONB,
+            ip VARCHAR(64),
+            request_id VARCHAR(64),
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE INDEX IF NOT EXISTS audit_log_created_at_idx ON audit_log (created_at);
+        CREATE INDEX IF NOT EXISTS audit_log_room_id_created_at_idx ON audit_log (room_id, created_at);
+        CREATE INDEX IF NOT EXISTS audit_log_actor_id_idx ON audit_log (actor_id);
+        CREATE INDEX IF NOT EXISTS audit_log_target_idx ON audit_log (target_type, target_id);
+
+        -- The audit log is append-only: entries can't be changed or removed, even by the server
+        CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+        BEGIN
+            RAISE EXCEPTION 'audit_log is append-only';
+        END;
+        $$ LANGUAGE plpgsql;
+        DROP TRIGGER IF EXISTS audit_log_no_change ON audit_log;
+        CREATE TRIGGER audit_log_no_change BEFORE UPDATE OR DELETE ON audit_log
+            FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
+        DROP TRIGGER IF EXISTS audit_log_no_truncate ON audit_log;
+        CREATE TRIGGER audit_log_no_truncate BEFORE TRUNCATE ON audit_log
+            FOR EACH STATEMENT EXECUTE FUNCTION audit_log_append_only();
+
+        -- Server-wide administrators and account suspension
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS is_admin BOOLEAN NOT NULL DEFAULT FALSE;
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS suspended_at TIMESTAMP;
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS suspension_reason VARCHAR(500);
+
+        -- Days a room keeps its messages: NULL follows retention.default_days, 0 keeps them forever
+        ALTER TABLE rooms ADD COLUMN IF NOT EXISTS retention_days INTEGER;
+
+        -- Expired messages when retention.mode is archive, no longer part of any room's history
+        CREATE TABLE IF NOT EXISTS messages_archive (
+            id INTEGER PRIMARY KEY,
+            room_id INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+            sender_id INTEGER REFERENCES users(id),
+            content TEXT NOT NULL,
+            shadow_hidden BOOLEAN NOT NULL DEFAULT FALSE,
+            created_at TIMESTAMP,
+            archived_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE INDEX IF NOT EXISTS messages_archive_room_id_created_at_idx ON messages_archive (room_id, created_at);
+        CREATE INDEX IF NOT EXISTS messages_archive_sender_id_idx ON messages_archive (sender_id);
+        -- Attachments of archived messages stay, pointing at the archived copy
+        ALTER TABLE attachments ADD COLUMN IF NOT EXISTS archived_message_id INTEGER
+            REFERENCES messages_archive(id) ON DELETE SET NULL;
+        CREATE INDEX IF NOT EXISTS attachments_archived_message_id_idx ON attachments (archived_message_id);
+
+        -- The placeholder account each author of an imported export posts as, by the export's id
+        CREATE TABLE IF NOT EXISTS import_authors (
+            source VARCHAR(16) NOT NULL,
+            external_id VARCHAR(255) NOT NULL,
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            PRIMARY KEY (source, external_id)
+        );
+
+        -- Rooms from before owners existed: the longest-standing admin becomes owner
+        UPDATE room_members SET role = 'owner'
+        WHERE (room_id, user_id) IN (
+            SELECT DISTINCT ON (room_id) room_id, user_id
+            FROM room_members
+            WHERE role = 'admin'
+              AND room_id NOT IN (SELECT room_id FROM room_members WHERE role = 'owner')
+            ORDER BY room_id, joined_at, user_id
+        );
+    ").await?;
+    migrate(client).await
+}
+
+async fn migrate(client: &Client) -> Result<(), tokio_postgres::Error> {
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                 name VARCHAR(64) PRIMARY KEY,
+                 applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+             )",
+        )
+        .await?;
+    let applied: Vec<String> = client
+        .query("SELECT name FROM schema_migrations", &[])
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+
+    for (name, sql) in MIGRATIONS {
+        if applied.iter().any(|applied| applied == name) {
+            continue;
+        }
+        let batch = format!(
+            "BEGIN;
+             {}
+             INSERT INTO schema_migrations (name) VALUES ('{}') ON CONFLICT (name) DO NOTHING;
+             COMMIT;",
+            sql, name
+        );
+        if let Err(e) = client.batch_execute(&batch).await {
+            client.batch_execute("ROLLBACK").await.ok();
+            return Err(e);
+        }
+        log::info!("Applied schema migration {}", name);
+    }
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::test_support;
+
+    #[actix_web::test]
+    async fn migrations_run_once() {
+        let Some(pool) = test_support::pool().await else { return };
+        let client = pool.get().await.unwrap();
+        let constraint = "SELECT oid FROM pg_constraint WHERE conname = 'room_members_role_check'";
+        let before: u32 = client.query_one(constraint, &[]).await.unwrap().get(0);
+
+        create_tables(&client).await.unwrap();
+        let after: u32 = client.query_one(constraint, &[]).await.unwrap().get(0);
+        assert_eq!(before, after);
+        let applied = client.query("SELECT name FROM schema_migrations", &[]).await.unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+    }
+}
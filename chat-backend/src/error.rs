@@ -0,0 +1,210 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{HttpResponse, ResponseError};
+use deadpool_postgres::PoolError;
+use serde::Serialize;
+use std::fmt;
+use tokio_postgres::error::SqlState;
+use crate::handlers::validation::FieldError;
+use crate::models::permissions::AuthzError;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// Id of the request being handled, if called from within one
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// Tags each request with an id (reusing a sane incoming X-Request-Id), makes it
+// available to error responses and echoes it back in the response headers
+pub async fn request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 64 && value.chars().all(|c| c.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let mut res = REQUEST_ID.scope(id.clone(), next.call(req)).await?;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(res)
+}
+
+// Every failure a handler can report. Client-facing messages never include
+// database or driver details; those are logged with the request id instead.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    PayloadTooLarge(String),
+    // Seconds until the client may retry
+    TooManyRequests(u64),
+    Unprocessable(String),
+    Unavailable(String),
+    Validation(Vec<FieldError>),
+    Pool(PoolError),
+    Database(tokio_postgres::Error),
+    Internal(String),
+}
+
+impl AppError {
+    pub fn internal(error: impl fmt::Display) -> AppError {
+        AppError::Internal(error.to_string())
+    }
+
+    fn database_status(error: &tokio_postgres::Error) -> (StatusCode, &'static str) {
+        let Some(db_error) = error.as_db_error() else {
+            return (StatusCode::SERVICE_UNAVAILABLE, "The database is temporarily unavailable");
+        };
+        match db_error.code() {
+            code if code == &SqlState::UNIQUE_VIOLATION => (StatusCode::CONFLICT, "A resource with these values already exists"),
+            code if code == &SqlState::FOREIGN_KEY_VIOLATION => {
+                // Inserts pointing at a missing row vs. deletes of a row that is still referenced
+                if db_error.detail().is_some_and(|detail| detail.contains("is not present")) {
+                    (StatusCode::NOT_FOUND, "A referenced resource does not exist")
+                } else {
+                    (StatusCode::UNPROCESSABLE_ENTITY, "The resource is still referenced by other records")
+                }
+            }
+            code if code == &SqlState::CHECK_VIOLATION || code == &SqlState::NOT_NULL_VIOLATION => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "The request contains invalid values")
+            }
+            code if code == &SqlState::T_R_SERIALIZATION_FAILURE || code == &SqlState::T_R_DEADLOCK_DETECTED => {
+                (StatusCode::SERVICE_UNAVAILABLE, "The request conflicted with another one; please retry")
+            }
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error"),
+        }
+    }
+
+    // What the client is told; server-side detail stays in the logs
+    pub fn client_message(&self) -> String {
+        match self {
+            AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::NotFound(message)
+            | AppError::Conflict(message)
+            | AppError::PayloadTooLarge(message)
+            | AppError::Unprocessable(message)
+            | AppError::Unavailable(message) => message.clone(),
+            AppError::TooManyRequests(secs) => format!("Too many requests; try again in {}s", secs),
+            AppError::Validation(_) => "Validation failed".to_string(),
+            AppError::Pool(_) => "The server is busy; please retry shortly".to_string(),
+            AppError::Database(e) => AppError::database_status(e).1.to_string(),
+            AppError::Internal(_) => "Internal server error".to_string(),
+        }
+    }
+
+    // Server faults are logged as errors with the request id; client mistakes only at debug level
+    pub fn log(&self) {
+        let status = self.status_code();
+        let request_id = current_request_id();
+        let id = request_id.as_deref().unwrap_or("-");
+        match self {
+            AppError::Pool(_) | AppError::Database(_) | AppError::Internal(_) if status.is_server_error() => {
+                log::error!("[{}] {}", id, self)
+            }
+            AppError::Database(_) => log::warn!("[{}] {}", id, self),
+            _ => log::debug!("[{}] {} {}", id, status.as_u16(), self),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Validation(errors) => write!(f, "validation failed: {:?}", errors),
+            AppError::Pool(e) => write!(f, "database pool error: {}", e),
+            AppError::Database(e) => write!(f, "database error: {:?}", e),
+            AppError::Internal(message) => write!(f, "internal error: {}", message),
+            other => write!(f, "{}", other.client_message()),
+        }
+    }
+}
+
+// The `ApiResponse` envelope, plus the request id for correlating with server logs
+#[derive(Serialize)]
+struct ErrorBody {
+    success: bool,
+    message: Option<String>,
+    data: Option<Vec<FieldError>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unprocessable(_) | AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Unavailable(_) | AppError::Pool(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(e) => AppError::database_status(e).0,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.log();
+
+        let data = match self {
+            AppError::Validation(errors) => Some(errors.clone()),
+            _ => None,
+        };
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::TooManyRequests(secs) = self {
+            response.insert_header((RETRY_AFTER, secs.to_string()));
+        }
+        response.json(ErrorBody {
+            success: false,
+            message: Some(self.client_message()),
+            data,
+            request_id: current_request_id(),
+        })
+    }
+}
+
+impl From<PoolError> for AppError {
+    fn from(error: PoolError) -> Self {
+        AppError::Pool(error)
+    }
+}
+
+impl From<tokio_postgres::Error> for AppError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        AppError::Database(error)
+    }
+}
+
+impl From<AuthzError> for AppError {
+    fn from(error: AuthzError) -> Self {
+        match error {
+            AuthzError::RoomNotFound => AppError::NotFound(error.message()),
+            AuthzError::NotMember | AuthzError::Archived | AuthzError::Forbidden(_) => {
+                AppError::Forbidden(error.message())
+            }
+            AuthzError::Database(e) => AppError::Database(e),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use super::{FilterInput, FilterMatch, MessageFilter};
+use crate::settings::{DuplicateFilterSettings, FilterAction};
+
+// Senders tracked before idle ones are forgotten
+const MAX_TRACKED_SENDERS: usize = 10_000;
+
+// When each recent message was sent, with its fingerprint; oldest first
+type Sent = VecDeque<(Instant, u64)>;
+
+// Catches a sender repeating the same message in a room, ignoring case and spacing
+pub struct DuplicateFilter {
+    action: FilterAction,
+    max_repeats: usize,
+    window: Duration,
+    // Keyed by room and sender
+    recent: Mutex<HashMap<(i32, String), Sent>>,
+}
+
+fn fingerprint(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for word in text.split_whitespace() {
+        word.to_lowercase().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+impl DuplicateFilter {
+    pub fn new(settings: &DuplicateFilterSettings) -> DuplicateFilter {
+        DuplicateFilter {
+            action: settings.action,
+            max_repeats: settings.max_repeats,
+            window: Duration::from_secs(settings.window_secs),
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check_at(&self, input: &FilterInput<'_>, now: Instant) -> Option<FilterMatch> {
+        let window = self.window;
+        let expired = |at: &Instant| now.saturating_duration_since(*at) > window;
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= MAX_TRACKED_SENDERS {
+            recent.retain(|_, sent| sent.back().is_some_and(|(at, _)| !expired(at)));
+        }
+
+        let sent = recent.entry((input.room_id, input.sender.to_string())).or_default();
+        while sent.front().is_some_and(|(at, _)| expired(at)) {
+            sent.pop_front();
+        }
+        let fingerprint = fingerprint(input.text);
+        let repeats = sent.iter().filter(|(_, sent)| *sent == fingerprint).count();
+        sent.push_back((now, fingerprint));
+        // Only the last few messages can matter for the count
+        if sent.len() > self.max_repeats.saturating_mul(4).max(16) {
+            sent.pop_front();
+        }
+
+        (repeats >= self.max_repeats).then(|| FilterMatch {
+            rule: "duplicates",
+            action: self.action,
+            reason: "You are sending the same message too often".to_string(),
+            masked: None,
+        })
+    }
+}
+
+impl MessageFilter for DuplicateFilter {
+    fn check(&self, input: &FilterInput<'_>) -> Option<FilterMatch> {
+        self.check_at(input, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_within_the_window_match() {
+        let filter = DuplicateFilter::new(&DuplicateFilterSettings {
+            max_repeats: 2,
+            window_secs: 10,
+            ..DuplicateFilterSettings::default()
+        });
+        let input = |room_id, sender, text| FilterInput { room_id, sender, text };
+        let start = Instant::now();
+
+        assert!(filter.check_at(&input(1, "alice", "buy now"), start).is_none());
+        assert!(filter.check_at(&input(1, "alice", "Buy   NOW"), start).is_none());
+        // Other rooms and other senders are tracked separately
+        assert!(filter.check_at(&input(2, "alice", "buy now"), start).is_none());
+        assert!(filter.check_at(&input(1, "bob", "buy now"), start).is_none());
+
+        let found = filter.check_at(&input(1, "alice", "buy now"), start).unwrap();
+        assert_eq!(found.action, FilterAction::Reject);
+        assert!(filter.check_at(&input(1, "alice", "buy now"), start + Duration::from_secs(11)).is_none());
+    }
+}
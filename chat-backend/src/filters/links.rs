@@ -0,0 +1,147 @@
+use std::ops::Range;
+use super::{FilterInput, FilterMatch, MessageFilter};
+use crate::settings::{FilterAction, LinkFilterSettings};
+
+const REMOVED: &str = "[link removed]";
+
+// Catches links to blocked domains and messages with more links than a conversation needs
+pub struct LinkFilter {
+    action: FilterAction,
+    max_links: usize,
+    blocked_domains: Vec<String>,
+    allowed_domains: Vec<String>,
+}
+
+struct Link {
+    range: Range<usize>,
+    host: String,
+}
+
+fn domains(domains: &[String]) -> Vec<String> {
+    domains
+        .iter()
+        .map(|domain| domain.trim().trim_start_matches('.').to_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect()
+}
+
+// The host itself or any subdomain of it
+fn on_domain(host: &str, domains: &[String]) -> bool {
+    domains.iter().any(|domain| {
+        host == domain || host.strip_suffix(domain.as_str()).is_some_and(|sub| sub.ends_with('.'))
+    })
+}
+
+// http(s):// and www. links, without surrounding punctuation such as "(...)," or quotes
+fn find_links(text: &str) -> Vec<Link> {
+    let mut links = Vec::new();
+    let mut offset = 0;
+    for word in text.split_inclusive(char::is_whitespace) {
+        let start = offset + (word.len() - word.trim_start_matches(['(', '<', '[', '"', '\'']).len());
+        let trimmed = word.trim_end().trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '>', ']', '"', '\'']);
+        let end = offset + trimmed.len();
+        offset += word.len();
+        if start >= end {
+            continue;
+        }
+
+        let candidate = &text[start..end];
+        let lower = candidate.to_lowercase();
+        let rest = match lower.strip_prefix("https://").or_else(|| lower.strip_prefix("http://")) {
+            Some(rest) => rest,
+            None if lower.starts_with("www.") => lower.as_str(),
+            None => continue,
+        };
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        let host = authority.rsplit('@').next().unwrap_or_default().split(':').next().unwrap_or_default();
+        if host.contains('.') {
+            links.push(Link { range: start..end, host: host.to_string() });
+        }
+    }
+    links
+}
+
+impl LinkFilter {
+    pub fn new(settings: &LinkFilterSettings) -> LinkFilter {
+        LinkFilter {
+            action: settings.action,
+            max_links: settings.max_links,
+            blocked_domains: domains(&settings.blocked_domains),
+            allowed_domains: domains(&settings.allowed_domains),
+        }
+    }
+}
+
+impl MessageFilter for LinkFilter {
+    fn check(&self, input: &FilterInput<'_>) -> Option<FilterMatch> {
+        let counted: Vec<Link> = find_links(input.text)
+            .into_iter()
+            .filter(|link| !on_domain(&link.host, &self.allowed_domains))
+            .collect();
+
+        let blocked: Vec<&Link> = counted.iter().filter(|link| on_domain(&link.host, &self.blocked_domains)).collect();
+        let (reason, offending) = if !blocked.is_empty() {
+            ("Message links to a blocked site", blocked)
+        } else if counted.len() > self.max_links {
+            ("Message contains too many links", counted.iter().collect())
+        } else {
+            return None;
+        };
+
+        let mut masked = String::with_capacity(input.text.len());
+        let mut last = 0;
+        for link in offending {
+            masked.push_str(&input.text[last..link.range.start]);
+            masked.push_str(REMOVED);
+            last = link.range.end;
+        }
+        masked.push_str(&input.text[last..]);
+
+        Some(FilterMatch {
+            rule: "links",
+            action: self.action,
+            reason: reason.to_string(),
+            masked: Some(masked),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(filter: &LinkFilter, text: &str) -> Option<FilterMatch> {
+        filter.check(&FilterInput { room_id: 1, sender: "alice", text })
+    }
+
+    #[test]
+    fn finds_links_in_prose() {
+        let hosts: Vec<String> = find_links("see (https://user@Docs.Example.com:8080/a?b), www.test.org. or http://x")
+            .into_iter()
+            .map(|link| link.host)
+            .collect();
+        assert_eq!(hosts, ["docs.example.com", "www.test.org"]);
+    }
+
+    #[test]
+    fn blocks_domains_and_link_floods() {
+        let filter = LinkFilter::new(&LinkFilterSettings {
+            action: FilterAction::Mask,
+            max_links: 2,
+            blocked_domains: vec!["spam.example".to_string()],
+            allowed_domains: vec!["docs.rs".to_string()],
+            ..LinkFilterSettings::default()
+        });
+
+        let found = check(&filter, "win at https://prizes.spam.example/now!").unwrap();
+        assert_eq!(found.reason, "Message links to a blocked site");
+        assert_eq!(found.masked.as_deref(), Some("win at [link removed]!"));
+        assert!(check(&filter, "not https://notspam.example").is_none());
+
+        // Allowed domains don't count towards the limit
+        assert!(check(&filter, "https://a.com https://b.com https://docs.rs/x https://docs.rs/y").is_none());
+        let found = check(&filter, "https://a.com https://b.com https://c.com").unwrap();
+        assert_eq!(found.reason, "Message contains too many links");
+        assert_eq!(found.masked.as_deref(), Some("[link removed] [link removed] [link removed]"));
+    }
+}
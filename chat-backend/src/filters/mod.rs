@@ -0,0 +1,172 @@
+mod duplicates;
+mod links;
+mod words;
+
+use std::sync::{Arc, RwLock};
+use crate::settings::{self, Cli, FilterAction, FilterSettings};
+
+pub use duplicates::DuplicateFilter;
+pub use links::LinkFilter;
+pub use words::WordFilter;
+
+// A chat message on its way to a room
+pub struct FilterInput<'a> {
+    pub room_id: i32,
+    // Username of the sender, registered or not
+    pub sender: &'a str,
+    pub text: &'a str,
+}
+
+// A rule that matched a message
+pub struct FilterMatch {
+    pub rule: &'static str,
+    pub action: FilterAction,
+    pub reason: String,
+    // The text with the offending parts masked, from filters that can mask
+    pub masked: Option<String>,
+}
+
+pub trait MessageFilter: Send + Sync {
+    fn check(&self, input: &FilterInput<'_>) -> Option<FilterMatch>;
+}
+
+// What the chain decided for one message
+#[derive(Debug, PartialEq)]
+pub struct FilterOutcome {
+    // The text to store and deliver, masked where a rule asked for it
+    pub text: String,
+    // Set when the message must not be delivered; tells the sender why
+    pub rejected: Option<String>,
+    pub shadow_hidden: bool,
+    // (rule, reason) for every match to queue for review, shadow-hidden messages included
+    pub flags: Vec<(&'static str, String)>,
+}
+
+// Filters run in order; a rejection stops the chain, masks feed their text to the next filter
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn MessageFilter>>,
+}
+
+impl FilterChain {
+    pub fn new(filters: Vec<Box<dyn MessageFilter>>) -> FilterChain {
+        FilterChain { filters }
+    }
+
+    pub fn from_settings(settings: &FilterSettings) -> FilterChain {
+        let mut filters: Vec<Box<dyn MessageFilter>> = Vec::new();
+        if !settings.enabled {
+            return FilterChain::new(filters);
+        }
+        if settings.words.enabled {
+            filters.push(Box::new(WordFilter::new(&settings.words)));
+        }
+        if settings.links.enabled {
+            filters.push(Box::new(LinkFilter::new(&settings.links)));
+        }
+        if settings.duplicates.enabled {
+            filters.push(Box::new(DuplicateFilter::new(&settings.duplicates)));
+        }
+        FilterChain::new(filters)
+    }
+
+    pub fn apply(&self, room_id: i32, sender: &str, text: &str) -> FilterOutcome {
+        let mut outcome = FilterOutcome {
+            text: text.to_string(),
+            rejected: None,
+            shadow_hidden: false,
+            flags: Vec::new(),
+        };
+        for filter in &self.filters {
+            let input = FilterInput { room_id, sender, text: &outcome.text };
+            let Some(found) = filter.check(&input) else {
+                continue;
+            };
+            match (found.action, found.masked) {
+                (FilterAction::Mask, Some(masked)) => outcome.text = masked,
+                // Nothing to mask, so leave it to a moderator
+                (FilterAction::Mask, None) | (FilterAction::Flag, _) => outcome.flags.push((found.rule, found.reason)),
+                (FilterAction::ShadowHide, _) => {
+                    outcome.shadow_hidden = true;
+                    outcome.flags.push((found.rule, found.reason));
+                }
+                (FilterAction::Reject, _) => {
+                    outcome.rejected = Some(found.reason);
+                    return outcome;
+                }
+            }
+        }
+        outcome
+    }
+}
+
+// Shared handle to the current chain, which SIGHUP swaps for one built from fresh settings
+#[derive(Clone, Default)]
+pub struct MessageFilters(Arc<RwLock<Arc<FilterChain>>>);
+
+impl MessageFilters {
+    pub fn new(settings: &FilterSettings) -> MessageFilters {
+        MessageFilters(Arc::new(RwLock::new(Arc::new(FilterChain::from_settings(settings)))))
+    }
+
+    pub fn apply(&self, room_id: i32, sender: &str, text: &str) -> FilterOutcome {
+        let chain = self.0.read().unwrap().clone();
+        chain.apply(room_id, sender, text)
+    }
+
+    // Replaces the rules; duplicate tracking starts over
+    pub fn reload(&self, settings: &FilterSettings) {
+        *self.0.write().unwrap() = Arc::new(FilterChain::from_settings(settings));
+    }
+}
+
+// Reloads the filter rules from the config file and environment on SIGHUP. Invalid
+// settings are logged and the current rules stay in place.
+pub fn spawn_reload_handler(cli: Cli, filters: MessageFilters) {
+    #[cfg(unix)]
+    actix_web::rt::spawn(async move {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                log::error!("Failed to listen for SIGHUP; message filters cannot be reloaded: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            match settings::load(&cli).and_then(|settings| settings.validate().map(|()| settings)) {
+                Ok(settings) => {
+                    filters.reload(&settings.filters);
+                    log::info!("Reloaded message filters");
+                }
+                Err(e) => log::error!("Keeping the current message filters: {}", e),
+            }
+        }
+    });
+    #[cfg(not(unix))]
+    let _ = (cli, filters);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{LinkFilterSettings, WordFilterSettings};
+
+    #[test]
+    fn chain_masks_then_stops_at_a_rejection() {
+        let words = WordFilterSettings { words: vec!["darn".to_string()], ..WordFilterSettings::default() };
+        let links = LinkFilterSettings {
+            action: FilterAction::Reject,
+            blocked_domains: vec!["spam.example".to_string()],
+            ..LinkFilterSettings::default()
+        };
+        let chain = FilterChain::new(vec![Box::new(WordFilter::new(&words)), Box::new(LinkFilter::new(&links))]);
+
+        let outcome = chain.apply(1, "alice", "darn it");
+        assert_eq!(outcome.text, "**** it");
+        assert_eq!(outcome.rejected, None);
+
+        let outcome = chain.apply(1, "alice", "darn, see https://spam.example/win");
+        assert_eq!(outcome.rejected.as_deref(), Some("Message links to a blocked site"));
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::{HashMap, HashSet};
+use super::{FilterInput, FilterMatch, MessageFilter};
+use crate::settings::{FilterAction, WordFilterSettings};
+
+// Masks (or otherwise acts on) blocked words. Rooms can add words and pick their own action.
+pub struct WordFilter {
+    action: FilterAction,
+    words: HashSet<String>,
+    rooms: HashMap<i32, (HashSet<String>, FilterAction)>,
+}
+
+fn word_set(words: &[String]) -> HashSet<String> {
+    words
+        .iter()
+        .map(|word| word.trim().to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+impl WordFilter {
+    pub fn new(settings: &WordFilterSettings) -> WordFilter {
+        let rooms = settings
+            .rooms
+            .iter()
+            .filter_map(|(room_id, room)| {
+                let room_id = room_id.parse().ok()?;
+                Some((room_id, (word_set(&room.words), room.action.unwrap_or(settings.action))))
+            })
+            .collect();
+        WordFilter {
+            action: settings.action,
+            words: word_set(&settings.words),
+            rooms,
+        }
+    }
+}
+
+impl MessageFilter for WordFilter {
+    fn check(&self, input: &FilterInput<'_>) -> Option<FilterMatch> {
+        let (room_words, action) = match self.rooms.get(&input.room_id) {
+            Some((words, action)) => (Some(words), *action),
+            None => (None, self.action),
+        };
+        let blocked = |word: &str| {
+            let word = word.to_lowercase();
+            self.words.contains(&word) || room_words.is_some_and(|words| words.contains(&word))
+        };
+
+        // Walk runs of letters and digits, so "Darn!" and "darn," match "darn" but "darned" does not
+        let mut masked = String::with_capacity(input.text.len());
+        let mut found = false;
+        let mut rest = input.text;
+        while let Some(c) = rest.chars().next() {
+            let alphanumeric = c.is_alphanumeric();
+            let end = rest.find(|c: char| c.is_alphanumeric() != alphanumeric).unwrap_or(rest.len());
+            let (run, tail) = rest.split_at(end);
+            if alphanumeric && blocked(run) {
+                masked.extend(std::iter::repeat_n('*', run.chars().count()));
+                found = true;
+            } else {
+                masked.push_str(run);
+            }
+            rest = tail;
+        }
+
+        found.then(|| FilterMatch {
+            rule: "words",
+            action,
+            reason: "Message contains blocked words".to_string(),
+            masked: Some(masked),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::RoomWordSettings;
+
+    fn check(filter: &WordFilter, room_id: i32, text: &str) -> Option<FilterMatch> {
+        filter.check(&FilterInput { room_id, sender: "alice", text })
+    }
+
+    #[test]
+    fn masks_whole_words_with_room_overrides() {
+        let mut settings = WordFilterSettings { words: vec!["Darn".to_string()], ..WordFilterSettings::default() };
+        settings.rooms.insert(
+            "7".to_string(),
+            RoomWordSettings { words: vec!["heck".to_string()], action: Some(FilterAction::Reject) },
+        );
+        let filter = WordFilter::new(&settings);
+
+        let found = check(&filter, 1, "DARN, it's darned heck").unwrap();
+        assert_eq!(found.action, FilterAction::Mask);
+        assert_eq!(found.masked.as_deref(), Some("****, it's darned heck"));
+        assert!(check(&filter, 1, "all fine").is_none());
+
+        let found = check(&filter, 7, "what the heck").unwrap();
+        assert_eq!(found.action, FilterAction::Reject);
+        assert_eq!(found.masked.as_deref(), Some("what the ****"));
+    }
+}
@@ -0,0 +1,733 @@
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use validator::Validate;
+use crate::db::models::{AccountUpdate, AuditFilter, AuditRecord, UserAccount, UserFilter};
+use crate::error::AppError;
+use crate::handlers::api::{ApiResponse, Page, PageQuery};
+use crate::handlers::auth::{AdminUser, AuthUser};
+use crate::handlers::profile::delete_account;
+use crate::handlers::rooms::{audit_state, remove_room};
+use crate::handlers::validation::{self, Validated};
+use crate::importer::{self, ImportError};
+use crate::models::audit::{self, Change, RequestOrigin};
+use crate::models::permissions::{authorize, Permission, Role};
+use crate::models::session::{broadcast_announcement, disconnect_session, disconnect_user, Connections, LiveSession};
+use crate::retention::{RetentionMetrics, RetentionStats};
+use crate::settings::{AccountSettings, ImportSource, MessageSettings, RetentionSettings};
+use crate::storage::Storage;
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    pub room_id: Option<i32>,
+    pub actor_id: Option<i32>,
+    // e.g. room.update or member.ban
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    // Entries at or after `from` and before `to`
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct UserQuery {
+    // Part of a username or display name
+    pub q: Option<String>,
+    pub admin: Option<bool>,
+    pub suspended: Option<bool>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct UpdateAccountRequest {
+    pub is_admin: Option<bool>,
+    pub suspended: Option<bool>,
+    // Shown to nobody but admins; only used when suspending
+    #[validate(length(max = 500), custom(function = "validation::no_control_chars"))]
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ConnectionQuery {
+    pub room_id: Option<i32>,
+    pub user_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct KickQuery {
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct AnnouncementRequest {
+    #[validate(length(min = 1, max = 2000), custom(function = "validation::not_blank"), custom(function = "validation::no_control_chars"))]
+    pub text: String,
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    // Detected from the upload when left out
+    pub source: Option<ImportSource>,
+    #[serde(default)]
+    pub dry_run: bool,
+    // Comma-separated author=username pairs; other authors get placeholder accounts
+    pub map: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AnnouncementResponse {
+    pub recipients: usize,
+}
+
+#[derive(Serialize)]
+pub struct RetentionResponse {
+    pub settings: RetentionSettings,
+    pub stats: RetentionStats,
+}
+
+// Blank query parameters are the same as leaving them out
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+fn user_not_found() -> AppError {
+    AppError::NotFound("User not found".to_string())
+}
+
+// The audit log: every entry for server admins, otherwise the rooms the caller administers,
+// without the IP addresses and request ids of the people in them. Newest first.
+pub async fn get_audit_log(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+    let (limit, offset) = PageQuery { limit: query.limit, offset: query.offset }.bounds();
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from >= to {
+            return Err(AppError::BadRequest("from must be before to".to_string()));
+        }
+    }
+
+    let client = pool.get().await?;
+    if let (Some(room_id), false) = (query.room_id, auth.is_admin) {
+        authorize(&client, room_id, auth.id, Permission::ViewAuditLog).await?;
+    }
+
+    let filter = AuditFilter {
+        viewer_id: (!auth.is_admin).then_some(auth.id),
+        roles: Role::with(Permission::ViewAuditLog).into_iter().map(Role::as_str).collect(),
+        room_id: query.room_id,
+        actor_id: query.actor_id,
+        action: non_empty(query.action),
+        target_type: non_empty(query.target_type),
+        target_id: non_empty(query.target_id),
+        from: query.from,
+        to: query.to,
+    };
+    let (mut items, total) = AuditRecord::find(&client, &filter, limit, offset).await?;
+    if !auth.is_admin {
+        for item in &mut items {
+            item.ip = None;
+            item.request_id = None;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: None,
+        data: Some(Page { items, total, limit, offset }),
+    }))
+}
+
+pub async fn list_users(
+    _admin: AdminUser,
+    pool: web::Data<Pool>,
+    query: web::Query<UserQuery>,
+) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+    let (limit, offset) = PageQuery { limit: query.limit, offset: query.offset }.bounds();
+    let filter = UserFilter {
+        search: non_empty(query.q),
+        admin: query.admin,
+        suspended: query.suspended,
+    };
+
+    let client = pool.get().await?;
+    let (items, total) = UserAccount::find(&client, &filter, limit, offset).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: None,
+        data: Some(Page { items, total, limit, offset }),
+    }))
+}
+
+// Grants or revokes server admin rights and suspends or reinstates an account. Suspended users
+// are disconnected and can't sign in or use their tokens until reinstated.
+pub async fn update_user(
+    admin: AdminUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    user_id: web::Path<i32>,
+    request: Validated<UpdateAccountRequest>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = *user_id;
+    let request = request.into_inner();
+    if user_id == admin.id && (request.is_admin == Some(false) || request.suspended == Some(true)) {
+        return Err(AppError::Unprocessable("You cannot demote or suspend yourself".to_string()));
+    }
+
+    let client = pool.get().await?;
+    let before = UserAccount::find_by_id(&client, user_id).await?.ok_or_else(user_not_found)?;
+    let update = AccountUpdate {
+        is_admin: request.is_admin,
+        suspended: request.suspended,
+        suspension_reason: non_empty(request.reason),
+    };
+    let after = UserAccount::update(&client, user_id, &update).await?.ok_or_else(user_not_found)?;
+
+    if after.suspended_at.is_some() && before.suspended_at.is_none() {
+        disconnect_user(&connections, user_id, "Your account was suspended");
+    }
+
+    let changes = [
+        (before.is_admin != after.is_admin, if after.is_admin { "user.grant_admin" } else { "user.revoke_admin" }),
+        (before.suspended_at != after.suspended_at, if after.suspended_at.is_some() { "user.suspend" } else { "user.unsuspend" }),
+    ];
+    for (_, action) in changes.iter().filter(|(changed, _)| *changed) {
+        let state = |account: &UserAccount| {
+            json!({
+                "is_admin": account.is_admin,
+                "suspended_at": account.suspended_at,
+                "suspension_reason": account.suspension_reason,
+            })
+        };
+        let change = Change::new(action, "user", user_id).before(&state(&before)).after(&state(&after));
+        audit::record(&client, &origin, admin.id, change).await;
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: Some("User updated".to_string()),
+        data: Some(after),
+    }))
+}
+
+// Deletes an account with everything the user uploaded; their messages go per the deletion policy
+pub async fn delete_user(
+    admin: AdminUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    storage: web::Data<dyn Storage>,
+    accounts: web::Data<AccountSettings>,
+    user_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = *user_id;
+    if user_id == admin.id {
+        return Err(AppError::Unprocessable("You cannot delete your own account here".to_string()));
+    }
+
+    let mut client = pool.get().await?;
+    let policy = accounts.deletion_policy;
+    if !delete_account(&mut client, &origin, admin.id, &connections, storage.get_ref(), user_id, policy).await? {
+        return Err(user_not_found());
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse::<()> {
+        success: true,
+        message: Some("User deleted".to_string()),
+        data: None,
+    }))
+}
+
+// Deletes any room, whoever owns it
+pub async fn force_delete_room(
+    admin: AdminUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    storage: web::Data<dyn Storage>,
+    room_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let room_id = *room_id;
+    let client = pool.get().await?;
+    let room = remove_room(&client, &connections, storage.get_ref(), room_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Room not found".to_string()))?;
+
+    let change = Change::new("room.delete", "room", room_id).room(room_id).before(&audit_state(&room));
+    audit::record(&client, &origin, admin.id, change).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::<()> {
+        success: true,
+        message: Some("Room deleted successfully".to_string()),
+        data: None,
+    }))
+}
+
+// Live WebSocket sessions on this server, oldest first
+pub async fn list_connections(
+    _admin: AdminUser,
+    connections: web::Data<Connections>,
+    query: web::Query<ConnectionQuery>,
+) -> Result<HttpResponse, AppError> {
+    let sessions: Vec<LiveSession> = match connections.lock() {
+        Ok(connections) => connections
+            .iter()
+            .filter(|session| query.room_id.is_none_or(|room_id| session.room_id == room_id))
+            .filter(|session| query.user_id.is_none_or(|user_id| session.user_id == Some(user_id)))
+            .cloned()
+            .collect(),
+        Err(_) => return Err(AppError::internal("connection registry is unavailable")),
+    };
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: None,
+        data: Some(sessions),
+    }))
+}
+
+// Closes one session; the user may reconnect unless they are also banned or suspended
+pub async fn kick_connection(
+    admin: AdminUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    session_id: web::Path<u64>,
+    query: web::Query<KickQuery>,
+) -> Result<HttpResponse, AppError> {
+    let session_id = *session_id;
+    let session = connections
+        .lock()
+        .ok()
+        .and_then(|connections| connections.iter().find(|session| session.id == session_id).cloned());
+    let reason = non_empty(query.into_inner().reason).unwrap_or_else(|| "You were disconnected by an administrator".to_string());
+    let Some(session) = session.filter(|_| disconnect_session(&connections, session_id, &reason)) else {
+        return Err(AppError::NotFound("Session not found".to_string()));
+    };
+
+    let client = pool.get().await?;
+    let change = Change::new("session.kick", "session", session_id)
+        .room(session.room_id)
+        .before(&session)
+        .after(&json!({ "reason": reason }));
+    audit::record(&client, &origin, admin.id, change).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::<()> {
+        success: true,
+        message: Some("Session disconnected".to_string()),
+        data: None,
+    }))
+}
+
+// Sends a system announcement to every connected session
+pub async fn post_announcement(
+    admin: AdminUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    request: Validated<AnnouncementRequest>,
+) -> Result<HttpResponse, AppError> {
+    let text = request.text.trim().to_string();
+    let recipients = broadcast_announcement(&connections, &text);
+
+    let client = pool.get().await?;
+    let change = Change::new("announcement.send", "announcement", "all")
+        .after(&json!({ "text": text, "recipients": recipients }));
+    audit::record(&client, &origin, admin.id, change).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: Some("Announcement sent".to_string()),
+        data: Some(AnnouncementResponse { recipients }),
+    }))
+}
+
+// Imports a Slack export ZIP or a Discord JSON export (or a ZIP of them) sent as the request
+// body into new rooms. Each room is written in its own transaction; a dry run rolls every one
+// back and only returns the report.
+pub async fn import_archive(
+    admin: AdminUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    messages: web::Data<MessageSettings>,
+    query: web::Query<ImportQuery>,
+    body: web::Bytes,
+) -> Result<HttpResponse, AppError> {
+    let source = query
+        .source
+        .or_else(|| importer::detect(&body))
+        .ok_or_else(|| AppError::BadRequest("Unrecognized export; pass source=slack or source=discord".to_string()))?;
+    let authors = importer::parse_authors(query.map.as_deref().unwrap_or("").split(',').filter(|pair| !pair.is_empty()))
+        .map_err(|e| AppError::BadRequest(format!("Invalid map: {}", e)))?;
+    // Unpacking a large archive is CPU-bound
+    let archive = web::block(move || importer::parse(source, &body, &messages))
+        .await
+        .map_err(AppError::internal)?
+        .map_err(|e| AppError::Unprocessable(format!("Cannot read the export: {}", e)))?;
+
+    let mut client = pool.get().await?;
+    let authors = importer::resolve_authors(&client, &archive, &authors).await.map_err(|e| match e {
+        ImportError::Database(e) => AppError::from(e),
+        e => AppError::Unprocessable(e.to_string()),
+    })?;
+    let report = importer::run(&mut client, &origin, admin.id, source, archive, authors, query.dry_run).await;
+
+    let message = match (report.failed(), query.dry_run) {
+        (0, true) => "Dry run; nothing was imported".to_string(),
+        (0, false) => "Import complete".to_string(),
+        (failed, _) => format!("{} of {} rooms failed", failed, report.rooms.len()),
+    };
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: Some(message),
+        data: Some(report),
+    }))
+}
+
+// The retention policy and what the pruning task has removed since the server started
+pub async fn get_retention(
+    _admin: AdminUser,
+    settings: web::Data<RetentionSettings>,
+    metrics: web::Data<RetentionMetrics>,
+) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: None,
+        data: Some(RetentionResponse {
+            settings: **settings,
+            stats: metrics.snapshot(),
+        }),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use serde_json::{json, Value};
+    use crate::db::models::{User, UserAccount};
+    use crate::handlers::test_support::{self, bearer, json};
+
+    fn audit(token: &str, room_id: i32) -> TestRequest {
+        TestRequest::get().uri(&format!("/api/admin/audit?room_id={}", room_id)).insert_header(bearer(token))
+    }
+
+    fn entry<'a>(body: &'a Value, action: &str) -> &'a Value {
+        let items = body["data"]["items"].as_array().unwrap();
+        items.iter().find(|item| item["action"] == action).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn room_changes_are_attributed_to_the_caller() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (guest_id, _) = test_support::user(&pool, "guest").await;
+        let create = |body| TestRequest::post().uri("/api/rooms").set_json(body);
+        let room = json!({ "name": test_support::unique("Lounge"), "room_type": "private" });
+
+        assert_eq!(test::call_service(&app, create(room.clone()).to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        let (status, body) = json(test::call_service(&app, create(room).insert_header(bearer(&owner)).to_request()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let room_id = body["data"]["id"].as_i64().unwrap() as i32;
+
+        let invite = json!({ "room_id": room_id, "user_id": guest_id });
+        let request = TestRequest::post().uri("/api/rooms/join").insert_header(bearer(&owner)).set_json(invite);
+        assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::OK);
+
+        let (status, body) = json(test::call_service(&app, audit(&owner, room_id).to_request()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(entry(&body, "room.create")["actor_id"], owner_id);
+        let join = entry(&body, "member.join");
+        assert_eq!(join["actor_id"], owner_id);
+        assert_eq!(join["target_id"], guest_id.to_string());
+    }
+
+    #[actix_web::test]
+    async fn room_audit_logs_need_view_audit_log() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, _) = test_support::user(&pool, "owner").await;
+        let (member_id, member) = test_support::user(&pool, "member").await;
+        let (_, outsider) = test_support::user(&pool, "outsider").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        test_support::member(&pool, room_id, member_id, "moderator").await;
+
+        let response = test::call_service(&app, audit(&member, room_id).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&app, audit(&outsider, room_id).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let request = TestRequest::get().uri("/api/admin/audit").to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn only_server_admins_see_where_requests_came_from() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (_, admin) = test_support::admin(&pool, "admin").await;
+        let (_, member) = test_support::user(&pool, "member").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+
+        let request = TestRequest::post()
+            .uri("/api/rooms/join")
+            .insert_header(bearer(&member))
+            .peer_addr("203.0.113.7:4000".parse().unwrap())
+            .set_json(json!({ "room_id": room_id }));
+        assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::OK);
+
+        let (_, body) = json(test::call_service(&app, audit(&admin, room_id).to_request()).await).await;
+        assert_eq!(entry(&body, "member.join")["ip"], "203.0.113.7");
+        let (status, body) = json(test::call_service(&app, audit(&owner, room_id).to_request()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let join = entry(&body, "member.join");
+        assert!(join["ip"].is_null() && join["request_id"].is_null());
+    }
+
+    #[actix_web::test]
+    async fn admin_endpoints_need_a_server_admin() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        test_support::room(&pool, owner_id, "public").await;
+
+        let requests = || {
+            [
+                TestRequest::get().uri("/api/admin/users"),
+                TestRequest::get().uri("/api/admin/connections"),
+                TestRequest::post().uri("/api/admin/announcements").set_json(json!({ "text": "hi" })),
+                TestRequest::patch().uri(&format!("/api/admin/users/{}", owner_id)).set_json(json!({ "is_admin": true })),
+            ]
+        };
+        for request in requests() {
+            assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        }
+        // Owning a room doesn't make anyone a server admin
+        for request in requests() {
+            let response = test::call_service(&app, request.insert_header(bearer(&owner)).to_request()).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[actix_web::test]
+    async fn suspended_users_lose_access() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (admin_id, admin) = test_support::admin(&pool, "admin").await;
+        let (user_id, user) = test_support::user(&pool, "user").await;
+        let update = |user_id: i32, body| {
+            TestRequest::patch()
+                .uri(&format!("/api/admin/users/{}", user_id))
+                .insert_header(bearer(&admin))
+                .set_json(body)
+                .to_request()
+        };
+
+        let response = test::call_service(&app, update(admin_id, json!({ "suspended": true }))).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let response = test::call_service(&app, update(i32::MAX, json!({ "suspended": true }))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let (status, body) = json(test::call_service(&app, update(user_id, json!({ "suspended": true, "reason": "spam" }))).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["data"]["suspended_at"].is_string());
+
+        let request = TestRequest::get().uri("/api/users/me").insert_header(bearer(&user)).to_request();
+        let (status, body) = json(test::call_service(&app, request).await).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["message"], "This account is suspended");
+
+        let request = TestRequest::delete().uri("/api/admin/connections/999999").insert_header(bearer(&admin));
+        assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::NOT_FOUND);
+        let request = TestRequest::delete().uri(&format!("/api/admin/rooms/{}", i32::MAX)).insert_header(bearer(&admin));
+        assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn startup_grants_go_by_user_id() {
+        let Some(pool) = test_support::pool().await else { return };
+        let (user_id, _) = test_support::user(&pool, "operator").await;
+        let client = pool.get().await.unwrap();
+
+        let granted = User::grant_admin(&client, &[user_id, i32::MAX]).await.unwrap();
+        assert_eq!(granted, vec![user_id]);
+        let account = UserAccount::find_by_id(&client, user_id).await.unwrap().unwrap();
+        assert!(account.is_admin);
+    }
+
+    // A Discord export of the channel `name` with (author id, author name, text) messages, a
+    // minute apart. Author ids must be unique to the test: placeholder accounts are kept by id.
+    fn discord_export(name: &str, messages: &[(&str, &str, &str)]) -> Value {
+        let messages: Vec<Value> = messages
+            .iter()
+            .enumerate()
+            .map(|(i, (id, author, content))| {
+                json!({
+                    "id": i.to_string(), "type": "Default", "timestamp": format!("2021-01-01T00:{:02}:00+00:00", i),
+                    "content": content, "author": { "id": id, "name": author },
+                })
+            })
+            .collect();
+        json!({ "channel": { "id": "1", "type": "GuildTextChat", "name": name }, "messages": messages })
+    }
+
+    fn import(token: &str, query: &str, export: &Value) -> TestRequest {
+        TestRequest::post()
+            .uri(&format!("/api/admin/imports?source=discord&{}", query))
+            .insert_header(bearer(token))
+            .set_payload(export.to_string())
+    }
+
+    #[actix_web::test]
+    async fn imports_create_rooms_and_placeholder_authors() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (admin_id, admin) = test_support::admin(&pool, "admin").await;
+        let (victim_id, victim) = test_support::user(&pool, "victim").await;
+        let client = pool.get().await.unwrap();
+        let name: String = client
+            .query_one("SELECT username FROM users WHERE id = $1", &[&victim_id])
+            .await
+            .unwrap()
+            .get(0);
+        // A room of the same name is left alone
+        let existing = test_support::room(&pool, victim_id, "public").await;
+        let row = client.query_one("SELECT name FROM rooms WHERE id = $1", &[&existing]).await.unwrap();
+        let room_name: String = row.get(0);
+
+        let author = test_support::unique("author");
+        let long = "x".repeat(5000);
+        let export = discord_export(&room_name, &[(&author, &name, "hi\u{0}\u{202e} there"), (&author, &name, &long)]);
+        let (status, body) = json(test::call_service(&app, import(&admin, "", &export).to_request()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let room = &body["data"]["rooms"][0];
+        assert_eq!(room["parsed"]["too_long_skipped"], 1);
+        assert_eq!(room["result"]["users_created"], json!([format!("{}@discord", name)]));
+        let room_id = room["result"]["room_id"].as_i64().unwrap() as i32;
+        assert_ne!(room_id, existing);
+
+        let rows = client
+            .query(
+                "SELECT m.content, u.username, m.sender_id FROM messages m JOIN users u ON u.id = m.sender_id
+                 WHERE m.room_id = $1",
+                &[&room_id],
+            )
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get::<_, String>(0), "hi there");
+        assert_eq!(rows[0].get::<_, String>(1), format!("{}@discord", name));
+        let rows = client.query("SELECT id FROM messages WHERE room_id = $1", &[&existing]).await.unwrap();
+        assert!(rows.is_empty());
+
+        // The registered user's account is untouched and not in the imported room
+        let request = TestRequest::get().uri("/api/users/me").insert_header(bearer(&victim)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        let rows = client
+            .query("SELECT 1 FROM room_members WHERE room_id = $1 AND user_id = $2", &[&room_id, &victim_id])
+            .await
+            .unwrap();
+        assert!(rows.is_empty());
+        let rows = client
+            .query("SELECT role FROM room_members WHERE room_id = $1 AND user_id = $2", &[&room_id, &admin_id])
+            .await
+            .unwrap();
+        assert_eq!(rows[0].get::<_, String>(0), "owner");
+    }
+
+    #[actix_web::test]
+    async fn admins_can_map_authors_to_accounts() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (_, admin) = test_support::admin(&pool, "admin").await;
+        let (user_id, _) = test_support::user(&pool, "mapped").await;
+        let client = pool.get().await.unwrap();
+        let row = client.query_one("SELECT username FROM users WHERE id = $1", &[&user_id]).await.unwrap();
+        let name: String = row.get(0);
+        let author = test_support::unique("author");
+        let export = discord_export(&test_support::unique("imported"), &[(&author, "Old Name", "hello")]);
+
+        let unknown = import(&admin, &format!("map=old_name={}", test_support::unique("nobody")), &export);
+        assert_eq!(test::call_service(&app, unknown.to_request()).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let malformed = import(&admin, "map=old_name", &export);
+        assert_eq!(test::call_service(&app, malformed.to_request()).await.status(), StatusCode::BAD_REQUEST);
+
+        let request = import(&admin, &format!("map=Old%20Name={}", name), &export).to_request();
+        let (status, body) = json(test::call_service(&app, request).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let result = &body["data"]["rooms"][0]["result"];
+        assert_eq!(result["users_created"], json!([]));
+        let room_id = result["room_id"].as_i64().unwrap() as i32;
+        let row = client.query_one("SELECT sender_id FROM messages WHERE room_id = $1", &[&room_id]).await.unwrap();
+        assert_eq!(row.get::<_, Option<i32>>(0), Some(user_id));
+    }
+
+    #[actix_web::test]
+    async fn dry_runs_and_non_admins_import_nothing() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (_, admin) = test_support::admin(&pool, "admin").await;
+        let (_, user) = test_support::user(&pool, "user").await;
+        let room_name = test_support::unique("dry");
+        let author = test_support::unique("author");
+        let export = discord_export(&room_name, &[(&author, "someone", "hello")]);
+
+        let request = import(&user, "", &export).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+        let request = import(&admin, "dry_run=true", &export).to_request();
+        let (status, body) = json(test::call_service(&app, request).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["rooms"][0]["result"]["messages_imported"], 1);
+        assert!(body["data"]["rooms"][0]["result"]["room_id"].is_null());
+
+        let client = pool.get().await.unwrap();
+        let rows = client.query("SELECT id FROM rooms WHERE name = $1", &[&room_name]).await.unwrap();
+        assert!(rows.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn authors_with_clashing_names_stay_apart() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (_, admin) = test_support::admin(&pool, "admin").await;
+        let (zoe, zo) = (test_support::unique("zoe"), test_support::unique("zo"));
+        let export = discord_export(&test_support::unique("clash"), &[(&zoe, "Zoë", "from zoë"), (&zo, "Zo!", "from zo")]);
+        let client = pool.get().await.unwrap();
+        let senders = |room_id: i32| {
+            let client = &client;
+            async move {
+                let rows = client
+                    .query("SELECT content, sender_id FROM messages WHERE room_id = $1 ORDER BY created_at", &[&room_id])
+                    .await
+                    .unwrap();
+                rows.iter().map(|row| (row.get::<_, String>(0), row.get::<_, i32>(1))).collect::<Vec<_>>()
+            }
+        };
+
+        let (status, body) = json(test::call_service(&app, import(&admin, "", &export).to_request()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let result = &body["data"]["rooms"][0]["result"];
+        assert_eq!(result["users_created"].as_array().unwrap().len(), 2);
+        let first = senders(result["room_id"].as_i64().unwrap() as i32).await;
+        assert_eq!(first[0].0, "from zoë");
+        assert_ne!(first[0].1, first[1].1);
+
+        // The same authors post as the same placeholders next time
+        let (_, body) = json(test::call_service(&app, import(&admin, "", &export).to_request()).await).await;
+        let result = &body["data"]["rooms"][0]["result"];
+        assert_eq!(result["users_created"], json!([]));
+        assert_eq!(senders(result["room_id"].as_i64().unwrap() as i32).await, first);
+    }
+}
@@ -1,31 +1,126 @@
-use actix_web::{web, HttpResponse, Responder};
-use serde::{Deserialize, Serialize};
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
 use deadpool_postgres::Pool;
-use crate::db::models::{User, Room, Message};
-use crate::utils::password::hash_password;
+use crate::db::models::{AuthToken, PublicRoom, PublicUser, User, Room, RoomFilter, RoomSort, RoomSummary, Message, Report, Sanction, SanctionKind, UserProfile};
+use crate::error::AppError;
+use crate::handlers::auth::{AuthUser, TOKEN_TTL_DAYS};
+use crate::handlers::validation::{self, invalid_field, Validated};
+use crate::handlers::rooms::audit_state;
+use crate::filters::MessageFilters;
+use crate::models::audit::{self, Change, RequestOrigin};
+use crate::models::content::prepare_message;
+use crate::models::permissions::{authorize, Permission, Role};
+use crate::models::presence::PresenceTracker;
+use crate::models::session::{broadcast_to_room, muted_message, post_message, Connections, WsMessage};
+use crate::settings::MessageSettings;
+use crate::utils::password::{hash_password, verify_password};
+use validator::{Validate, ValidationError};
 
 // Request/Response Structs
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
+pub struct PostMessageRequest {
+    // Normalized and length-checked like WebSocket chat messages
+    pub content: String,
+    #[serde(default)]
+    #[validate(length(max = 20))]
+    pub attachment_ids: Vec<i32>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct EditMessageRequest {
+    // Normalized and length-checked like a new message
+    pub content: String,
+}
+
+#[derive(Deserialize, Validate)]
 pub struct CreateUserRequest {
+    #[validate(length(min = 3, max = 32), custom(function = "validation::username_chars"))]
     pub username: String,
+    #[validate(length(min = 8, max = 128))]
     pub password: Option<String>,
+    #[validate(length(max = 255), custom(function = "validation::http_url"))]
     pub avatar_url: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
+#[validate(schema(function = "room_password_rules"))]
 pub struct CreateRoomRequest {
+    #[validate(length(min = 1, max = 255), custom(function = "validation::not_blank"), custom(function = "validation::single_line"))]
     pub name: String,
+    #[validate(custom(function = "validation::room_type"))]
     pub room_type: String, // "public", "private", "protected"
+    #[validate(length(max = 128))]
     pub password: Option<String>,
 }
 
-#[derive(Deserialize)]
+// Protected rooms need a password; other rooms must not be given one
+fn room_password_rules(request: &CreateRoomRequest) -> Result<(), ValidationError> {
+    let has_password = request.password.as_deref().is_some_and(|p| !p.is_empty());
+    match (request.room_type == "protected", has_password) {
+        (true, false) => Err(invalid_field("password", "required", "is required for protected rooms")),
+        (false, true) => Err(invalid_field("password", "not_allowed", "is only allowed for protected rooms")),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Deserialize, Validate)]
 pub struct JoinRoomRequest {
-    pub user_id: i32,
+    // Someone to add instead of the caller, which takes the Invite permission in the room
+    pub user_id: Option<i32>,
     pub room_id: i32,
+    #[validate(length(max = 128))]
     pub password: Option<String>,
 }
 
+// The created user plus a token so password-less accounts can authenticate
+#[derive(Serialize)]
+pub struct CreateUserResponse {
+    #[serde(flatten)]
+    pub user: PublicUser,
+    pub token: String,
+    pub token_expires_at: DateTime<Utc>,
+}
+
+// For PATCH bodies: marks a field as present, even when its value is null,
+// so `None` means "leave alone" and `Some(None)` means "clear"
+pub fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+// Query parameters for paginated listings
+#[derive(Deserialize)]
+pub struct PageQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl PageQuery {
+    pub const DEFAULT_LIMIT: i64 = 50;
+    pub const MAX_LIMIT: i64 = 100;
+
+    // Clamped (limit, offset)
+    pub fn bounds(&self) -> (i64, i64) {
+        (
+            self.limit.unwrap_or(Self::DEFAULT_LIMIT).clamp(1, Self::MAX_LIMIT),
+            self.offset.unwrap_or(0).max(0),
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
 #[derive(Serialize)]
 pub struct ApiResponse<T> {
     pub success: bool,
@@ -36,38 +131,13 @@ pub struct ApiResponse<T> {
 // User API Handlers
 pub async fn create_user(
     pool: web::Data<Pool>,
-    user_data: web::Json<CreateUserRequest>,
-) -> impl Responder {
-    let client = match pool.get().await {
-        Ok(client) => client,
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
-                success: false,
-                message: Some(format!("Database error: {}", e)),
-                data: None,
-            });
-        }
-    };
+    user_data: Validated<CreateUserRequest>,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await?;
 
     // Check if username already exists
-    match User::find_by_username(&client, &user_data.username).await {
-        Ok(Some(_)) => {
-            return HttpResponse::BadRequest().json(ApiResponse::<()> {
-                success: false,
-                message: Some("Username already exists".to_string()),
-                data: None,
-            });
-        }
-        Ok(None) => {
-            // Username available, continue
-        }
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
-                success: false,
-                message: Some(format!("Database error: {}", e)),
-                data: None,
-            });
-        }
+    if User::find_by_username(&client, &user_data.username).await?.is_some() {
+        return Err(AppError::Conflict("Username already exists".to_string()));
     }
 
     // Hash the password if provided
@@ -80,166 +150,505 @@ pub async fn create_user(
         password_hash,
         avatar_url: user_data.avatar_url.clone(),
         created_at: None,
+        is_admin: false,
+        suspended_at: None,
     };
 
-    match User::create(&client, &new_user).await {
-        Ok(created_user) => HttpResponse::Ok().json(ApiResponse {
-            success: true,
-            message: Some("User created successfully".to_string()),
-            data: Some(created_user),
-        }),
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
-            success: false,
-            message: Some(format!("Failed to create user: {}", e)),
-            data: None,
+    let created_user = User::create(&client, &new_user).await?;
+    let user_id = created_user.id.unwrap_or_default();
+    let token = AuthToken::create(&client, user_id, chrono::Duration::days(TOKEN_TTL_DAYS)).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: Some("User created successfully".to_string()),
+        data: Some(CreateUserResponse {
+            user: PublicUser::from(&created_user),
+            token: token.token,
+            token_expires_at: token.expires_at,
         }),
-    }
+    }))
 }
 
 // Room API Handlers
+// Creates a room owned by the caller
 pub async fn create_room(
+    auth: AuthUser,
+    origin: RequestOrigin,
     pool: web::Data<Pool>,
-    user_id: web::Path<i32>,
-    room_data: web::Json<CreateRoomRequest>,
-) -> impl Responder {
-    let client = match pool.get().await {
-        Ok(client) => client,
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
-                success: false,
-                message: Some(format!("Database error: {}", e)),
-                data: None,
-            });
-        }
-    };
+    presence: web::Data<PresenceTracker>,
+    room_data: Validated<CreateRoomRequest>,
+) -> Result<HttpResponse, AppError> {
+    let mut client = pool.get().await?;
 
-    // Hash the room password if it's a protected room
-    let password_hash = if room_data.room_type == "protected" {
-        room_data.password.as_ref().map(|password| hash_password(password))
-    } else {
-        None
-    };
+    // Only protected rooms carry a password, which validation has already required
+    let password_hash = room_data.password.as_ref().map(|password| hash_password(password));
 
     // Create room
     let new_room = Room {
         id: None,
-        name: room_data.name.clone(),
+        name: room_data.name.trim().to_string(),
         type_: room_data.room_type.clone(),
         password_hash,
-        created_by: Some(*user_id),
+        created_by: Some(auth.id),
         created_at: None,
+        description: None,
+        topic: None,
+        archived_at: None,
+        retention_days: None,
     };
 
-    match Room::create(&client, &new_room).await {
-        Ok(created_room) => {
-            // Add the creator as an admin member of the room
-            if let Some(room_id) = created_room.id {
-                if let Err(e) = Room::join_room(&client, *user_id, room_id, "admin").await {
-                    // Log error but don't fail the request
-                    eprintln!("Failed to add user as admin to room: {}", e);
-                }
-            }
-
-            HttpResponse::Ok().json(ApiResponse {
-                success: true,
-                message: Some("Room created successfully".to_string()),
-                data: Some(created_room),
-            })
-        },
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
-            success: false,
-            message: Some(format!("Failed to create room: {}", e)),
-            data: None,
-        }),
+    // The creator joins as the owner along with it
+    let created_room = Room::create(&mut client, &new_room).await?;
+    if let Some(room_id) = created_room.id {
+        presence.add_room(auth.id, room_id);
+        let change = Change::new("room.create", "room", room_id).room(room_id).after(&audit_state(&created_room));
+        audit::record(&client, &origin, auth.id, change).await;
     }
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: Some("Room created successfully".to_string()),
+        data: Some(PublicRoom::from(&created_room)),
+    }))
+}
+
+// Query parameters for the room directory
+#[derive(Deserialize)]
+pub struct RoomDirectoryQuery {
+    pub q: Option<String>,
+    pub room_type: Option<String>,
+    pub sort: Option<RoomSort>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct RoomDirectoryEntry {
+    #[serde(flatten)]
+    pub room: RoomSummary,
+    pub online_count: usize,
 }
 
 pub async fn get_rooms(
+    auth: Option<AuthUser>,
     pool: web::Data<Pool>,
-) -> impl Responder {
-    let client = match pool.get().await {
-        Ok(client) => client,
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
-                success: false,
-                message: Some(format!("Database error: {}", e)),
-                data: None,
-            });
+    presence: web::Data<PresenceTracker>,
+    query: web::Query<RoomDirectoryQuery>,
+) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+    let (limit, offset) = PageQuery { limit: query.limit, offset: query.offset }.bounds();
+
+    let room_type = query.room_type.filter(|t| !t.is_empty());
+    if let Some(room_type) = &room_type {
+        if !validation::ROOM_TYPES.contains(&room_type.as_str()) {
+            return Err(AppError::BadRequest(
+                "room_type must be one of: public, private, protected".to_string(),
+            ));
         }
+    }
+
+    let filter = RoomFilter {
+        viewer_id: auth.map(|auth| auth.id),
+        search: query.q.map(|q| q.trim().to_string()).filter(|q| !q.is_empty()),
+        room_type,
+        sort: query.sort.unwrap_or(RoomSort::Activity),
+        include_private: false,
     };
 
-    match Room::find_all(&client).await {
-        Ok(rooms) => HttpResponse::Ok().json(ApiResponse {
-            success: true,
-            message: None,
-            data: Some(rooms),
-        }),
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
-            success: false,
-            message: Some(format!("Failed to fetch rooms: {}", e)),
-            data: None,
-        }),
-    }
+    let client = pool.get().await?;
+    let (rooms, total) = RoomSummary::find(&client, &filter, limit, offset).await?;
+    let items = rooms
+        .into_iter()
+        .map(|room| RoomDirectoryEntry {
+            online_count: presence.online_in_room(room.id),
+            room,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: None,
+        data: Some(Page { items, total, limit, offset }),
+    }))
 }
 
+// Joins the caller to a room, or adds another user when the caller may invite. Private rooms
+// can only be entered by invitation.
 pub async fn join_room(
+    auth: AuthUser,
+    origin: RequestOrigin,
     pool: web::Data<Pool>,
-    join_data: web::Json<JoinRoomRequest>,
-) -> impl Responder {
-    let client = match pool.get().await {
-        Ok(client) => client,
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
-                success: false,
-                message: Some(format!("Database error: {}", e)),
-                data: None,
-            });
+    presence: web::Data<PresenceTracker>,
+    join_data: Validated<JoinRoomRequest>,
+) -> Result<HttpResponse, AppError> {
+    let room_id = join_data.room_id;
+    let user_id = join_data.user_id.unwrap_or(auth.id);
+    let client = pool.get().await?;
+
+    let room = Room::find_by_id(&client, room_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Room not found".to_string()))?;
+
+    if user_id != auth.id {
+        // Inviting skips the room password but needs a member allowed to invite
+        authorize(&client, room_id, auth.id, Permission::Invite).await?;
+        if UserProfile::find_by_id(&client, user_id).await?.is_none() {
+            return Err(AppError::NotFound("User not found".to_string()));
         }
-    };
+    } else if !Room::is_member(&client, room_id, auth.id).await? {
+        if room.type_ == "private" {
+            return Err(AppError::Forbidden("This room is invite-only".to_string()));
+        }
+        if let Some(password_hash) = &room.password_hash {
+            let password = join_data.password.as_deref().unwrap_or_default();
+            if !verify_password(password, password_hash) {
+                return Err(AppError::Forbidden("Incorrect room password".to_string()));
+            }
+        }
+    }
 
-    // In a real app, you'd check the room password if it's protected
+    if Sanction::find_active(&client, SanctionKind::Ban, room_id, user_id).await?.is_some() {
+        let message = match user_id == auth.id {
+            true => "You are banned from this room",
+            false => "This user is banned from this room",
+        };
+        return Err(AppError::Forbidden(message.to_string()));
+    }
 
-    match Room::join_room(&client, join_data.user_id, join_data.room_id, "member").await {
-        Ok(_) => HttpResponse::Ok().json(ApiResponse::<()> {
-            success: true,
-            message: Some("Joined room successfully".to_string()),
-            data: None,
-        }),
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
-            success: false,
-            message: Some(format!("Failed to join room: {}", e)),
-            data: None,
-        }),
+    let joined = Room::join_room(&client, user_id, room_id, Role::Member.as_str()).await?;
+    presence.add_room(user_id, room_id);
+
+    if joined {
+        let change = Change::new("member.join", "user", user_id)
+            .room(room_id)
+            .after(&json!({ "role": Role::Member }));
+        audit::record(&client, &origin, auth.id, change).await;
     }
+
+    Ok(HttpResponse::Ok().json(ApiResponse::<()> {
+        success: true,
+        message: Some("Joined room successfully".to_string()),
+        data: None,
+    }))
 }
 
 // Message API handlers
 pub async fn get_room_messages(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    room_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let room_id = *room_id;
+    let client = pool.get().await?;
+    let not_found = || AppError::NotFound("Room not found".to_string());
+
+    // Public history is open to any signed-in user; other rooms only to their members
+    let room = Room::find_by_id(&client, room_id).await?.ok_or_else(not_found)?;
+    if room.type_ != "public" && !Room::is_member(&client, room_id, auth.id).await? {
+        return Err(not_found());
+    }
+
+    // Fetch last 50 messages
+    let messages = Message::find_by_room(&client, room_id, 50).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: None,
+        data: Some(messages),
+    }))
+}
+
+// Posts a chat message without a WebSocket, e.g. from bots and integrations; it is delivered
+// to the room's live sessions like any other
+pub async fn post_room_message(
+    auth: AuthUser,
     pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    messages: web::Data<MessageSettings>,
+    filters: web::Data<MessageFilters>,
     room_id: web::Path<i32>,
-) -> impl Responder {
-    let client = match pool.get().await {
-        Ok(client) => client,
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
-                success: false,
-                message: Some(format!("Database error: {}", e)),
-                data: None,
-            });
+    request: Validated<PostMessageRequest>,
+) -> Result<HttpResponse, AppError> {
+    let room_id = *room_id;
+    let request = request.into_inner();
+    let content = prepare_message(&request.content, !request.attachment_ids.is_empty(), &messages)?;
+
+    let client = pool.get().await?;
+    let sender = UserProfile::find_by_id(&client, auth.id)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("Invalid or expired token".to_string()))?;
+    let posted = post_message(&client, &filters, room_id, auth.id, &sender.username, content, &request.attachment_ids).await?;
+
+    // Shadow-hidden messages look posted to the sender but reach nobody else
+    if !posted.shadow_hidden {
+        let mut frame = WsMessage::new("chat", &sender.username, posted.message.content.clone(), room_id);
+        frame.message_id = posted.message.id;
+        if !posted.attachments.is_empty() {
+            frame.attachment_ids = Some(posted.attachments.iter().filter_map(|a| a.id).collect());
+        }
+        broadcast_to_room(&connections, room_id, &frame);
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: Some("Message posted".to_string()),
+        data: Some(posted.message),
+    }))
+}
+
+// Replaces a message's text. Senders edit their own as they post, through the message filters
+// and not while muted; other people's messages take EditOthers and the change is audited.
+#[allow(clippy::too_many_arguments)]
+pub async fn edit_message(
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    messages: web::Data<MessageSettings>,
+    filters: web::Data<MessageFilters>,
+    message_id: web::Path<i32>,
+    request: Validated<EditMessageRequest>,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await?;
+    let not_found = || AppError::NotFound("Message not found".to_string());
+    let message = Message::find_by_id(&client, *message_id).await?.ok_or_else(not_found)?;
+    let room_id = message.room_id;
+    let content = prepare_message(&request.into_inner().content, false, &messages)?;
+
+    let own = message.sender_id == Some(auth.id);
+    let (content, shadow_hidden, flags) = if own {
+        let sender = authorize(&client, room_id, auth.id, Permission::Post).await?;
+        if let Some(mute) = Sanction::find_active(&client, SanctionKind::Mute, room_id, auth.id).await? {
+            return Err(AppError::Forbidden(muted_message(&mute)));
+        }
+        let outcome = filters.apply(room_id, &sender.username, &content);
+        if let Some(reason) = outcome.rejected {
+            return Err(AppError::Unprocessable(reason));
         }
+        (outcome.text, outcome.shadow_hidden, outcome.flags)
+    } else {
+        authorize(&client, room_id, auth.id, Permission::EditOthers).await?;
+        (content, false, Vec::new())
     };
 
-    // Fetch last 50 messages
-    match Message::find_by_room(&client, *room_id, 50).await {
-        Ok(messages) => HttpResponse::Ok().json(ApiResponse {
-            success: true,
-            message: None,
-            data: Some(messages),
-        }),
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
-            success: false,
-            message: Some(format!("Failed to fetch messages: {}", e)),
-            data: None,
-        }),
+    let edited = Message::edit(&client, *message_id, &content, shadow_hidden).await?.ok_or_else(not_found)?;
+    for (rule, reason) in &flags {
+        Report::create(&client, &edited, None, &format!("Flagged by the {} filter: {}", rule, reason)).await?;
+    }
+    if !shadow_hidden {
+        let mut notice = WsMessage::new("message_edited", "system", edited.content.clone(), room_id);
+        notice.message_id = edited.id;
+        broadcast_to_room(&connections, room_id, &notice);
+    }
+    if !own {
+        let change = Change::new("message.edit", "message", *message_id)
+            .room(room_id)
+            .before(&json!({ "content": message.content }))
+            .after(&json!({ "content": edited.content }));
+        audit::record(&client, &origin, auth.id, change).await;
     }
-} 
\ No newline at end of file
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: Some("Message edited".to_string()),
+        data: Some(edited),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use serde_json::json;
+    use crate::handlers::test_support::{self, bearer, json};
+
+    fn join(token: &str, body: serde_json::Value) -> TestRequest {
+        TestRequest::post().uri("/api/rooms/join").insert_header(bearer(token)).set_json(body)
+    }
+
+    #[actix_web::test]
+    async fn private_rooms_are_joined_by_invitation() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (reader_id, reader) = test_support::user(&pool, "reader").await;
+        let (guest_id, guest) = test_support::user(&pool, "guest").await;
+        let (_, outsider) = test_support::user(&pool, "outsider").await;
+        let room_id = test_support::room(&pool, owner_id, "private").await;
+        test_support::member(&pool, room_id, reader_id, "read_only").await;
+
+        let request = TestRequest::post().uri("/api/rooms/join").set_json(json!({ "room_id": room_id })).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+
+        let (status, body) = json(test::call_service(&app, join(&guest, json!({ "room_id": room_id })).to_request()).await).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["message"], "This room is invite-only");
+
+        // Only members whose role allows it may add someone else
+        let invite = json!({ "room_id": room_id, "user_id": guest_id });
+        let response = test::call_service(&app, join(&outsider, invite.clone()).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&app, join(&reader, invite.clone()).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let missing = json!({ "room_id": room_id, "user_id": i32::MAX });
+        let response = test::call_service(&app, join(&owner, missing).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        assert_eq!(test::call_service(&app, join(&owner, invite).to_request()).await.status(), StatusCode::OK);
+        let request = TestRequest::get()
+            .uri(&format!("/api/rooms/{}/members", room_id))
+            .insert_header(bearer(&guest))
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn protected_rooms_check_the_password_of_self_joins() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (_, guest) = test_support::user(&pool, "guest").await;
+        let (friend_id, _) = test_support::user(&pool, "friend").await;
+        let room_id = test_support::room(&pool, owner_id, "protected").await;
+        let hash = crate::utils::password::hash_password("hunter22");
+        let client = pool.get().await.unwrap();
+        client.execute("UPDATE rooms SET password_hash = $2 WHERE id = $1", &[&room_id, &hash]).await.unwrap();
+
+        let wrong = json!({ "room_id": room_id, "password": "nope" });
+        assert_eq!(test::call_service(&app, join(&guest, wrong).to_request()).await.status(), StatusCode::FORBIDDEN);
+        let right = json!({ "room_id": room_id, "password": "hunter22" });
+        assert_eq!(test::call_service(&app, join(&guest, right).to_request()).await.status(), StatusCode::OK);
+
+        let invite = json!({ "room_id": room_id, "user_id": friend_id });
+        assert_eq!(test::call_service(&app, join(&owner, invite).to_request()).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn message_history_follows_room_visibility() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (_, outsider) = test_support::user(&pool, "outsider").await;
+        let public = test_support::room(&pool, owner_id, "public").await;
+        let private = test_support::room(&pool, owner_id, "private").await;
+        let history = |room_id: i32| TestRequest::get().uri(&format!("/api/rooms/{}/messages", room_id));
+
+        assert_eq!(test::call_service(&app, history(public).to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        let request = history(public).insert_header(bearer(&outsider)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        let request = history(private).insert_header(bearer(&outsider)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+        let request = history(i32::MAX).insert_header(bearer(&outsider)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+
+        let post = TestRequest::post()
+            .uri(&format!("/api/rooms/{}/messages", private))
+            .insert_header(bearer(&owner))
+            .set_json(json!({ "content": "  secret plans " }));
+        assert_eq!(test::call_service(&app, post.to_request()).await.status(), StatusCode::OK);
+        let (status, body) = json(test::call_service(&app, history(private).insert_header(bearer(&owner)).to_request()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"][0]["content"], "secret plans");
+    }
+
+    #[actix_web::test]
+    async fn directory_hides_private_rooms_from_outsiders() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (_, outsider) = test_support::user(&pool, "outsider").await;
+        let room_id = test_support::room(&pool, owner_id, "private").await;
+        let client = pool.get().await.unwrap();
+        let name: String = client.query_one("SELECT name FROM rooms WHERE id = $1", &[&room_id]).await.unwrap().get(0);
+        let search = |token: &str| {
+            TestRequest::get().uri(&format!("/api/rooms?q={}", name)).insert_header(bearer(token)).to_request()
+        };
+
+        let (status, body) = json(test::call_service(&app, search(&outsider)).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["total"], 0);
+        let (_, body) = json(test::call_service(&app, search(&owner)).await).await;
+        assert_eq!(body["data"]["total"], 1);
+        assert_eq!(body["data"]["items"][0]["is_member"], true);
+
+        for room_type in ["secret", "direct"] {
+            let request = TestRequest::get().uri(&format!("/api/rooms?room_type={}", room_type)).to_request();
+            assert_eq!(test::call_service(&app, request).await.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[actix_web::test]
+    async fn new_rooms_are_owned_by_their_creator() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let request = TestRequest::post()
+            .uri("/api/rooms")
+            .insert_header(bearer(&owner))
+            .set_json(json!({ "name": test_support::unique("Lounge"), "room_type": "public" }))
+            .to_request();
+        let (status, body) = json(test::call_service(&app, request).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let room_id = body["data"]["id"].as_i64().unwrap() as i32;
+
+        let client = pool.get().await.unwrap();
+        let roles: Vec<(i32, String)> = client
+            .query("SELECT user_id, role FROM room_members WHERE room_id = $1", &[&room_id])
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        assert_eq!(roles, [(owner_id, "owner".to_string())]);
+    }
+
+    #[actix_web::test]
+    async fn senders_and_moderators_edit_messages() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, _) = test_support::user(&pool, "owner").await;
+        let (mod_id, moderator) = test_support::user(&pool, "mod").await;
+        let (alice_id, alice) = test_support::user(&pool, "alice").await;
+        let (bob_id, bob) = test_support::user(&pool, "bob").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        test_support::member(&pool, room_id, mod_id, "moderator").await;
+        test_support::member(&pool, room_id, alice_id, "member").await;
+        test_support::member(&pool, room_id, bob_id, "member").await;
+        let message_id = test_support::message(&pool, room_id, alice_id, "helo").await;
+        let edit = |token: &str, content: &str| {
+            TestRequest::patch()
+                .uri(&format!("/api/messages/{}", message_id))
+                .insert_header(bearer(token))
+                .set_json(json!({ "content": content }))
+                .to_request()
+        };
+
+        let (status, body) = json(test::call_service(&app, edit(&alice, "  hello ")).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["content"], "hello");
+        assert_eq!(test::call_service(&app, edit(&alice, "   ")).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // Members can't edit each other; moderators can, and that is audited
+        assert_eq!(test::call_service(&app, edit(&bob, "bob was here")).await.status(), StatusCode::FORBIDDEN);
+        let (status, body) = json(test::call_service(&app, edit(&moderator, "[removed]")).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["content"], "[removed]");
+        assert_eq!(body["data"]["sender_id"], alice_id);
+
+        let client = pool.get().await.unwrap();
+        let row = client
+            .query_one(
+                "SELECT actor_id, before->>'content' FROM audit_log WHERE action = 'message.edit' AND target_id = $1",
+                &[&message_id.to_string()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, Option<i32>>(0), Some(mod_id));
+        assert_eq!(row.get::<_, Option<String>>(1).as_deref(), Some("hello"));
+
+        // Leaving the room ends the right to edit
+        client.execute("DELETE FROM room_members WHERE user_id = $1", &[&alice_id]).await.unwrap();
+        assert_eq!(test::call_service(&app, edit(&alice, "again")).await.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,342 @@
+use actix_multipart::Multipart;
+use actix_web::{http::header, web, HttpResponse};
+use deadpool_postgres::Pool;
+use futures_util::TryStreamExt;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use crate::db::models::{Attachment, Room, Sanction, SanctionKind};
+use crate::error::AppError;
+use crate::handlers::api::ApiResponse;
+use crate::handlers::auth::AuthUser;
+use crate::models::permissions::{authorize, Permission};
+use crate::models::session::muted_message;
+use crate::storage::{Storage, UploadConfig};
+
+// Bytes kept from the start of an upload for content sniffing
+const SNIFF_LEN: usize = 8192;
+const THUMBNAIL_SIZE: u32 = 256;
+
+// Removes a scratch file when the upload is abandoned part-way
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// The file name as it goes into Content-Disposition: quotes, backslashes and control characters
+// would break out of the quoted string or make the header invalid, so they become '_'
+fn header_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c == '"' || c == '\\' || c.is_control() { '_' } else { c })
+        .collect()
+}
+
+// Renders a reduced PNG preview for image uploads, or None if the file can't be decoded
+fn render_thumbnail(source: &Path, target: &Path) -> Option<()> {
+    // Temp files carry no extension, so detect the format from the content
+    let image = image::ImageReader::open(source)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .decode()
+        .ok()?;
+    image
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .save_with_format(target, image::ImageFormat::Png)
+        .ok()
+}
+
+pub async fn upload_attachment(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+    upload_config: web::Data<UploadConfig>,
+    room_id: web::Path<i32>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, AppError> {
+    let room_id = *room_id;
+    let client = pool.get().await?;
+
+    authorize(&client, room_id, auth.id, Permission::Post).await?;
+    if let Some(mute) = Sanction::find_active(&client, SanctionKind::Mute, room_id, auth.id).await? {
+        return Err(AppError::Forbidden(muted_message(&mute)));
+    }
+
+    let invalid_upload = |e: actix_multipart::MultipartError| AppError::BadRequest(format!("Invalid upload: {}", e));
+    let store_failed = |e: std::io::Error| AppError::internal(format!("failed to store upload: {}", e));
+
+    // Find the "file" part; any other fields are ignored
+    let mut field = loop {
+        match payload.try_next().await.map_err(invalid_upload)? {
+            Some(field) if field.name() == Some("file") => break field,
+            Some(_) => continue,
+            None => return Err(AppError::BadRequest("Missing \"file\" field".to_string())),
+        }
+    };
+
+    let file_name = field
+        .content_disposition()
+        .and_then(|cd| cd.get_filename())
+        .map(|name| name.chars().take(255).collect::<String>())
+        .unwrap_or_else(|| "upload".to_string());
+    let declared_type = field.content_type().map(|mime| mime.essence_str().to_string());
+
+    let storage_key = format!("attachments/{}", uuid::Uuid::new_v4());
+    let temp = TempFile(upload_config.temp_dir.join(uuid::Uuid::new_v4().to_string()));
+    let mut file = tokio::fs::File::create(&temp.0).await.map_err(store_failed)?;
+
+    // Stream the part to disk, enforcing the size cap as we go
+    let mut size: u64 = 0;
+    let mut head: Vec<u8> = Vec::with_capacity(SNIFF_LEN);
+    while let Some(chunk) = field.try_next().await.map_err(invalid_upload)? {
+        size += chunk.len() as u64;
+        if size > upload_config.max_bytes {
+            return Err(AppError::PayloadTooLarge(format!(
+                "Attachments are limited to {} bytes",
+                upload_config.max_bytes
+            )));
+        }
+        if head.len() < SNIFF_LEN {
+            let take = (SNIFF_LEN - head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..take]);
+        }
+        file.write_all(&chunk).await.map_err(store_failed)?;
+    }
+    file.flush().await.map_err(store_failed)?;
+    drop(file);
+
+    if size == 0 {
+        return Err(AppError::BadRequest("Uploaded file is empty".to_string()));
+    }
+
+    // Trust the bytes over the client's declared type
+    let content_type = infer::get(&head)
+        .map(|kind| kind.mime_type().to_string())
+        .or(declared_type.filter(|mime| !mime.starts_with("image/")))
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    let mut thumbnail_key = None;
+    if content_type.starts_with("image/") {
+        let source = temp.0.clone();
+        let thumb = TempFile(upload_config.temp_dir.join(format!("{}.thumb.png", uuid::Uuid::new_v4())));
+        let target = thumb.0.clone();
+        if let Ok(Some(())) = web::block(move || render_thumbnail(&source, &target)).await {
+            let key = format!("{}.thumb.png", storage_key);
+            match storage.put(&key, &thumb.0, "image/png").await {
+                Ok(()) => thumbnail_key = Some(key),
+                Err(e) => log::error!("Failed to store thumbnail: {}", e),
+            }
+        }
+    }
+
+    storage.put(&storage_key, &temp.0, &content_type).await.map_err(store_failed)?;
+
+    let new_attachment = Attachment {
+        id: None,
+        room_id,
+        uploader_id: auth.id,
+        message_id: None,
+        file_name,
+        content_type,
+        size_bytes: size as i64,
+        storage_key,
+        thumbnail_key,
+        created_at: None,
+    };
+
+    match Attachment::create(&client, &new_attachment).await {
+        Ok(created) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            message: Some("Attachment uploaded successfully".to_string()),
+            data: Some(created),
+        })),
+        Err(e) => {
+            let _ = storage.delete(&new_attachment.storage_key).await;
+            if let Some(key) = &new_attachment.thumbnail_key {
+                let _ = storage.delete(key).await;
+            }
+            Err(e.into())
+        }
+    }
+}
+
+async fn serve_attachment(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+    attachment_id: i32,
+    thumbnail: bool,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await?;
+    let not_found = || AppError::NotFound("Attachment not found".to_string());
+
+    let attachment = Attachment::find_by_id(&client, attachment_id).await?.ok_or_else(not_found)?;
+
+    // Only members of the room the file was shared in may download it
+    if !Room::is_member(&client, attachment.room_id, auth.id).await? {
+        return Err(not_found());
+    }
+
+    let (key, content_type) = if thumbnail {
+        match &attachment.thumbnail_key {
+            Some(key) => (key.clone(), "image/png".to_string()),
+            None => return Err(AppError::NotFound("Attachment has no thumbnail".to_string())),
+        }
+    } else {
+        (attachment.storage_key.clone(), attachment.content_type.clone())
+    };
+
+    let body = storage
+        .get(&key)
+        .await
+        .map_err(|e| AppError::internal(format!("failed to read attachment {}: {}", key, e)))?;
+
+    // Only images are rendered inline; everything else is forced to download
+    let disposition = if content_type.starts_with("image/") { "inline" } else { "attachment" };
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("{}; filename=\"{}\"", disposition, header_file_name(&attachment.file_name)),
+        ))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .streaming(body))
+}
+
+pub async fn download_attachment(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+    attachment_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    serve_attachment(auth, pool, storage, *attachment_id, false).await
+}
+
+pub async fn download_thumbnail(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    storage: web::Data<dyn Storage>,
+    attachment_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    serve_attachment(auth, pool, storage, *attachment_id, true).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::web;
+    use crate::handlers::test_support::{self, bearer, json};
+    use crate::settings::Settings;
+    use crate::storage::UploadConfig;
+    use super::header_file_name;
+
+    const BOUNDARY: &str = "attachment-test-boundary";
+
+    // A multipart upload of one file
+    fn upload(room_id: i32, token: &str, file_name: &str, content_type: &str, bytes: &[u8]) -> TestRequest {
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+            BOUNDARY, file_name, content_type
+        )
+        .into_bytes();
+        body.extend_from_slice(bytes);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        TestRequest::post()
+            .uri(&format!("/api/rooms/{}/attachments", room_id))
+            .insert_header(bearer(token))
+            .insert_header((header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .set_payload(body)
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Cursor::new(Vec::new());
+        image::RgbImage::new(width, height).write_to(&mut bytes, image::ImageFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn header_file_names_lose_control_characters() {
+        assert_eq!(header_file_name("a\"b\\c\r\nd\te\u{0}f\u{7f}g\u{85}h.txt"), "a_b_c__d_e_f_g_h.txt");
+        assert_eq!(header_file_name("résumé.pdf"), "résumé.pdf");
+    }
+
+    #[actix_web::test]
+    async fn images_get_thumbnails_and_only_members_download() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (_, outsider) = test_support::user(&pool, "outsider").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+
+        let request = upload(room_id, &owner, "cat.png", "image/png", &png(600, 300)).to_request();
+        let (status, body) = json(test::call_service(&app, request).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["content_type"], "image/png");
+        let id = body["data"]["id"].as_i64().unwrap();
+
+        let request = TestRequest::get()
+            .uri(&format!("/api/attachments/{}/thumbnail", id))
+            .insert_header(bearer(&owner))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let thumbnail = image::load_from_memory(&test::read_body(response).await).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (256, 128));
+
+        // Public rooms are open to read, but their files only to members
+        let download = |token: &str| {
+            TestRequest::get().uri(&format!("/api/attachments/{}", id)).insert_header(bearer(token)).to_request()
+        };
+        assert_eq!(test::call_service(&app, download(&outsider)).await.status(), StatusCode::NOT_FOUND);
+        let response = test::call_service(&app, download(&owner)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(test::read_body(response).await, png(600, 300));
+    }
+
+    #[actix_web::test]
+    async fn declared_image_types_must_match_the_content() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+
+        let request = upload(room_id, &owner, "evil\u{85}.png", "image/png", b"not really a picture").to_request();
+        let (status, body) = json(test::call_service(&app, request).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["content_type"], "application/octet-stream");
+        let id = body["data"]["id"].as_i64().unwrap();
+
+        let request = TestRequest::get()
+            .uri(&format!("/api/attachments/{}/thumbnail", id))
+            .insert_header(bearer(&owner))
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+
+        let request = TestRequest::get()
+            .uri(&format!("/api/attachments/{}", id))
+            .insert_header(bearer(&owner))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let disposition = response.headers().get(header::CONTENT_DISPOSITION).unwrap().to_str().unwrap();
+        assert_eq!(disposition, "attachment; filename=\"evil_.png\"");
+    }
+
+    #[actix_web::test]
+    async fn uploads_over_the_limit_are_refused() {
+        let Some(pool) = test_support::pool().await else { return };
+        let config = UploadConfig { max_bytes: 1024, ..UploadConfig::from(&Settings::default().uploads) };
+        let app = test::init_service(test_support::app(&pool).app_data(web::Data::new(config))).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+
+        let request = upload(room_id, &owner, "big.bin", "application/octet-stream", &[7; 1025]).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let request = upload(room_id, &owner, "small.bin", "application/octet-stream", &[7; 1024]).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,175 @@
+use actix_web::{dev::{Payload, ServiceRequest}, web, FromRequest, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use crate::db::models::{AuthToken, User};
+use crate::error::AppError;
+use crate::handlers::api::ApiResponse;
+use crate::handlers::validation::Validated;
+use crate::utils::password::verify_password;
+use validator::Validate;
+
+// How long an issued token stays valid
+pub const TOKEN_TTL_DAYS: i64 = 30;
+
+#[derive(Deserialize, Validate)]
+pub struct LoginRequest {
+    #[validate(length(min = 1, max = 32))]
+    pub username: String,
+    #[validate(length(min = 1, max = 128))]
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginResponse {
+    pub token: String,
+    pub token_expires_at: DateTime<Utc>,
+    pub user_id: i32,
+    pub username: String,
+}
+
+// The user behind the request's bearer token.
+// The token is read from the `Authorization: Bearer` header, or from a
+// `token` query parameter for clients that cannot set headers (img tags, websockets).
+pub struct AuthUser {
+    pub id: i32,
+    pub is_admin: bool,
+}
+
+// An authenticated server admin; anyone else gets 403
+pub struct AdminUser {
+    pub id: i32,
+}
+
+fn unauthorized(message: &str) -> actix_web::Error {
+    AppError::Unauthorized(message.to_string()).into()
+}
+
+pub fn token_from_request(req: &HttpRequest) -> Option<String> {
+    let header_token = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+
+    header_token.or_else(|| {
+        web::Query::<std::collections::HashMap<String, String>>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.get("token").cloned())
+    })
+}
+
+// The request line for the access log, with `token` query parameters masked so bearer tokens
+// never end up in logs
+pub fn logged_request_line(req: &ServiceRequest) -> String {
+    match req.query_string() {
+        "" => format!("{} {} {:?}", req.method(), req.path(), req.version()),
+        query => format!("{} {}?{} {:?}", req.method(), req.path(), redact_token(query), req.version()),
+    }
+}
+
+fn redact_token(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some(("token", _)) => "token=[redacted]",
+            _ => pair,
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+impl FromRequest for AuthUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let pool = req.app_data::<web::Data<Pool>>().cloned();
+        let token = token_from_request(req);
+
+        Box::pin(async move {
+            let token = token.ok_or_else(|| unauthorized("Missing authentication token"))?;
+            let pool = pool.ok_or_else(|| AppError::internal("database pool is not configured"))?;
+            let client = pool.get().await.map_err(AppError::from)?;
+
+            match AuthToken::find_user(&client, &token).await.map_err(AppError::from)? {
+                Some(User { suspended_at: Some(_), .. }) => Err(suspended().into()),
+                Some(User { id: Some(id), is_admin, .. }) => Ok(AuthUser { id, is_admin }),
+                _ => Err(unauthorized("Invalid or expired token")),
+            }
+        })
+    }
+}
+
+impl FromRequest for AdminUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let auth = AuthUser::from_request(req, payload);
+        Box::pin(async move {
+            match auth.await? {
+                AuthUser { id, is_admin: true } => Ok(AdminUser { id }),
+                _ => Err(AppError::Forbidden("Only server admins can do this".to_string()).into()),
+            }
+        })
+    }
+}
+
+pub fn suspended() -> AppError {
+    AppError::Forbidden("This account is suspended".to_string())
+}
+
+pub async fn login(
+    pool: web::Data<Pool>,
+    login_data: Validated<LoginRequest>,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await?;
+    let invalid = || AppError::Unauthorized("Invalid username or password".to_string());
+
+    let user = User::find_by_username(&client, &login_data.username)
+        .await?
+        .ok_or_else(invalid)?;
+
+    // Accounts created without a password can only use the token issued at registration
+    let password_ok = user
+        .password_hash
+        .as_deref()
+        .map(|hash| verify_password(&login_data.password, hash))
+        .unwrap_or(false);
+    let user_id = match (password_ok, user.id) {
+        (true, Some(user_id)) => user_id,
+        _ => return Err(invalid()),
+    };
+    if user.suspended_at.is_some() {
+        return Err(suspended());
+    }
+
+    let token = AuthToken::create(&client, user_id, chrono::Duration::days(TOKEN_TTL_DAYS)).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: Some("Logged in successfully".to_string()),
+        data: Some(LoginResponse {
+            token: token.token,
+            token_expires_at: token.expires_at,
+            user_id,
+            username: user.username,
+        }),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_masked_in_logged_queries() {
+        assert_eq!(redact_token("token=abc&roomId=3"), "token=[redacted]&roomId=3");
+        assert_eq!(redact_token("roomId=3&token="), "roomId=3&token=[redacted]");
+        assert_eq!(redact_token("tokens=1&q=token=x"), "tokens=1&q=token=x");
+    }
+}
@@ -1,14 +1,31 @@
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
-use rand::Rng;
-use std::sync::{Arc, Mutex};
-use crate::models::session::ChatSession;
+use deadpool_postgres::Pool;
+use crate::db::models::{AuthToken, Room, Sanction, SanctionKind, User};
+use crate::error::AppError;
+use crate::filters::MessageFilters;
+use crate::handlers::auth::{suspended, token_from_request};
+use crate::models::presence::PresenceTracker;
+use crate::models::session::{next_session_id, ChatSession, Connections};
+use crate::models::permissions::Role;
+use crate::models::shutdown::Shutdown;
+use crate::rate_limit::RateLimiter;
+use crate::settings::MessageSettings;
 
 pub async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
-    srv: web::Data<Arc<Mutex<Vec<(String, i32, actix::Addr<ChatSession>)>>>>,
+    srv: web::Data<Connections>,
+    pool: web::Data<Pool>,
+    presence: web::Data<PresenceTracker>,
+    shutdown: web::Data<Shutdown>,
+    limiter: web::Data<RateLimiter>,
 ) -> Result<HttpResponse, Error> {
+    // No new sessions once the server has started draining the existing ones
+    if shutdown.is_draining() {
+        return Err(AppError::Unavailable("The server is shutting down; please reconnect shortly".to_string()).into());
+    }
+
     // Parse username from query string
     let username = req.query_string()
         .split('&')
@@ -29,17 +46,150 @@ pub async fn chat_route(
         .and_then(|s| s.parse::<i32>().ok())
         .unwrap_or(0); // Default to room 0 if not specified
 
+    // Only a token identifies a registered user; without one the session is anonymous and
+    // may not use a registered user's name
+    let token = token_from_request(&req);
+    let client = pool.get().await.map_err(AppError::from)?;
+    let user = match &token {
+        Some(token) => match AuthToken::find_user(&client, token).await.map_err(AppError::from)? {
+            Some(user) => Some(user),
+            None => return Err(AppError::Unauthorized("Invalid or expired token".to_string()).into()),
+        },
+        None => {
+            if User::name_taken(&client, &username).await.map_err(AppError::from)? {
+                return Err(AppError::Conflict(format!("{} is a registered user; sign in to use that name", username)).into());
+            }
+            None
+        }
+    };
+    let room = Room::find_by_id(&client, room_id)
+        .await
+        .map_err(AppError::from)?
+        .ok_or_else(|| AppError::NotFound("Room not found".to_string()))?;
+    let (member_rooms, room_role) = match user.as_ref().and_then(|user| user.id) {
+        Some(user_id) => {
+            // Banned users may not connect to the room at all
+            if Sanction::find_active(&client, SanctionKind::Ban, room_id, user_id).await.map_err(AppError::from)?.is_some() {
+                return Err(AppError::Forbidden("You are banned from this room".to_string()).into());
+            }
+            let role = Room::member(&client, room_id, user_id).await.map_err(AppError::from)?.map(|m| m.role());
+            (Room::ids_for_user(&client, user_id).await.unwrap_or_default(), role)
+        }
+        None => (Vec::new(), None),
+    };
+    // Anyone may listen in on a public room; other rooms only stream to their members
+    if room.type_ != "public" && room_role.is_none() {
+        return Err(AppError::Forbidden("You are not a member of this room".to_string()).into());
+    }
+    drop(client);
+    if user.as_ref().is_some_and(|user| user.suspended_at.is_some()) {
+        return Err(suspended().into());
+    }
+    let (user_id, username, is_admin) = match user {
+        Some(user) => (user.id, user.username, user.is_admin),
+        None => (None, username, false),
+    };
+
+    // Server admins are never rate limited, room owners and admins not in their own rooms
+    let exempt = user_id.is_some()
+        && (is_admin || limiter.is_exempt_user(&username) || room_role.is_some_and(|role| role >= Role::Admin));
+
+    let messages = req.app_data::<web::Data<MessageSettings>>().map(|m| *m.get_ref()).unwrap_or_default();
+    let filters = req
+        .app_data::<web::Data<MessageFilters>>()
+        .map(|f| f.get_ref().clone())
+        .ok_or_else(|| AppError::internal("message filters are not configured"))?;
+
     println!("New connection from user: {} to room: {}", username, room_id);
 
-    let resp = ws::start(
+    // Larger frames fail with an overflow error, which the session reports before closing
+    ws::WsResponseBuilder::new(
         ChatSession {
-            id: rand::thread_rng().gen_range(1..=1000),
+            id: next_session_id(),
             username,
+            user_id,
             room_id,
+            ip: Some(limiter.client_ip(&req)).filter(|ip| !ip.is_empty()),
+            member_rooms,
             addr: srv.get_ref().clone(),
+            pool: pool.get_ref().clone(),
+            presence: presence.get_ref().clone(),
+            shutdown: shutdown.get_ref().clone(),
+            limits: limiter.session_limits(exempt),
+            messages,
+            filters,
         },
         &req,
         stream,
-    );
-    resp
-}
\ No newline at end of file
+    )
+    .frame_size(messages.max_frame_bytes)
+    .start()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::web;
+    use crate::handlers::test_support::{self, bearer, json};
+    use crate::models::shutdown::Shutdown;
+
+    fn handshake(query: &str) -> TestRequest {
+        TestRequest::get()
+            .uri(&format!("/ws?{}", query))
+            .insert_header(("Upgrade", "websocket"))
+            .insert_header(("Connection", "Upgrade"))
+            .insert_header(("Sec-WebSocket-Version", "13"))
+            .insert_header(("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+    }
+
+    #[actix_web::test]
+    async fn anonymous_sessions_only_listen_to_public_rooms() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, _) = test_support::user(&pool, "owner").await;
+        let public = test_support::room(&pool, owner_id, "public").await;
+        let private = test_support::room(&pool, owner_id, "private").await;
+        let guest = test_support::unique("guest");
+
+        let request = handshake(&format!("roomId={}&username={}", public, guest)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let request = handshake(&format!("roomId={}&username={}", private, guest)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+        let request = handshake(&format!("roomId={}&username={}", i32::MAX, guest)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn private_rooms_only_stream_to_members() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (_, outsider) = test_support::user(&pool, "outsider").await;
+        let room_id = test_support::room(&pool, owner_id, "private").await;
+        let query = format!("roomId={}", room_id);
+
+        let request = handshake(&query).insert_header(bearer(&outsider)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+        let request = handshake(&query).insert_header(bearer(&owner)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let request = handshake(&query).insert_header(bearer("not-a-token")).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn draining_servers_refuse_new_sessions() {
+        let Some(pool) = test_support::pool().await else { return };
+        let shutdown = Shutdown::default();
+        let app = test::init_service(test_support::app(&pool).app_data(web::Data::new(shutdown.clone()))).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        let query = format!("roomId={}", room_id);
+
+        shutdown.start_draining();
+        let request = handshake(&query).insert_header(bearer(&owner)).to_request();
+        let (status, body) = json(test::call_service(&app, request).await).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["message"], "The server is shutting down; please reconnect shortly");
+    }
+}
@@ -0,0 +1,349 @@
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::db::models::{MemberChange, Room, RoomMember};
+use crate::error::AppError;
+use crate::handlers::api::{ApiResponse, Page, PageQuery};
+use crate::handlers::auth::AuthUser;
+use crate::handlers::validation::{self, Validated};
+use crate::models::audit::{self, Change, RequestOrigin};
+use crate::models::permissions::{authorize, Permission, Role};
+use crate::models::presence::{PresenceStatus, PresenceTracker};
+use validator::Validate;
+use crate::models::session::{broadcast_to_room, broadcast_user_list, disconnect_from_room, Connections, WsMessage};
+
+#[derive(Deserialize, Validate)]
+pub struct UpdateMemberRequest {
+    #[validate(custom(function = "validation::role"))]
+    pub role: String, // "owner", "admin", "moderator", "member", "read_only"
+}
+
+#[derive(Serialize)]
+pub struct MemberPresence {
+    pub status: PresenceStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct MemberResponse {
+    #[serde(flatten)]
+    pub member: RoomMember,
+    pub presence: MemberPresence,
+}
+
+// Live presence when the user has connected since startup, otherwise offline
+// with the last seen time recorded in the database
+fn presence_of(presence: &PresenceTracker, member: &RoomMember) -> MemberPresence {
+    match presence.get(member.user_id) {
+        Some(info) => MemberPresence {
+            status: info.status,
+            last_seen: info.last_seen.or(member.last_seen_at),
+        },
+        None => MemberPresence {
+            status: PresenceStatus::Offline,
+            last_seen: member.last_seen_at,
+        },
+    }
+}
+
+pub async fn get_room_members(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    presence: web::Data<PresenceTracker>,
+    room_id: web::Path<i32>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, AppError> {
+    let room_id = *room_id;
+    let (limit, offset) = page.bounds();
+    let client = pool.get().await?;
+    let not_found = || AppError::NotFound("Room not found".to_string());
+
+    let room = Room::find_by_id(&client, room_id).await?.ok_or_else(not_found)?;
+
+    // Anyone may see who is in a public room; other rooms only show members to members
+    if room.type_ != "public" && !Room::is_member(&client, room_id, auth.id).await? {
+        return Err(not_found());
+    }
+
+    let (members, total) = Room::members(&client, room_id, limit, offset).await?;
+    let items: Vec<MemberResponse> = members
+        .into_iter()
+        .map(|member| MemberResponse {
+            presence: presence_of(&presence, &member),
+            member,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: None,
+        data: Some(Page { items, total, limit, offset }),
+    }))
+}
+
+// Tells everyone in the room about a membership change
+pub fn announce(connections: &Connections, room_id: i32, text: String) {
+    broadcast_to_room(connections, room_id, &WsMessage::new("system", "system", text, room_id));
+    broadcast_user_list(connections, room_id);
+}
+
+fn not_a_member() -> AppError {
+    AppError::NotFound("User is not a member of this room".to_string())
+}
+
+fn last_owner() -> AppError {
+    AppError::Conflict("A room must keep at least one owner".to_string())
+}
+
+pub async fn update_room_member(
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    path: web::Path<(i32, i32)>,
+    member_data: Validated<UpdateMemberRequest>,
+) -> Result<HttpResponse, AppError> {
+    let (room_id, user_id) = path.into_inner();
+    let new_role = Role::parse(&member_data.role).unwrap_or(Role::Member);
+    let mut client = pool.get().await?;
+
+    let actor = authorize(&client, room_id, auth.id, Permission::ManageRoles).await?;
+    let target = Room::member(&client, room_id, user_id).await?.ok_or_else(not_a_member)?;
+
+    // Nobody can hand out or take away a role at or above their own (owners excepted)
+    if !actor.role().can_manage(target.role()) || !actor.role().can_manage(new_role) {
+        return Err(AppError::Forbidden("You cannot change this member's role".to_string()));
+    }
+
+    match Room::change_role(&mut client, room_id, user_id, new_role.as_str()).await? {
+        MemberChange::Done => {}
+        MemberChange::NotMember => return Err(not_a_member()),
+        MemberChange::LastOwner => return Err(last_owner()),
+    }
+
+    if target.role() != new_role {
+        let verb = if new_role > target.role() { "promoted" } else { "demoted" };
+        announce(
+            &connections,
+            room_id,
+            format!("{} was {} to {} by {}", target.username, verb, new_role.as_str(), actor.username),
+        );
+        let change = Change::new("member.role", "user", user_id)
+            .room(room_id)
+            .before(&json!({ "role": target.role() }))
+            .after(&json!({ "role": new_role }));
+        audit::record(&client, &origin, auth.id, change).await;
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse::<()> {
+        success: true,
+        message: Some("Member updated successfully".to_string()),
+        data: None,
+    }))
+}
+
+pub async fn remove_room_member(
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    presence: web::Data<PresenceTracker>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, AppError> {
+    let (room_id, user_id) = path.into_inner();
+    let mut client = pool.get().await?;
+
+    let actor = authorize(&client, room_id, auth.id, Permission::Kick).await?;
+    let target = Room::member(&client, room_id, user_id).await?.ok_or_else(not_a_member)?;
+
+    // Kicking is only allowed downwards; owners leave or are demoted first
+    if user_id != auth.id && (target.role() == Role::Owner || !actor.role().can_manage(target.role())) {
+        return Err(AppError::Forbidden("You cannot remove this member".to_string()));
+    }
+
+    match Room::remove_member(&mut client, room_id, user_id).await? {
+        MemberChange::Done => {}
+        MemberChange::NotMember => return Err(not_a_member()),
+        MemberChange::LastOwner => return Err(last_owner()),
+    }
+
+    presence.remove_room(user_id, room_id);
+    disconnect_from_room(&connections, user_id, room_id, "You were removed from this room");
+    announce(&connections, room_id, format!("{} was removed by {}", target.username, actor.username));
+
+    let change = Change::new("member.remove", "user", user_id)
+        .room(room_id)
+        .before(&json!({ "role": target.role() }));
+    audit::record(&client, &origin, auth.id, change).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::<()> {
+        success: true,
+        message: Some("Member removed successfully".to_string()),
+        data: None,
+    }))
+}
+
+pub async fn leave_room(
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    presence: web::Data<PresenceTracker>,
+    room_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let room_id = *room_id;
+    let mut client = pool.get().await?;
+    let not_member = || AppError::NotFound("You are not a member of this room".to_string());
+
+    let member = Room::member(&client, room_id, auth.id).await?.ok_or_else(not_member)?;
+
+    match Room::remove_member(&mut client, room_id, auth.id).await? {
+        MemberChange::Done => {}
+        MemberChange::NotMember => return Err(not_member()),
+        MemberChange::LastOwner => {
+            return Err(AppError::Conflict("Make another member an owner before leaving this room".to_string()));
+        }
+    }
+
+    presence.remove_room(auth.id, room_id);
+    disconnect_from_room(&connections, auth.id, room_id, "You left this room");
+    announce(&connections, room_id, format!("{} left the room", member.username));
+
+    let change = Change::new("member.leave", "user", auth.id)
+        .room(room_id)
+        .before(&json!({ "role": member.role() }));
+    audit::record(&client, &origin, auth.id, change).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::<()> {
+        success: true,
+        message: Some("Left room successfully".to_string()),
+        data: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::web;
+    use crate::handlers::test_support::{self, bearer, json};
+    use crate::models::presence::PresenceTracker;
+
+    #[actix_web::test]
+    async fn member_list_carries_presence() {
+        let Some(pool) = test_support::pool().await else { return };
+        let presence = PresenceTracker::default();
+        let app = test::init_service(test_support::app(&pool).app_data(web::Data::new(presence.clone()))).await;
+        let (alice_id, alice) = test_support::user(&pool, "alice").await;
+        let (bob_id, _) = test_support::user(&pool, "bob").await;
+        let room_id = test_support::room(&pool, alice_id, "public").await;
+        test_support::member(&pool, room_id, bob_id, "member").await;
+        presence.connect(alice_id, "alice", &[room_id]);
+
+        let request = TestRequest::get()
+            .uri(&format!("/api/rooms/{}/members", room_id))
+            .insert_header(bearer(&alice))
+            .to_request();
+        let (status, body) = json(test::call_service(&app, request).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["total"], 2);
+        let status_of = |user_id: i32| {
+            let items = body["data"]["items"].as_array().unwrap();
+            let member = items.iter().find(|m| m["user_id"] == user_id).unwrap();
+            member["presence"]["status"].clone()
+        };
+        assert_eq!(status_of(alice_id), "online");
+        assert_eq!(status_of(bob_id), "offline");
+    }
+
+    #[actix_web::test]
+    async fn private_member_lists_are_hidden_from_outsiders() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (alice_id, _) = test_support::user(&pool, "alice").await;
+        let (_, mallory) = test_support::user(&pool, "mallory").await;
+        let room_id = test_support::room(&pool, alice_id, "private").await;
+        let uri = format!("/api/rooms/{}/members", room_id);
+
+        let request = TestRequest::get().uri(&uri).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+
+        let request = TestRequest::get().uri(&uri).insert_header(bearer(&mallory)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn roles_can_only_be_managed_downwards() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (mod_id, moderator) = test_support::user(&pool, "mod").await;
+        let (member_id, _) = test_support::user(&pool, "member").await;
+        let (outsider_id, _) = test_support::user(&pool, "outsider").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        test_support::member(&pool, room_id, mod_id, "moderator").await;
+        test_support::member(&pool, room_id, member_id, "member").await;
+
+        let set_role = |token: &str, user_id: i32, role: &str| {
+            TestRequest::patch()
+                .uri(&format!("/api/rooms/{}/members/{}", room_id, user_id))
+                .insert_header(bearer(token))
+                .set_json(serde_json::json!({ "role": role }))
+                .to_request()
+        };
+
+        // Moderators lack ManageRoles
+        let response = test::call_service(&app, set_role(&moderator, member_id, "moderator")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = test::call_service(&app, set_role(&owner, member_id, "superuser")).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let response = test::call_service(&app, set_role(&owner, outsider_id, "member")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = test::call_service(&app, set_role(&owner, member_id, "admin")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The last owner can't demote themselves
+        let (status, body) = json(test::call_service(&app, set_role(&owner, owner_id, "admin")).await).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["message"], "A room must keep at least one owner");
+    }
+
+    #[actix_web::test]
+    async fn kicks_and_leaving_respect_the_hierarchy() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (mod_id, moderator) = test_support::user(&pool, "mod").await;
+        let (admin_id, _) = test_support::user(&pool, "admin").await;
+        let (member_id, member) = test_support::user(&pool, "member").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        test_support::member(&pool, room_id, mod_id, "moderator").await;
+        test_support::member(&pool, room_id, admin_id, "admin").await;
+        test_support::member(&pool, room_id, member_id, "member").await;
+
+        let kick = |token: &str, user_id: i32| {
+            TestRequest::delete()
+                .uri(&format!("/api/rooms/{}/members/{}", room_id, user_id))
+                .insert_header(bearer(token))
+                .to_request()
+        };
+        let leave = |token: &str| {
+            TestRequest::post().uri(&format!("/api/rooms/{}/leave", room_id)).insert_header(bearer(token)).to_request()
+        };
+
+        assert_eq!(test::call_service(&app, kick(&member, mod_id)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::call_service(&app, kick(&moderator, admin_id)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::call_service(&app, kick(&moderator, member_id)).await.status(), StatusCode::OK);
+        assert_eq!(test::call_service(&app, kick(&moderator, member_id)).await.status(), StatusCode::NOT_FOUND);
+
+        // A removed member can no longer act in the room
+        assert_eq!(test::call_service(&app, leave(&member)).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(test::call_service(&app, leave(&owner)).await.status(), StatusCode::CONFLICT);
+        assert_eq!(test::call_service(&app, leave(&moderator)).await.status(), StatusCode::OK);
+    }
+}
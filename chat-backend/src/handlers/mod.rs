@@ -1,2 +1,84 @@
 pub mod http;
-pub mod api;
\ No newline at end of file
+pub mod api;
+pub mod auth;
+pub mod attachments;
+pub mod profile;
+pub mod members;
+pub mod moderation;
+pub mod reports;
+pub mod rooms;
+pub mod admin;
+pub mod transcripts;
+pub mod validation;
+#[cfg(test)]
+mod response_tests;
+#[cfg(test)]
+pub(crate) mod test_support;
+
+use actix_web::web;
+use self::admin::{
+    delete_user, force_delete_room, get_audit_log, get_retention, import_archive, kick_connection, list_connections,
+    list_users, post_announcement, update_user,
+};
+use self::api::{create_room, create_user, edit_message, get_room_messages, get_rooms, join_room, post_room_message};
+use self::attachments::{download_attachment, download_thumbnail, upload_attachment};
+use self::auth::login;
+use self::members::{get_room_members, leave_room, remove_room_member, update_room_member};
+use self::moderation::{ban_user, get_bans, get_mutes, mute_user, unban_user, unmute_user};
+use self::profile::{delete_my_account, export_my_data, get_my_profile, get_user_profile, update_my_profile};
+use self::reports::{get_reports, report_message, resolve_report};
+use self::rooms::{delete_room, update_room};
+use self::transcripts::export_room;
+
+// Every route below /api; imports get their own body size limit
+pub fn api_routes(import_max_bytes: usize) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg
+            .route("/users", web::post().to(create_user))
+            .route("/login", web::post().to(login))
+            .route("/users/me", web::get().to(get_my_profile))
+            .route("/users/me", web::patch().to(update_my_profile))
+            .route("/users/me", web::delete().to(delete_my_account))
+            .route("/users/me/export", web::get().to(export_my_data))
+            .route("/users/{username}", web::get().to(get_user_profile))
+            .route("/rooms", web::get().to(get_rooms))
+            .route("/rooms", web::post().to(create_room))
+            .route("/rooms/join", web::post().to(join_room))
+            .route("/rooms/{room_id}", web::patch().to(update_room))
+            .route("/rooms/{room_id}", web::delete().to(delete_room))
+            .route("/rooms/{room_id}/messages", web::get().to(get_room_messages))
+            .route("/rooms/{room_id}/messages", web::post().to(post_room_message))
+            .route("/rooms/{room_id}/export", web::get().to(export_room))
+            .route("/rooms/{room_id}/members", web::get().to(get_room_members))
+            .route("/rooms/{room_id}/members/{user_id}", web::patch().to(update_room_member))
+            .route("/rooms/{room_id}/members/{user_id}", web::delete().to(remove_room_member))
+            .route("/rooms/{room_id}/leave", web::post().to(leave_room))
+            .route("/rooms/{room_id}/bans", web::get().to(get_bans))
+            .route("/rooms/{room_id}/bans", web::post().to(ban_user))
+            .route("/rooms/{room_id}/bans/{user_id}", web::delete().to(unban_user))
+            .route("/rooms/{room_id}/mutes", web::get().to(get_mutes))
+            .route("/rooms/{room_id}/mutes", web::post().to(mute_user))
+            .route("/rooms/{room_id}/mutes/{user_id}", web::delete().to(unmute_user))
+            .route("/rooms/{room_id}/attachments", web::post().to(upload_attachment))
+            .route("/attachments/{attachment_id}", web::get().to(download_attachment))
+            .route("/attachments/{attachment_id}/thumbnail", web::get().to(download_thumbnail))
+            .route("/messages/{message_id}", web::patch().to(edit_message))
+            .route("/messages/{message_id}/report", web::post().to(report_message))
+            .route("/moderation/reports", web::get().to(get_reports))
+            .route("/moderation/reports/{report_id}/resolve", web::post().to(resolve_report))
+            .route("/admin/audit", web::get().to(get_audit_log))
+            .route("/admin/users", web::get().to(list_users))
+            .route("/admin/users/{user_id}", web::patch().to(update_user))
+            .route("/admin/users/{user_id}", web::delete().to(delete_user))
+            .route("/admin/rooms/{room_id}", web::delete().to(force_delete_room))
+            .route("/admin/connections", web::get().to(list_connections))
+            .route("/admin/connections/{session_id}", web::delete().to(kick_connection))
+            .route("/admin/announcements", web::post().to(post_announcement))
+            .route("/admin/retention", web::get().to(get_retention))
+            .service(
+                web::resource("/admin/imports")
+                    .app_data(web::PayloadConfig::new(import_max_bytes))
+                    .route(web::post().to(import_archive)),
+            );
+    }
+}
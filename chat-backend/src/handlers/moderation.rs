@@ -0,0 +1,377 @@
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::Deserialize;
+use validator::Validate;
+use crate::db::models::{Room, Sanction, SanctionKind, UserProfile};
+use crate::error::AppError;
+use crate::handlers::api::ApiResponse;
+use crate::handlers::auth::AuthUser;
+use crate::handlers::validation::{self, Validated};
+use crate::handlers::members::announce;
+use crate::models::audit::{self, Change, RequestOrigin};
+use crate::models::permissions::{authorize, Permission};
+use crate::models::presence::PresenceTracker;
+use crate::models::session::{disconnect_from_room, Connections};
+
+#[derive(Deserialize, Validate)]
+pub struct SanctionRequest {
+    pub user_id: i32,
+    #[validate(length(max = 500), custom(function = "validation::no_control_chars"))]
+    pub reason: Option<String>,
+    // Omit for a permanent ban/mute
+    #[validate(custom(function = "validation::in_future"))]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+fn permission_for(kind: SanctionKind) -> Permission {
+    match kind {
+        SanctionKind::Ban => Permission::Ban,
+        SanctionKind::Mute => Permission::Mute,
+    }
+}
+
+fn audit_action(kind: SanctionKind, lifted: bool) -> &'static str {
+    match (kind, lifted) {
+        (SanctionKind::Ban, false) => "member.ban",
+        (SanctionKind::Ban, true) => "member.unban",
+        (SanctionKind::Mute, false) => "member.mute",
+        (SanctionKind::Mute, true) => "member.unmute",
+    }
+}
+
+fn describe(sanction: &Sanction) -> String {
+    let action = match sanction.kind {
+        SanctionKind::Ban => "banned",
+        SanctionKind::Mute => "muted",
+    };
+    let until = match sanction.expires_at {
+        Some(at) => format!(" until {}", at.format("%Y-%m-%d %H:%M UTC")),
+        None => String::new(),
+    };
+    let reason = match &sanction.reason {
+        Some(reason) => format!(" ({})", reason),
+        None => String::new(),
+    };
+    format!("{} was {}{}{}", sanction.username, action, until, reason)
+}
+
+// Bans or mutes a user in the room on behalf of `actor_id`; also used to resolve reports.
+// Returns the sanction along with the change for the caller to record in the audit log.
+pub async fn sanction(
+    kind: SanctionKind,
+    actor_id: i32,
+    pool: &Pool,
+    connections: &Connections,
+    presence: &PresenceTracker,
+    room_id: i32,
+    request: SanctionRequest,
+) -> Result<(Sanction, Change<'static>), AppError> {
+    let reason = request.reason.map(|r| r.trim().to_string()).filter(|r| !r.is_empty());
+    if request.user_id == actor_id {
+        return Err(AppError::Unprocessable("You cannot sanction yourself".to_string()));
+    }
+
+    let mut client = pool.get().await?;
+    let actor = authorize(&client, room_id, actor_id, permission_for(kind)).await?;
+
+    // Members can only be sanctioned by someone who outranks them; non-members
+    // may be banned pre-emptively
+    if let Some(target) = Room::member(&client, room_id, request.user_id).await? {
+        if !actor.role().can_manage(target.role()) {
+            return Err(AppError::Forbidden("You cannot moderate this member".to_string()));
+        }
+    }
+    if UserProfile::find_by_id(&client, request.user_id).await?.is_none() {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    let sanction = Sanction {
+        kind,
+        room_id,
+        user_id: request.user_id,
+        username: String::new(),
+        created_by: Some(actor_id),
+        reason,
+        expires_at: request.expires_at,
+        created_at: None,
+    };
+    let previous = Sanction::find_active(&client, kind, room_id, request.user_id).await?;
+    let sanction = Sanction::upsert(&mut client, &sanction).await?;
+
+    // A ban has also ended the membership; close any live sessions in the room
+    if kind == SanctionKind::Ban {
+        presence.remove_room(sanction.user_id, room_id);
+        disconnect_from_room(connections, sanction.user_id, room_id, "You were banned from this room");
+    }
+    announce(connections, room_id, format!("{} by {}", describe(&sanction), actor.username));
+
+    let mut change = Change::new(audit_action(kind, false), "user", sanction.user_id).room(room_id).after(&sanction);
+    if let Some(previous) = &previous {
+        change = change.before(previous);
+    }
+    Ok((sanction, change))
+}
+
+fn sanction_response(sanction: Sanction) -> HttpResponse {
+    HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: None,
+        data: Some(sanction),
+    })
+}
+
+async fn lift_sanction(
+    kind: SanctionKind,
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    room_id: i32,
+    user_id: i32,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await?;
+
+    let actor = authorize(&client, room_id, auth.id, permission_for(kind)).await?;
+    let username = UserProfile::find_by_id(&client, user_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?
+        .username;
+
+    let previous = Sanction::find_active(&client, kind, room_id, user_id).await?;
+    if !Sanction::remove(&client, kind, room_id, user_id).await? {
+        return Err(AppError::NotFound(format!("User has no {:?}", kind).to_lowercase()));
+    }
+
+    let action = match kind {
+        SanctionKind::Ban => "unbanned",
+        SanctionKind::Mute => "unmuted",
+    };
+    announce(&connections, room_id, format!("{} was {} by {}", username, action, actor.username));
+
+    let mut change = Change::new(audit_action(kind, true), "user", user_id).room(room_id);
+    if let Some(previous) = &previous {
+        change = change.before(previous);
+    }
+    audit::record(&client, &origin, auth.id, change).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::<()> {
+        success: true,
+        message: Some(format!("User {}", action)),
+        data: None,
+    }))
+}
+
+async fn list_sanctions(
+    kind: SanctionKind,
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    room_id: i32,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await?;
+    authorize(&client, room_id, auth.id, permission_for(kind)).await?;
+    let sanctions = Sanction::find_active_by_room(&client, kind, room_id).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: None,
+        data: Some(sanctions),
+    }))
+}
+
+pub async fn ban_user(
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    presence: web::Data<PresenceTracker>,
+    room_id: web::Path<i32>,
+    ban_data: Validated<SanctionRequest>,
+) -> Result<HttpResponse, AppError> {
+    let (ban, change) =
+        sanction(SanctionKind::Ban, auth.id, &pool, &connections, &presence, *room_id, ban_data.into_inner()).await?;
+    audit::record(&*pool.get().await?, &origin, auth.id, change).await;
+    Ok(sanction_response(ban))
+}
+
+pub async fn unban_user(
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, AppError> {
+    let (room_id, user_id) = path.into_inner();
+    lift_sanction(SanctionKind::Ban, auth, origin, pool, connections, room_id, user_id).await
+}
+
+pub async fn get_bans(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    room_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    list_sanctions(SanctionKind::Ban, auth, pool, *room_id).await
+}
+
+pub async fn mute_user(
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    presence: web::Data<PresenceTracker>,
+    room_id: web::Path<i32>,
+    mute_data: Validated<SanctionRequest>,
+) -> Result<HttpResponse, AppError> {
+    let (mute, change) =
+        sanction(SanctionKind::Mute, auth.id, &pool, &connections, &presence, *room_id, mute_data.into_inner()).await?;
+    audit::record(&*pool.get().await?, &origin, auth.id, change).await;
+    Ok(sanction_response(mute))
+}
+
+pub async fn unmute_user(
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, AppError> {
+    let (room_id, user_id) = path.into_inner();
+    lift_sanction(SanctionKind::Mute, auth, origin, pool, connections, room_id, user_id).await
+}
+
+pub async fn get_mutes(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    room_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    list_sanctions(SanctionKind::Mute, auth, pool, *room_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use serde_json::json;
+    use crate::db::models::Room;
+    use crate::handlers::test_support::{self, bearer};
+
+    #[actix_web::test]
+    async fn bans_end_the_membership_until_lifted() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (mod_id, moderator) = test_support::user(&pool, "mod").await;
+        let (member_id, member) = test_support::user(&pool, "member").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        test_support::member(&pool, room_id, mod_id, "moderator").await;
+        test_support::member(&pool, room_id, member_id, "member").await;
+        let bans = format!("/api/rooms/{}/bans", room_id);
+
+        let request = TestRequest::post()
+            .uri(&bans)
+            .insert_header(bearer(&moderator))
+            .set_json(json!({ "user_id": member_id, "reason": "spam" }))
+            .to_request();
+        let (status, body) = test_support::json(test::call_service(&app, request).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["reason"], "spam");
+        assert!(!Room::is_member(&pool.get().await.unwrap(), room_id, member_id).await.unwrap());
+
+        // Banned users can't come back in
+        let request = TestRequest::post()
+            .uri("/api/rooms/join")
+            .insert_header(bearer(&member))
+            .set_json(json!({ "room_id": room_id }))
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+
+        let request = TestRequest::get().uri(&bans).insert_header(bearer(&owner)).to_request();
+        let (_, body) = test_support::json(test::call_service(&app, request).await).await;
+        assert_eq!(body["data"][0]["user_id"], member_id);
+
+        let unban = format!("/api/rooms/{}/bans/{}", room_id, member_id);
+        let request = TestRequest::delete().uri(&unban).insert_header(bearer(&moderator)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        let request = TestRequest::delete().uri(&unban).insert_header(bearer(&moderator)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+
+        let request = TestRequest::post()
+            .uri("/api/rooms/join")
+            .insert_header(bearer(&member))
+            .set_json(json!({ "room_id": room_id }))
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn muted_members_cannot_post_until_unmuted() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (member_id, member) = test_support::user(&pool, "member").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        test_support::member(&pool, room_id, member_id, "member").await;
+        let post = || {
+            TestRequest::post()
+                .uri(&format!("/api/rooms/{}/messages", room_id))
+                .insert_header(bearer(&member))
+                .set_json(json!({ "content": "hello" }))
+                .to_request()
+        };
+
+        let request = TestRequest::post()
+            .uri(&format!("/api/rooms/{}/mutes", room_id))
+            .insert_header(bearer(&owner))
+            .set_json(json!({ "user_id": member_id }))
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        // Muting keeps the membership
+        assert!(Room::is_member(&pool.get().await.unwrap(), room_id, member_id).await.unwrap());
+
+        let (status, body) = test_support::json(test::call_service(&app, post()).await).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body["message"].as_str().unwrap().contains("muted"));
+
+        let request = TestRequest::delete()
+            .uri(&format!("/api/rooms/{}/mutes/{}", room_id, member_id))
+            .insert_header(bearer(&owner))
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        assert_eq!(test::call_service(&app, post()).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn sanctions_only_reach_lower_ranks() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, _) = test_support::user(&pool, "owner").await;
+        let (mod_id, moderator) = test_support::user(&pool, "mod").await;
+        let (admin_id, _) = test_support::user(&pool, "admin").await;
+        let (other_mod_id, _) = test_support::user(&pool, "mod").await;
+        let (member_id, member) = test_support::user(&pool, "member").await;
+        let (outsider_id, _) = test_support::user(&pool, "outsider").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        test_support::member(&pool, room_id, mod_id, "moderator").await;
+        test_support::member(&pool, room_id, admin_id, "admin").await;
+        test_support::member(&pool, room_id, other_mod_id, "moderator").await;
+        test_support::member(&pool, room_id, member_id, "member").await;
+        let ban = |token: &str, user_id: i32| {
+            TestRequest::post()
+                .uri(&format!("/api/rooms/{}/bans", room_id))
+                .insert_header(bearer(token))
+                .set_json(json!({ "user_id": user_id }))
+                .to_request()
+        };
+
+        // Members lack the permission; moderators can't touch their peers or superiors
+        assert_eq!(test::call_service(&app, ban(&member, outsider_id)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::call_service(&app, ban(&moderator, admin_id)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::call_service(&app, ban(&moderator, other_mod_id)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::call_service(&app, ban(&moderator, mod_id)).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(test::call_service(&app, ban(&moderator, i32::MAX)).await.status(), StatusCode::NOT_FOUND);
+        assert!(Room::is_member(&pool.get().await.unwrap(), room_id, admin_id).await.unwrap());
+
+        // Non-members may be banned pre-emptively
+        assert_eq!(test::call_service(&app, ban(&moderator, outsider_id)).await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,441 @@
+use std::io::{Cursor, Write};
+use actix_web::{http::header, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{Client, Pool};
+use serde::Deserialize;
+use serde_json::json;
+use tokio_postgres::error::SqlState;
+use validator::Validate;
+use zip::write::{SimpleFileOptions, ZipWriter};
+use crate::db::models::{ProfileUpdate, User, UserAccount, UserExport, UserProfile};
+use crate::error::AppError;
+use crate::handlers::api::{present, ApiResponse};
+use crate::handlers::auth::AuthUser;
+use crate::handlers::members::announce;
+use crate::handlers::validation::{self, Validated};
+use crate::models::audit::{self, Change, RequestOrigin};
+use crate::models::session::{
+    broadcast_to_room, broadcast_user_list, disconnect_room, disconnect_user, rooms_of_user, Connections, WsMessage,
+};
+use crate::settings::{AccountSettings, DeletionPolicy};
+use crate::storage::Storage;
+
+// Fields left out of the body are unchanged; an explicit `null` clears them
+#[derive(Deserialize, Validate)]
+pub struct UpdateProfileRequest {
+    #[serde(default, deserialize_with = "present")]
+    #[validate(length(max = 64), custom(function = "validation::single_line"))]
+    pub display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    #[validate(length(max = 500), custom(function = "validation::no_control_chars"))]
+    pub bio: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    #[validate(length(max = 255), custom(function = "validation::http_url"))]
+    pub avatar_url: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    #[validate(length(max = 128), custom(function = "validation::single_line"))]
+    pub status_text: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    #[validate(custom(function = "validation::in_future"))]
+    pub status_expires_at: Option<Option<DateTime<Utc>>>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    // profile.json, memberships.json and messages.json in one archive
+    #[default]
+    Zip,
+    // A single JSON document
+    Json,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+// Trims a text field, treating blank values as cleared
+fn clean_text(value: Option<Option<String>>) -> Option<Option<String>> {
+    value.map(|v| v.map(|s| s.trim().to_string()).filter(|s| !s.is_empty()))
+}
+
+fn to_update(request: UpdateProfileRequest) -> ProfileUpdate {
+    let status_text = clean_text(request.status_text);
+    let mut status_expires_at = request.status_expires_at;
+    // A new status without an expiry is permanent, not bound to the previous expiry
+    if status_text.is_some() && status_expires_at.is_none() {
+        status_expires_at = Some(None);
+    }
+
+    ProfileUpdate {
+        display_name: clean_text(request.display_name),
+        bio: clean_text(request.bio),
+        avatar_url: clean_text(request.avatar_url),
+        status_text,
+        status_expires_at,
+    }
+}
+
+pub async fn get_user_profile(
+    pool: web::Data<Pool>,
+    username: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await?;
+    let profile = UserProfile::find_by_username(&client, &username)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: None,
+        data: Some(profile),
+    }))
+}
+
+pub async fn get_my_profile(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await?;
+    let profile = UserProfile::find_by_id(&client, auth.id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: None,
+        data: Some(profile),
+    }))
+}
+
+pub async fn update_my_profile(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    profile_data: Validated<UpdateProfileRequest>,
+) -> Result<HttpResponse, AppError> {
+    let update = to_update(profile_data.into_inner());
+    let client = pool.get().await?;
+    let taken = || AppError::Conflict("Display name is already taken".to_string());
+
+    if let Some(Some(name)) = &update.display_name {
+        if UserProfile::display_name_taken(&client, name, auth.id).await? {
+            return Err(taken());
+        }
+    }
+
+    let profile = match UserProfile::update(&client, auth.id, &update).await {
+        Ok(Some(profile)) => profile,
+        Ok(None) => return Err(AppError::NotFound("User not found".to_string())),
+        // Lost a race with another user claiming the same display name
+        Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => return Err(taken()),
+        Err(e) => return Err(e.into()),
+    };
+
+    // Let every room the user is connected to refresh its online list
+    let profile_json = serde_json::to_string(&profile).unwrap_or_default();
+    for room_id in rooms_of_user(connections.get_ref(), auth.id) {
+        let message = WsMessage::new("profile_update", &profile.username, profile_json.clone(), room_id);
+        broadcast_to_room(connections.get_ref(), room_id, &message);
+        broadcast_user_list(connections.get_ref(), room_id);
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: Some("Profile updated successfully".to_string()),
+        data: Some(profile),
+    }))
+}
+
+// Deletes an account and cleans up after it: the user's sessions are closed, rooms deleted with it
+// are emptied, rooms that changed hands are told, and uploads are removed from storage. False if
+// there is no such user.
+pub(crate) async fn delete_account(
+    client: &mut Client,
+    origin: &RequestOrigin,
+    actor_id: i32,
+    connections: &Connections,
+    storage: &dyn Storage,
+    user_id: i32,
+    policy: DeletionPolicy,
+) -> Result<bool, AppError> {
+    let Some(account) = UserAccount::find_by_id(client, user_id).await? else {
+        return Ok(false);
+    };
+    let Some(deleted) = User::delete(client, user_id, policy == DeletionPolicy::Anonymize).await? else {
+        return Ok(false);
+    };
+
+    disconnect_user(connections, user_id, "Your account was deleted");
+    for room_id in &deleted.deleted_rooms {
+        disconnect_room(connections, *room_id, "This room was deleted");
+    }
+    for (room_id, _) in &deleted.new_owners {
+        announce(connections, *room_id, format!("{}'s account was deleted; the room has a new owner", deleted.username));
+    }
+    for key in &deleted.storage_keys {
+        if let Err(e) = storage.delete(key).await {
+            log::error!("Failed to delete attachment {}: {}", key, e);
+        }
+    }
+
+    let new_owners: Vec<_> = deleted
+        .new_owners
+        .iter()
+        .map(|(room_id, owner_id)| json!({ "room_id": room_id, "owner_id": owner_id }))
+        .collect();
+    let change = Change::new("user.delete", "user", user_id).before(&account).after(&json!({
+        "messages": policy,
+        "deleted_rooms": deleted.deleted_rooms,
+        "new_owners": new_owners,
+    }));
+    audit::record(client, origin, actor_id, change).await;
+
+    Ok(true)
+}
+
+// Deletes the caller's own account. Their messages are anonymized or deleted as configured;
+// rooms they owned alone pass to another member or are deleted.
+pub async fn delete_my_account(
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    storage: web::Data<dyn Storage>,
+    accounts: web::Data<AccountSettings>,
+) -> Result<HttpResponse, AppError> {
+    let mut client = pool.get().await?;
+    let policy = accounts.deletion_policy;
+    if !delete_account(&mut client, &origin, auth.id, &connections, storage.get_ref(), auth.id, policy).await? {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse::<()> {
+        success: true,
+        message: Some("Account deleted".to_string()),
+        data: None,
+    }))
+}
+
+fn export_zip(export: &UserExport) -> Result<Vec<u8>, AppError> {
+    let files = [
+        ("profile.json", serde_json::to_vec_pretty(&export.profile)),
+        ("memberships.json", serde_json::to_vec_pretty(&export.memberships)),
+        ("messages.json", serde_json::to_vec_pretty(&export.messages)),
+    ];
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, contents) in files {
+        let contents = contents.map_err(AppError::internal)?;
+        zip.start_file(name, SimpleFileOptions::default()).map_err(AppError::internal)?;
+        zip.write_all(&contents).map_err(AppError::internal)?;
+    }
+    Ok(zip.finish().map_err(AppError::internal)?.into_inner())
+}
+
+// Everything stored about the caller: profile, room memberships and every message they wrote
+pub async fn export_my_data(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    query: web::Query<ExportQuery>,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await?;
+    let export = UserExport::collect(&client, auth.id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+    let file_name = format!("chat-export-{}", export.exported_at.format("%Y%m%d"));
+    let response = match query.format {
+        ExportFormat::Json => HttpResponse::Ok()
+            .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.json\"", file_name)))
+            .json(&export),
+        ExportFormat::Zip => HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.zip\"", file_name)))
+            .body(export_zip(&export)?),
+    };
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{self, TestRequest};
+    use actix_web::http::StatusCode;
+    use serde_json::json;
+    use crate::handlers::test_support::{self, bearer, json};
+    use crate::retention;
+    use crate::settings::{RetentionMode, RetentionSettings};
+    use crate::storage::local::LocalStorage;
+
+    #[actix_web::test]
+    async fn own_profile_requires_a_valid_token() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+
+        let request = TestRequest::get().uri("/api/users/me").to_request();
+        let (status, body) = json(test::call_service(&app, request).await).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["success"], false);
+
+        let request = TestRequest::get().uri("/api/users/me").insert_header(bearer("not-a-token")).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn unknown_users_are_not_found() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+
+        let uri = format!("/api/users/{}", test_support::unique("nobody"));
+        let (status, body) = json(test::call_service(&app, TestRequest::get().uri(&uri).to_request()).await).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["message"], "User not found");
+    }
+
+    #[actix_web::test]
+    async fn display_names_are_validated_and_unique() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (alice_id, alice) = test_support::user(&pool, "alice").await;
+        let (_, bob) = test_support::user(&pool, "bob").await;
+        let name = test_support::unique("Alice");
+        let client = pool.get().await.unwrap();
+        let alice_username: String =
+            client.query_one("SELECT username FROM users WHERE id = $1", &[&alice_id]).await.unwrap().get(0);
+
+        let update = |token: &str, body| {
+            TestRequest::patch().uri("/api/users/me").insert_header(bearer(token)).set_json(body).to_request()
+        };
+        let (status, body) = json(test::call_service(&app, update(&alice, json!({ "display_name": name }))).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["display_name"], name.as_str());
+
+        let (status, _) = json(test::call_service(&app, update(&bob, json!({ "display_name": name }))).await).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        // Nor may anyone take another user's username, in any case; their own is fine
+        let impostor = json!({ "display_name": alice_username.to_uppercase() });
+        let (status, _) = json(test::call_service(&app, update(&bob, impostor.clone())).await).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, _) = json(test::call_service(&app, update(&alice, impostor)).await).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = json(test::call_service(&app, update(&bob, json!({ "display_name": "a\nb" }))).await).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (status, _) = json(test::call_service(&app, update(&bob, json!({ "avatar_url": "javascript:alert(1)" }))).await).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[actix_web::test]
+    async fn deleted_accounts_lose_their_tokens() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (_, token) = test_support::user(&pool, "carol").await;
+
+        let request = TestRequest::delete().uri("/api/users/me").insert_header(bearer(&token)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+
+        let request = TestRequest::get().uri("/api/users/me/export").insert_header(bearer(&token)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn owned_rooms_pass_on_or_go_with_the_account() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (alice_id, alice) = test_support::user(&pool, "alice").await;
+        let (bob_id, _) = test_support::user(&pool, "bob").await;
+        let (carol_id, _) = test_support::user(&pool, "carol").await;
+        let shared = test_support::room(&pool, alice_id, "public").await;
+        let alone = test_support::room(&pool, alice_id, "public").await;
+        // The moderator outranks the longer-standing member
+        test_support::member(&pool, shared, carol_id, "member").await;
+        test_support::member(&pool, shared, bob_id, "moderator").await;
+        let message_id = test_support::message(&pool, shared, alice_id, "hello").await;
+
+        let request = TestRequest::delete().uri("/api/users/me").insert_header(bearer(&alice)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+
+        let client = pool.get().await.unwrap();
+        let row = client
+            .query_one("SELECT role FROM room_members WHERE room_id = $1 AND user_id = $2", &[&shared, &bob_id])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, String>(0), "owner");
+        let rooms = client.query("SELECT id FROM rooms WHERE id = $1", &[&alone]).await.unwrap();
+        assert!(rooms.is_empty());
+        // Anonymized by default: the message stays without a sender
+        let row = client.query_one("SELECT sender_id FROM messages WHERE id = $1", &[&message_id]).await.unwrap();
+        assert_eq!(row.get::<_, Option<i32>>(0), None);
+    }
+
+    #[actix_web::test]
+    async fn exports_hold_the_profile_memberships_and_messages() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, _) = test_support::user(&pool, "owner").await;
+        let (user_id, token) = test_support::user(&pool, "dave").await;
+        let room_id = test_support::room(&pool, owner_id, "private").await;
+        test_support::member(&pool, room_id, user_id, "member").await;
+        let old = test_support::message(&pool, room_id, user_id, "archived").await;
+        test_support::message(&pool, room_id, user_id, "mine").await;
+        test_support::message(&pool, room_id, owner_id, "not mine").await;
+        let client = pool.get().await.unwrap();
+        client
+            .execute("UPDATE messages SET created_at = NOW() - INTERVAL '2 years' WHERE id = $1", &[&old])
+            .await
+            .unwrap();
+        let settings = RetentionSettings { mode: RetentionMode::Archive, ..RetentionSettings::default() };
+        let storage = LocalStorage::new(std::env::temp_dir().join("chat-backend-tests")).unwrap();
+        retention::prune(&client, &storage, retention::cutoff(365).unwrap(), Some(room_id), &settings).await.unwrap();
+
+        let request = TestRequest::get().uri("/api/users/me/export?format=json").insert_header(bearer(&token));
+        let (status, body) = json(test::call_service(&app, request.to_request()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["profile"]["id"], user_id);
+        assert_eq!(body["memberships"].as_array().unwrap().len(), 1);
+        assert_eq!(body["memberships"][0]["room_id"], room_id);
+        assert_eq!(body["memberships"][0]["role"], "member");
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!((&messages[0]["content"], &messages[0]["archived"]), (&json!("archived"), &json!(true)));
+        assert_eq!((&messages[1]["content"], &messages[1]["archived"]), (&json!("mine"), &json!(false)));
+
+        let request = TestRequest::get().uri("/api/users/me/export").insert_header(bearer(&token)).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get("content-type").unwrap(), "application/zip");
+        let body = test::read_body(response).await;
+        let archive = zip::ZipArchive::new(std::io::Cursor::new(body.to_vec())).unwrap();
+        let mut names: Vec<_> = archive.file_names().collect();
+        names.sort();
+        assert_eq!(names, ["memberships.json", "messages.json", "profile.json"]);
+
+        let request = TestRequest::get().uri("/api/users/me/export?format=pdf").insert_header(bearer(&token));
+        assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn admins_delete_other_accounts_only() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (admin_id, admin) = test_support::admin(&pool, "admin").await;
+        let (user_id, token) = test_support::user(&pool, "erin").await;
+        let delete = |user_id: i32| {
+            TestRequest::delete().uri(&format!("/api/admin/users/{}", user_id)).insert_header(bearer(&admin)).to_request()
+        };
+
+        assert_eq!(test::call_service(&app, delete(admin_id)).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(test::call_service(&app, delete(i32::MAX)).await.status(), StatusCode::NOT_FOUND);
+
+        let request = TestRequest::delete().uri(&format!("/api/admin/users/{}", admin_id)).insert_header(bearer(&token));
+        assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::FORBIDDEN);
+
+        let (status, body) = json(test::call_service(&app, delete(user_id)).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["message"], "User deleted");
+        let request = TestRequest::get().uri("/api/users/me").insert_header(bearer(&token)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,301 @@
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use validator::Validate;
+use crate::db::models::{ContextMessage, Message, Report, ReportFilter, Room, SanctionKind};
+use crate::error::AppError;
+use crate::handlers::api::{ApiResponse, Page, PageQuery};
+use crate::handlers::auth::AuthUser;
+use crate::handlers::moderation::{sanction, SanctionRequest};
+use crate::handlers::validation::{self, Validated};
+use crate::models::audit::{self, Change, RequestOrigin};
+use crate::models::permissions::{authorize, Permission, Role};
+use crate::models::presence::PresenceTracker;
+use crate::models::session::{broadcast_to_room, Connections, WsMessage};
+
+// Messages shown on each side of a reported one
+const CONTEXT_MESSAGES: i64 = 3;
+
+const STATUSES: [&str; 3] = ["open", "dismissed", "resolved"];
+
+#[derive(Deserialize, Validate)]
+pub struct ReportRequest {
+    #[validate(length(min = 1, max = 500), custom(function = "validation::not_blank"), custom(function = "validation::no_control_chars"))]
+    pub reason: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReportQuery {
+    pub room_id: Option<i32>,
+    // open (the default), dismissed, resolved or all
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportAction {
+    Dismiss,
+    DeleteMessage,
+    Mute,
+    Ban,
+}
+
+impl ReportAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            ReportAction::Dismiss => "dismiss",
+            ReportAction::DeleteMessage => "delete_message",
+            ReportAction::Mute => "mute",
+            ReportAction::Ban => "ban",
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ResolveReportRequest {
+    pub action: ReportAction,
+    // Kept in the audit log and used as the reason for a mute or ban
+    #[validate(length(max = 500), custom(function = "validation::no_control_chars"))]
+    pub note: Option<String>,
+    // For mute and ban; omit for a permanent one
+    #[validate(custom(function = "validation::in_future"))]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct QueuedReport {
+    #[serde(flatten)]
+    pub report: Report,
+    // The reported message with a few before and after it, oldest first
+    pub context: Vec<ContextMessage>,
+}
+
+// Reports a message to the room's admins
+pub async fn report_message(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    message_id: web::Path<i32>,
+    request: Validated<ReportRequest>,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await?;
+    let not_found = || AppError::NotFound("Message not found".to_string());
+
+    let message = Message::find_by_id(&client, *message_id).await?.ok_or_else(not_found)?;
+    // Only members can see a room's messages, so only they can report them
+    if !Room::is_member(&client, message.room_id, auth.id).await? {
+        return Err(not_found());
+    }
+    if message.sender_id == Some(auth.id) {
+        return Err(AppError::Unprocessable("You cannot report your own message".to_string()));
+    }
+
+    let report = Report::create(&client, &message, Some(auth.id), request.reason.trim()).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: Some("Report submitted".to_string()),
+        data: Some(report),
+    }))
+}
+
+// The moderation queue: reports in the rooms the caller reviews, with surrounding messages
+pub async fn get_reports(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    query: web::Query<ReportQuery>,
+) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+    let (limit, offset) = PageQuery { limit: query.limit, offset: query.offset }.bounds();
+    let status = match query.status.as_deref().unwrap_or("open") {
+        "all" => None,
+        status if STATUSES.contains(&status) => Some(status.to_string()),
+        _ => return Err(AppError::BadRequest("status must be one of: open, dismissed, resolved, all".to_string())),
+    };
+
+    let client = pool.get().await?;
+    if let Some(room_id) = query.room_id {
+        authorize(&client, room_id, auth.id, Permission::ReviewReports).await?;
+    }
+
+    let filter = ReportFilter {
+        reviewer_id: auth.id,
+        roles: Role::with(Permission::ReviewReports).into_iter().map(Role::as_str).collect(),
+        room_id: query.room_id,
+        status,
+    };
+    let (reports, total) = Report::find(&client, &filter, limit, offset).await?;
+
+    let mut items = Vec::with_capacity(reports.len());
+    for report in reports {
+        let context = report.context(&client, CONTEXT_MESSAGES).await?;
+        items.push(QueuedReport { report, context });
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: None,
+        data: Some(Page { items, total, limit, offset }),
+    }))
+}
+
+// Dismisses a report or acts on the reported message or its sender. Other open reports on
+// the same message are closed with it.
+pub async fn resolve_report(
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    presence: web::Data<PresenceTracker>,
+    report_id: web::Path<i32>,
+    request: Validated<ResolveReportRequest>,
+) -> Result<HttpResponse, AppError> {
+    let request = request.into_inner();
+    let note = request.note.map(|note| note.trim().to_string()).filter(|note| !note.is_empty());
+    let mut client = pool.get().await?;
+
+    let report = Report::find_by_id(&client, *report_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Report not found".to_string()))?;
+    let room_id = report.room_id;
+    authorize(&client, room_id, auth.id, Permission::ReviewReports).await?;
+    if report.status != "open" {
+        return Err(AppError::Conflict(format!("This report was already {}", report.status)));
+    }
+
+    match request.action {
+        ReportAction::Dismiss => {}
+        ReportAction::DeleteMessage => {
+            authorize(&client, room_id, auth.id, Permission::DeleteOthers).await?;
+            if report.message_id.is_none() {
+                return Err(AppError::Unprocessable("The message was already deleted".to_string()));
+            }
+        }
+        ReportAction::Mute | ReportAction::Ban => {
+            let kind = match request.action {
+                ReportAction::Ban => SanctionKind::Ban,
+                _ => SanctionKind::Mute,
+            };
+            let user_id = report
+                .sender_id
+                .ok_or_else(|| AppError::Unprocessable("The sender's account no longer exists".to_string()))?;
+            let reason = note.clone().unwrap_or_else(|| format!("Reported: {}", report.reason));
+            let request = SanctionRequest {
+                user_id,
+                reason: Some(reason.chars().take(500).collect()),
+                expires_at: request.expires_at,
+            };
+            let (_, change) = sanction(kind, auth.id, &pool, &connections, &presence, room_id, request).await?;
+            audit::record(&client, &origin, auth.id, change).await;
+        }
+    }
+
+    let status = match request.action {
+        ReportAction::Dismiss => "dismissed",
+        _ => "resolved",
+    };
+    let delete_message = request.action == ReportAction::DeleteMessage;
+    let (resolved, deleted) = report
+        .resolve(&mut client, status, request.action.as_str(), auth.id, delete_message)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Report not found".to_string()))?;
+    if let (true, Some(message_id)) = (deleted, report.message_id) {
+        let mut notice = WsMessage::new("message_deleted", "system", String::new(), room_id);
+        notice.message_id = Some(message_id);
+        broadcast_to_room(&connections, room_id, &notice);
+    }
+
+    let change = Change::new("report.resolve", "report", report.id)
+        .room(room_id)
+        .before(&json!({ "status": report.status }))
+        .after(&json!({ "status": status, "resolution": request.action, "note": note }));
+    audit::record(&client, &origin, auth.id, change).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: Some(format!("Report {}", status)),
+        data: Some(resolved),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use serde_json::json;
+    use crate::handlers::test_support::{self, bearer, json};
+
+    fn report(token: &str, message_id: i32) -> TestRequest {
+        TestRequest::post()
+            .uri(&format!("/api/messages/{}/report", message_id))
+            .insert_header(bearer(token))
+            .set_json(json!({ "reason": "spam" }))
+    }
+
+    fn resolve(token: &str, report_id: i64, action: &str) -> TestRequest {
+        TestRequest::post()
+            .uri(&format!("/api/moderation/reports/{}/resolve", report_id))
+            .insert_header(bearer(token))
+            .set_json(json!({ "action": action }))
+    }
+
+    #[actix_web::test]
+    async fn only_members_report_other_peoples_messages() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (_, outsider) = test_support::user(&pool, "outsider").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        let message_id = test_support::message(&pool, room_id, owner_id, "hello").await;
+
+        assert_eq!(test::call_service(&app, report(&outsider, message_id).to_request()).await.status(), StatusCode::NOT_FOUND);
+        let response = test::call_service(&app, report(&owner, message_id).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(test::call_service(&app, report(&owner, i32::MAX).to_request()).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn deleting_a_message_closes_every_report_on_it() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (spammer_id, _) = test_support::user(&pool, "spammer").await;
+        let (alice_id, alice) = test_support::user(&pool, "alice").await;
+        let (bob_id, bob) = test_support::user(&pool, "bob").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        for user_id in [spammer_id, alice_id, bob_id] {
+            test_support::member(&pool, room_id, user_id, "member").await;
+        }
+        let message_id = test_support::message(&pool, room_id, spammer_id, "buy now").await;
+
+        let (_, first) = json(test::call_service(&app, report(&alice, message_id).to_request()).await).await;
+        let (_, second) = json(test::call_service(&app, report(&bob, message_id).to_request()).await).await;
+        let first = first["data"]["id"].as_i64().unwrap();
+        let second = second["data"]["id"].as_i64().unwrap();
+
+        // Members can report but not review
+        let response = test::call_service(&app, resolve(&alice, first, "delete_message").to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let (status, body) = json(test::call_service(&app, resolve(&owner, first, "delete_message").to_request()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["status"], "resolved");
+
+        let (status, body) = json(test::call_service(&app, resolve(&owner, second, "dismiss").to_request()).await).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["message"], "This report was already resolved");
+
+        let client = pool.get().await.unwrap();
+        let row = client.query_one("SELECT COUNT(*) FROM messages WHERE id = $1", &[&message_id]).await.unwrap();
+        assert_eq!(row.get::<_, i64>(0), 0);
+        let request = TestRequest::get()
+            .uri(&format!("/api/moderation/reports?room_id={}&status=open", room_id))
+            .insert_header(bearer(&owner))
+            .to_request();
+        let (_, body) = json(test::call_service(&app, request).await).await;
+        assert_eq!(body["data"]["total"], 0);
+    }
+}
@@ -0,0 +1,223 @@
+// Serializes every API response type built from rows that carry secrets and
+// checks that none of them make it into the JSON
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::Value;
+use crate::db::models::{
+    Attachment, Message, PublicRoom, PublicUser, Room, RoomMember, RoomSummary, Sanction, SanctionKind, User,
+    UserProfile,
+};
+use crate::handlers::api::{ApiResponse, CreateUserResponse, Page, RoomDirectoryEntry};
+use crate::handlers::auth::LoginResponse;
+use crate::handlers::members::{MemberPresence, MemberResponse};
+use crate::handlers::rooms::room_update_message;
+use crate::models::presence::{PresenceInfo, PresenceStatus};
+
+const SECRET: &str = "SECRET-VALUE";
+const SECRET_FIELDS: [&str; 5] = ["password", "password_hash", "storage_key", "thumbnail_key", "token_hash"];
+
+fn user() -> User {
+    User {
+        id: Some(1),
+        username: "alice".to_string(),
+        password_hash: Some(SECRET.to_string()),
+        avatar_url: None,
+        created_at: Some(Utc::now()),
+        is_admin: false,
+        suspended_at: None,
+    }
+}
+
+fn room() -> Room {
+    Room {
+        id: Some(1),
+        name: "general".to_string(),
+        type_: "protected".to_string(),
+        password_hash: Some(SECRET.to_string()),
+        created_by: Some(1),
+        created_at: Some(Utc::now()),
+        description: Some("About".to_string()),
+        topic: None,
+        archived_at: None,
+        retention_days: None,
+    }
+}
+
+fn attachment() -> Attachment {
+    Attachment {
+        id: Some(1),
+        room_id: 1,
+        uploader_id: 1,
+        message_id: None,
+        file_name: "cat.png".to_string(),
+        content_type: "image/png".to_string(),
+        size_bytes: 42,
+        storage_key: SECRET.to_string(),
+        thumbnail_key: Some(SECRET.to_string()),
+        created_at: Some(Utc::now()),
+    }
+}
+
+fn secret_keys(value: &Value, found: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                if SECRET_FIELDS.contains(&key.as_str()) {
+                    found.push(key.clone());
+                }
+                secret_keys(value, found);
+            }
+        }
+        Value::Array(items) => items.iter().for_each(|item| secret_keys(item, found)),
+        // Frames carry JSON payloads inside their text
+        Value::String(text) => {
+            if let Ok(inner @ (Value::Object(_) | Value::Array(_))) = serde_json::from_str::<Value>(text) {
+                secret_keys(&inner, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn assert_no_secrets<T: Serialize>(response: &T) {
+    let json = serde_json::to_string(response).unwrap();
+    assert!(!json.contains(SECRET), "secret value serialized: {}", json);
+
+    let mut found = Vec::new();
+    secret_keys(&serde_json::from_str(&json).unwrap(), &mut found);
+    assert!(found.is_empty(), "secret fields {:?} serialized: {}", found, json);
+}
+
+fn envelope<T>(data: T) -> ApiResponse<T> {
+    ApiResponse {
+        success: true,
+        message: None,
+        data: Some(data),
+    }
+}
+
+#[test]
+fn create_user_response() {
+    assert_no_secrets(&envelope(CreateUserResponse {
+        user: PublicUser::from(&user()),
+        token: "session-token".to_string(),
+        token_expires_at: Utc::now(),
+    }));
+}
+
+#[test]
+fn login_response() {
+    assert_no_secrets(&envelope(LoginResponse {
+        token: "session-token".to_string(),
+        token_expires_at: Utc::now(),
+        user_id: 1,
+        username: "alice".to_string(),
+    }));
+}
+
+#[test]
+fn room_responses() {
+    assert_no_secrets(&envelope(PublicRoom::from(&room())));
+    assert_no_secrets(&room_update_message(&room(), 1, "alice"));
+}
+
+#[test]
+fn room_directory_response() {
+    let entry = RoomDirectoryEntry {
+        room: RoomSummary {
+            id: 1,
+            name: "general".to_string(),
+            room_type: "public".to_string(),
+            description: None,
+            topic: None,
+            created_by: Some(1),
+            created_at: Some(Utc::now()),
+            archived_at: None,
+            last_activity_at: Some(Utc::now()),
+            member_count: 3,
+            is_member: true,
+        },
+        online_count: 1,
+    };
+    assert_no_secrets(&envelope(Page { items: vec![entry], total: 1, limit: 50, offset: 0 }));
+}
+
+#[test]
+fn member_responses() {
+    let member = MemberResponse {
+        member: RoomMember {
+            user_id: 1,
+            username: "alice".to_string(),
+            display_name: None,
+            avatar_url: None,
+            role: "owner".to_string(),
+            joined_at: Some(Utc::now()),
+            last_seen_at: None,
+        },
+        presence: MemberPresence {
+            status: PresenceStatus::Online,
+            last_seen: None,
+        },
+    };
+    assert_no_secrets(&envelope(Page { items: vec![member], total: 1, limit: 50, offset: 0 }));
+    assert_no_secrets(&PresenceInfo {
+        user_id: 1,
+        username: "alice".to_string(),
+        status: PresenceStatus::Away,
+        last_seen: Some(Utc::now()),
+    });
+}
+
+#[test]
+fn profile_response() {
+    assert_no_secrets(&envelope(UserProfile {
+        id: 1,
+        username: "alice".to_string(),
+        display_name: Some("Alice".to_string()),
+        bio: None,
+        avatar_url: None,
+        status_text: Some("Lunch".to_string()),
+        status_expires_at: None,
+        created_at: Some(Utc::now()),
+    }));
+}
+
+#[test]
+fn sanction_responses() {
+    let sanction = Sanction {
+        kind: SanctionKind::Ban,
+        room_id: 1,
+        user_id: 2,
+        username: "bob".to_string(),
+        created_by: Some(1),
+        reason: None,
+        expires_at: None,
+        created_at: Some(Utc::now()),
+    };
+    assert_no_secrets(&envelope(sanction.clone()));
+    assert_no_secrets(&envelope(vec![sanction]));
+}
+
+#[test]
+fn attachment_response() {
+    assert_no_secrets(&envelope(attachment()));
+}
+
+#[test]
+fn message_responses() {
+    assert_no_secrets(&envelope(vec![Message {
+        id: Some(1),
+        room_id: 1,
+        sender_id: Some(1),
+        content: "hello".to_string(),
+        created_at: Some(Utc::now()),
+    }]));
+}
+
+#[test]
+fn detects_leaks() {
+    let leaky = serde_json::json!({ "room": { "password_hash": "x" } });
+    let mut found = Vec::new();
+    secret_keys(&leaky, &mut found);
+    assert_eq!(found, vec!["password_hash".to_string()]);
+}
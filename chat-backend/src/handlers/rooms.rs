@@ -0,0 +1,290 @@
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::{Client, Pool};
+use serde::Deserialize;
+use validator::{Validate, ValidationErrors};
+use crate::db::models::{Attachment, PublicRoom, Room, RoomUpdate};
+use crate::error::AppError;
+use crate::handlers::api::{present, ApiResponse};
+use crate::handlers::auth::AuthUser;
+use crate::handlers::members::announce;
+use crate::handlers::validation::{self, field_errors, invalid_field, Validated};
+use crate::models::audit::{self, Change, RequestOrigin};
+use crate::models::permissions::{authorize, Permission};
+use crate::models::session::{broadcast_to_room, disconnect_room, Connections, WsMessage};
+use crate::storage::Storage;
+use crate::utils::password::hash_password;
+
+// Fields left out of the body are unchanged; an explicit `null` clears them
+#[derive(Deserialize, Validate)]
+pub struct UpdateRoomRequest {
+    #[validate(length(min = 1, max = 255), custom(function = "validation::not_blank"), custom(function = "validation::single_line"))]
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    #[validate(length(max = 1000), custom(function = "validation::no_control_chars"))]
+    pub description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    #[validate(length(max = 255), custom(function = "validation::single_line"))]
+    pub topic: Option<Option<String>>,
+    #[validate(custom(function = "validation::room_type"))]
+    pub room_type: Option<String>,
+    // Only meaningful for protected rooms; `null` clears it
+    #[serde(default, deserialize_with = "present")]
+    #[validate(length(max = 128))]
+    pub password: Option<Option<String>>,
+    pub archived: Option<bool>,
+    // Days messages are kept; 0 keeps them forever and `null` follows the server default
+    #[serde(default, deserialize_with = "present")]
+    #[validate(range(min = 0, max = 36500))]
+    pub retention_days: Option<Option<i32>>,
+}
+
+// Trims a text field, treating blank values as cleared
+fn clean_text(value: Option<Option<String>>) -> Option<Option<String>> {
+    value.map(|v| v.map(|s| s.trim().to_string()).filter(|s| !s.is_empty()))
+}
+
+// Applies the rules that depend on the room's current state and turns the request into an update
+fn build_update(room: &Room, request: UpdateRoomRequest) -> Result<RoomUpdate, ValidationErrors> {
+    let protected = request.room_type.as_deref().unwrap_or(&room.type_) == "protected";
+
+    // Only protected rooms carry a password, and they must always have one
+    let password_hash = match (protected, request.password) {
+        (true, Some(Some(password))) if !password.is_empty() => Some(Some(hash_password(&password))),
+        (true, Some(_)) => return Err(password_error("required", "is required for protected rooms")),
+        (true, None) if room.password_hash.is_none() => {
+            return Err(password_error("required", "is required for protected rooms"));
+        }
+        (true, None) => None,
+        (false, Some(Some(_))) => return Err(password_error("not_allowed", "is only allowed for protected rooms")),
+        (false, _) if room.password_hash.is_some() => Some(None),
+        (false, _) => None,
+    };
+
+    Ok(RoomUpdate {
+        name: request.name.map(|name| name.trim().to_string()),
+        description: clean_text(request.description),
+        topic: clean_text(request.topic),
+        type_: request.room_type,
+        password_hash,
+        archived: request.archived,
+        retention_days: request.retention_days,
+    })
+}
+
+fn password_error(code: &'static str, message: &'static str) -> ValidationErrors {
+    let mut errors = ValidationErrors::new();
+    errors.add("password", invalid_field("password", code, message));
+    errors
+}
+
+// Human-readable summary of what changed, for the room's system message
+fn describe_changes(before: &Room, after: &Room) -> Vec<String> {
+    let mut changes = Vec::new();
+    if before.name != after.name {
+        changes.push(format!("renamed the room to \"{}\"", after.name));
+    }
+    if before.topic != after.topic {
+        changes.push(match &after.topic {
+            Some(topic) => format!("set the topic to \"{}\"", topic),
+            None => "cleared the topic".to_string(),
+        });
+    }
+    if before.description != after.description {
+        changes.push("updated the description".to_string());
+    }
+    if before.type_ != after.type_ {
+        changes.push(format!("made the room {}", after.type_));
+    } else if before.password_hash != after.password_hash && after.password_hash.is_some() {
+        changes.push("changed the room password".to_string());
+    }
+    match (before.archived_at.is_some(), after.archived_at.is_some()) {
+        (false, true) => changes.push("archived the room".to_string()),
+        (true, false) => changes.push("unarchived the room".to_string()),
+        _ => {}
+    }
+    if before.retention_days != after.retention_days {
+        changes.push(match after.retention_days {
+            Some(0) => "set messages to be kept forever".to_string(),
+            Some(days) => format!("set messages to expire after {} days", days),
+            None => "set messages to follow the server's retention policy".to_string(),
+        });
+    }
+    changes
+}
+
+// A room's settings for the audit log; password hashes are reduced to whether there is one
+pub fn audit_state(room: &Room) -> serde_json::Value {
+    let mut state = serde_json::to_value(PublicRoom::from(room)).unwrap_or_default();
+    if let Some(fields) = state.as_object_mut() {
+        fields.insert("has_password".to_string(), room.password_hash.is_some().into());
+    }
+    state
+}
+
+pub(crate) fn room_update_message(room: &Room, room_id: i32, actor: &str) -> WsMessage {
+    let settings = serde_json::to_string(&PublicRoom::from(room)).unwrap_or_default();
+    WsMessage::new("room_update", actor, settings, room_id)
+}
+
+pub async fn update_room(
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    room_id: web::Path<i32>,
+    room_data: Validated<UpdateRoomRequest>,
+) -> Result<HttpResponse, AppError> {
+    let room_id = *room_id;
+    let client = pool.get().await?;
+    let not_found = || AppError::NotFound("Room not found".to_string());
+
+    let actor = authorize(&client, room_id, auth.id, Permission::ChangeSettings).await?;
+    let room = Room::find_by_id(&client, room_id).await?.ok_or_else(not_found)?;
+
+    let update = build_update(&room, room_data.into_inner())
+        .map_err(|errors| AppError::Validation(field_errors(&errors)))?;
+    let updated = Room::update(&client, room_id, &update).await?.ok_or_else(not_found)?;
+
+    let changes = describe_changes(&room, &updated);
+    if !changes.is_empty() {
+        broadcast_to_room(&connections, room_id, &room_update_message(&updated, room_id, &actor.username));
+        announce(&connections, room_id, format!("{} {}", actor.username, changes.join(", ")));
+
+        let mut after = audit_state(&updated);
+        if room.password_hash.is_some() && updated.password_hash.is_some() && room.password_hash != updated.password_hash {
+            after["password_changed"] = true.into();
+        }
+        let change = Change::new("room.update", "room", room_id)
+            .room(room_id)
+            .before(&audit_state(&room))
+            .after(&after);
+        audit::record(&client, &origin, auth.id, change).await;
+    }
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        message: Some("Room updated successfully".to_string()),
+        data: Some(PublicRoom::from(&updated)),
+    }))
+}
+
+// Deletes the room with everything in it, closes its sessions and removes its attachment blobs.
+// Returns the room as it was, or None if it did not exist.
+pub(crate) async fn remove_room(
+    client: &Client,
+    connections: &Connections,
+    storage: &dyn Storage,
+    room_id: i32,
+) -> Result<Option<Room>, AppError> {
+    let Some(room) = Room::find_by_id(client, room_id).await? else {
+        return Ok(None);
+    };
+
+    // Collect blob keys first; the rows disappear with the room
+    let storage_keys = Attachment::storage_keys_for_room(client, room_id).await?;
+    if !Room::delete(client, room_id).await? {
+        return Ok(None);
+    }
+
+    disconnect_room(connections, room_id, "This room was deleted");
+    for key in storage_keys {
+        if let Err(e) = storage.delete(&key).await {
+            log::error!("Failed to delete attachment {}: {}", key, e);
+        }
+    }
+    Ok(Some(room))
+}
+
+pub async fn delete_room(
+    auth: AuthUser,
+    origin: RequestOrigin,
+    pool: web::Data<Pool>,
+    connections: web::Data<Connections>,
+    storage: web::Data<dyn Storage>,
+    room_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let room_id = *room_id;
+    let client = pool.get().await?;
+
+    authorize(&client, room_id, auth.id, Permission::DeleteRoom).await?;
+    let room = remove_room(&client, &connections, storage.get_ref(), room_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Room not found".to_string()))?;
+
+    let change = Change::new("room.delete", "room", room_id).room(room_id).before(&audit_state(&room));
+    audit::record(&client, &origin, auth.id, change).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::<()> {
+        success: true,
+        message: Some("Room deleted successfully".to_string()),
+        data: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use serde_json::json;
+    use crate::handlers::test_support::{self, bearer, json};
+
+    #[actix_web::test]
+    async fn settings_need_change_settings_and_valid_passwords() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, _) = test_support::user(&pool, "owner").await;
+        let (admin_id, admin) = test_support::user(&pool, "admin").await;
+        let (member_id, member) = test_support::user(&pool, "member").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        test_support::member(&pool, room_id, admin_id, "admin").await;
+        test_support::member(&pool, room_id, member_id, "member").await;
+
+        let update = |token: &str, room_id: i32, body| {
+            TestRequest::patch().uri(&format!("/api/rooms/{}", room_id)).insert_header(bearer(token)).set_json(body)
+        };
+
+        let response = test::call_service(&app, update(&member, room_id, json!({ "topic": "x" })).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&app, update(&admin, i32::MAX, json!({ "topic": "x" })).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let protect = json!({ "room_type": "protected" });
+        let (status, body) = json(test::call_service(&app, update(&admin, room_id, protect).to_request()).await).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["data"][0]["field"], "password");
+
+        let rename = json!({ "name": "  Renamed  ", "topic": "News" });
+        let (status, body) = json(test::call_service(&app, update(&admin, room_id, rename).to_request()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["name"], "Renamed");
+        assert_eq!(body["data"]["topic"], "News");
+    }
+
+    #[actix_web::test]
+    async fn archived_rooms_are_read_only_and_only_owners_delete() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (admin_id, admin) = test_support::user(&pool, "admin").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        test_support::member(&pool, room_id, admin_id, "admin").await;
+        let uri = format!("/api/rooms/{}", room_id);
+
+        let request = TestRequest::patch().uri(&uri).insert_header(bearer(&admin)).set_json(json!({ "archived": true }));
+        assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::OK);
+        let request = TestRequest::post()
+            .uri(&format!("{}/messages", uri))
+            .insert_header(bearer(&owner))
+            .set_json(json!({ "content": "hello" }));
+        let (status, body) = json(test::call_service(&app, request.to_request()).await).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["message"], "This room is archived and read-only");
+
+        let request = TestRequest::delete().uri(&uri).insert_header(bearer(&admin));
+        assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::FORBIDDEN);
+        let request = TestRequest::delete().uri(&uri).insert_header(bearer(&owner));
+        assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::OK);
+        let request = TestRequest::delete().uri(&uri).insert_header(bearer(&owner));
+        assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::NOT_FOUND);
+    }
+}
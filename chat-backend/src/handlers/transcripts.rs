@@ -0,0 +1,332 @@
+use actix_web::{http::header, web, web::Bytes, HttpResponse};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use crate::db::models::{Message, Room, TranscriptLine};
+use crate::error::AppError;
+use crate::handlers::auth::AuthUser;
+
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscriptFormat {
+    #[default]
+    Json,
+    Csv,
+    // A standalone page with its own styles, laid out for printing
+    Html,
+    Txt,
+}
+
+#[derive(Deserialize)]
+pub struct TranscriptQuery {
+    #[serde(default)]
+    pub format: TranscriptFormat,
+    // Messages at or after `from` and before `to`
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+// What a transcript says about itself before the messages
+#[derive(Serialize)]
+struct TranscriptInfo {
+    room_id: i32,
+    room_name: String,
+    exported_at: DateTime<Utc>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+const DELETED_SENDER: &str = "[deleted user]";
+
+const HTML_STYLE: &str = "body{font:14px/1.5 system-ui,sans-serif;color:#222;max-width:50em;margin:2em auto;padding:0 1em}\
+h1{font-size:1.4em;margin:0}.meta{color:#666;margin:.2em 0 1.5em}\
+ol{list-style:none;padding:0}li{padding:.4em 0;border-bottom:1px solid #eee;break-inside:avoid}\
+time{color:#888;font-size:.85em;margin-right:.5em}.sender{font-weight:600}.deleted{color:#888;font-style:italic}\
+.content{white-space:pre-wrap;overflow-wrap:anywhere}\
+@media print{body{margin:0;max-width:none;font-size:11pt}li{border-color:#ccc}}";
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Quoted when it holds a separator, quote or line break (RFC 4180)
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn timestamp(time: Option<DateTime<Utc>>) -> String {
+    time.map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string()).unwrap_or_default()
+}
+
+fn rfc3339(time: Option<DateTime<Utc>>) -> String {
+    time.map(|t| t.to_rfc3339()).unwrap_or_default()
+}
+
+impl TranscriptFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            TranscriptFormat::Json => "application/json",
+            TranscriptFormat::Csv => "text/csv; charset=utf-8",
+            TranscriptFormat::Html => "text/html; charset=utf-8",
+            TranscriptFormat::Txt => "text/plain; charset=utf-8",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            TranscriptFormat::Json => "json",
+            TranscriptFormat::Csv => "csv",
+            TranscriptFormat::Html => "html",
+            TranscriptFormat::Txt => "txt",
+        }
+    }
+
+    fn header(self, info: &TranscriptInfo) -> String {
+        match self {
+            TranscriptFormat::Json => {
+                format!("{{\"room\":{},\"messages\":[", serde_json::to_string(info).unwrap_or_default())
+            }
+            TranscriptFormat::Csv => "id,created_at,sender,content\r\n".to_string(),
+            TranscriptFormat::Html => {
+                let name = escape_html(&info.room_name);
+                format!(
+                    "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+                     <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+                     <title>{name} transcript</title>\n<style>{HTML_STYLE}</style>\n</head>\n<body>\n\
+                     <h1>{name}</h1>\n<p class=\"meta\">{}</p>\n<ol>\n",
+                    escape_html(&describe(info)),
+                )
+            }
+            TranscriptFormat::Txt => format!("{}\n{}\n\n", info.room_name, describe(info)),
+        }
+    }
+
+    fn line(self, line: &TranscriptLine, first: bool) -> String {
+        let sender = line.sender.as_deref().unwrap_or(DELETED_SENDER);
+        match self {
+            TranscriptFormat::Json => {
+                format!("{}\n{}", if first { "" } else { "," }, serde_json::to_string(line).unwrap_or_default())
+            }
+            TranscriptFormat::Csv => format!(
+                "{},{},{},{}\r\n",
+                line.id,
+                rfc3339(line.created_at),
+                csv_field(sender),
+                csv_field(&line.content)
+            ),
+            TranscriptFormat::Html => format!(
+                "<li><time datetime=\"{}\">{}</time><span class=\"{}\">{}</span>\
+                 <div class=\"content\">{}</div></li>\n",
+                rfc3339(line.created_at),
+                timestamp(line.created_at),
+                if line.sender.is_some() { "sender" } else { "sender deleted" },
+                escape_html(sender),
+                escape_html(&line.content)
+            ),
+            // Continuation lines are indented under the first
+            TranscriptFormat::Txt => format!(
+                "[{}] {}: {}\n",
+                timestamp(line.created_at),
+                sender,
+                line.content.replace('\n', "\n    ")
+            ),
+        }
+    }
+
+    fn footer(self) -> &'static str {
+        match self {
+            TranscriptFormat::Json => "\n]}\n",
+            TranscriptFormat::Html => "</ol>\n</body>\n</html>\n",
+            TranscriptFormat::Csv | TranscriptFormat::Txt => "",
+        }
+    }
+}
+
+// "Exported <when>", plus the period covered when it was limited
+fn describe(info: &TranscriptInfo) -> String {
+    let period = match (info.from, info.to) {
+        (Some(from), Some(to)) => format!(", messages from {} until {}", timestamp(Some(from)), timestamp(Some(to))),
+        (Some(from), None) => format!(", messages from {}", timestamp(Some(from))),
+        (None, Some(to)) => format!(", messages until {}", timestamp(Some(to))),
+        (None, None) => String::new(),
+    };
+    format!("Exported {}{}", timestamp(Some(info.exported_at)), period)
+}
+
+// The room's messages in the requested format, for members only. The body is streamed row by
+// row, so long histories are never held in memory.
+pub async fn export_room(
+    auth: AuthUser,
+    pool: web::Data<Pool>,
+    room_id: web::Path<i32>,
+    query: web::Query<TranscriptQuery>,
+) -> Result<HttpResponse, AppError> {
+    let room_id = *room_id;
+    let TranscriptQuery { format, from, to } = query.into_inner();
+    if let (Some(from), Some(to)) = (from, to) {
+        if from >= to {
+            return Err(AppError::BadRequest("from must be before to".to_string()));
+        }
+    }
+
+    let client = pool.get().await?;
+    let not_found = || AppError::NotFound("Room not found".to_string());
+    let room = Room::find_by_id(&client, room_id).await?.ok_or_else(not_found)?;
+    if !Room::is_member(&client, room_id, auth.id).await? {
+        return Err(not_found());
+    }
+
+    let info = TranscriptInfo { room_id, room_name: room.name, exported_at: Utc::now(), from, to };
+    let file_name = format!("room-{}-{}.{}", room_id, info.exported_at.format("%Y%m%d"), format.extension());
+    let head = format.header(&info);
+    let rows = Message::transcript(&client, room_id, from, to).await?;
+
+    let body = stream::once(async move { Ok::<_, tokio_postgres::Error>(Bytes::from(head)) })
+        .chain(rows.enumerate().map(move |(i, row)| {
+            row.map(|row| Bytes::from(format.line(&TranscriptLine::from_row(&row), i == 0)))
+        }))
+        // The pooled connection stays checked out until the last row is sent
+        .chain(stream::once(async move {
+            drop(client);
+            Ok(Bytes::from_static(format.footer().as_bytes()))
+        }));
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name)))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .streaming(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use crate::handlers::test_support::{self, bearer};
+    use super::*;
+
+    fn line(sender: Option<&str>, content: &str) -> TranscriptLine {
+        TranscriptLine {
+            id: 7,
+            sender: sender.map(str::to_string),
+            content: content.to_string(),
+            created_at: DateTime::parse_from_rfc3339("2026-01-02T03:04:05Z").ok().map(|t| t.with_timezone(&Utc)),
+        }
+    }
+
+    #[test]
+    fn csv_quotes_only_when_needed() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\"\nbye"), "\"say \"\"hi\"\"\nbye\"");
+        assert_eq!(
+            TranscriptFormat::Csv.line(&line(None, "x"), true),
+            "7,2026-01-02T03:04:05+00:00,[deleted user],x\r\n"
+        );
+    }
+
+    #[test]
+    fn html_escapes_user_text() {
+        let html = TranscriptFormat::Html.line(&line(Some("<b>"), "<script>alert('x')</script> & co"), true);
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; &amp; co"));
+        assert!(html.contains("&lt;b&gt;"));
+    }
+
+    #[test]
+    fn json_lines_form_an_array() {
+        let info = TranscriptInfo { room_id: 1, room_name: "r".to_string(), exported_at: Utc::now(), from: None, to: None };
+        let format = TranscriptFormat::Json;
+        let document = [
+            format.header(&info),
+            format.line(&line(Some("alice"), "one"), true),
+            format.line(&line(None, "two"), false),
+            format.footer().to_string(),
+        ]
+        .concat();
+        let value: serde_json::Value = serde_json::from_str(&document).unwrap();
+        assert_eq!(value["room"]["room_name"], "r");
+        assert_eq!(value["messages"][1]["sender"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn txt_indents_continuation_lines() {
+        assert_eq!(
+            TranscriptFormat::Txt.line(&line(Some("bob"), "a\nb"), true),
+            "[2026-01-02 03:04:05 UTC] bob: a\n    b\n"
+        );
+    }
+
+    #[actix_web::test]
+    async fn private_transcripts_are_for_members_only() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let (_, outsider) = test_support::user(&pool, "outsider").await;
+        let room_id = test_support::room(&pool, owner_id, "private").await;
+        test_support::message(&pool, room_id, owner_id, "secret plans").await;
+        let export = |token: &str| {
+            TestRequest::get()
+                .uri(&format!("/api/rooms/{}/export?format=txt", room_id))
+                .insert_header(bearer(token))
+                .to_request()
+        };
+
+        assert_eq!(test::call_service(&app, export(&outsider)).await.status(), StatusCode::NOT_FOUND);
+        let response = test::call_service(&app, export(&owner)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = test::read_body(response).await;
+        assert!(String::from_utf8_lossy(&body).contains("secret plans"));
+    }
+
+    #[actix_web::test]
+    async fn transcripts_cover_only_the_requested_window() {
+        let Some(pool) = test_support::pool().await else { return };
+        let app = test::init_service(test_support::app(&pool)).await;
+        let (owner_id, owner) = test_support::user(&pool, "owner").await;
+        let room_id = test_support::room(&pool, owner_id, "public").await;
+        let client = pool.get().await.unwrap();
+        let sent = [("early", "2026-01-01 09:00"), ("inside", "2026-01-01 12:00"), ("late", "2026-01-01 15:00")];
+        for (content, sent_at) in sent {
+            let id = test_support::message(&pool, room_id, owner_id, content).await;
+            client
+                .execute("UPDATE messages SET created_at = $2::TEXT::TIMESTAMP WHERE id = $1", &[&id, &sent_at])
+                .await
+                .unwrap();
+        }
+        let export = |query: &str| {
+            TestRequest::get()
+                .uri(&format!("/api/rooms/{}/export?{}", room_id, query))
+                .insert_header(bearer(&owner))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, export("from=2026-01-01T12:00:00Z&to=2026-01-01T15:00:00Z")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let value: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+        let messages = value["messages"].as_array().unwrap();
+        assert_eq!(messages.iter().map(|m| m["content"].as_str().unwrap()).collect::<Vec<_>>(), ["inside"]);
+        assert_eq!(value["room"]["from"], "2026-01-01T12:00:00Z");
+
+        let response = test::call_service(&app, export("from=2026-01-01T12:00:00Z&to=2026-01-01T12:00:00Z")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = test::call_service(&app, export("from=2026-01-01T13:00:00Z&to=2026-01-01T12:00:00Z")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,203 @@
+use actix_web::{dev::Payload, error::JsonPayloadError, web, FromRequest, HttpRequest};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::error::Category;
+use std::borrow::Cow;
+use std::future::Future;
+use std::pin::Pin;
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
+use crate::error::AppError;
+use crate::models::permissions::Role;
+
+pub const ROOM_TYPES: [&str; 3] = ["public", "private", "protected"];
+
+// One failed rule, as reported to the client
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+// A JSON body that has passed its `Validate` rules; failures become a 422 listing every field error
+pub struct Validated<T>(pub T);
+
+impl<T> Validated<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> std::ops::Deref for Validated<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for Validated<T> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let body = web::Json::<T>::from_request(req, payload);
+        Box::pin(async move {
+            let body = match body.await {
+                Ok(body) => body.into_inner(),
+                Err(e) => {
+                    if let Some(JsonPayloadError::Deserialize(error)) = e.as_error::<JsonPayloadError>() {
+                        // Well-formed JSON of the wrong shape is reported like any other field error
+                        if error.classify() == Category::Data {
+                            return Err(AppError::Validation(vec![shape_error(error)]).into());
+                        }
+                    }
+                    return Err(AppError::BadRequest(format!("Invalid request body: {}", e)).into());
+                }
+            };
+
+            body.validate().map_err(|errors| AppError::Validation(field_errors(&errors)))?;
+            Ok(Validated(body))
+        })
+    }
+}
+
+pub fn field_errors(errors: &ValidationErrors) -> Vec<FieldError> {
+    let mut fields = Vec::new();
+    collect_errors("", errors, &mut fields);
+    fields.sort_by(|a, b| a.field.cmp(&b.field));
+    fields
+}
+
+// serde reports missing fields as "missing field `name` at line .."; anything else is about the body
+fn shape_error(error: &serde_json::Error) -> FieldError {
+    let text = error.to_string();
+    let missing = text
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.split('`').next());
+    match missing {
+        Some(field) => FieldError {
+            field: field.to_string(),
+            code: "required".to_string(),
+            message: format!("{} is required", field),
+        },
+        None => FieldError {
+            field: "body".to_string(),
+            code: "type".to_string(),
+            message: text,
+        },
+    }
+}
+
+fn collect_errors(prefix: &str, errors: &ValidationErrors, out: &mut Vec<FieldError>) {
+    for (field, kind) in errors.errors() {
+        let path = if prefix.is_empty() { field.to_string() } else { format!("{}.{}", prefix, field) };
+        match kind {
+            ValidationErrorsKind::Field(field_errors) => {
+                for error in field_errors {
+                    // Struct-level rules name the field they are about
+                    let field = match error.params.get("field").and_then(|f| f.as_str()) {
+                        Some(field) => field.to_string(),
+                        None => path.clone(),
+                    };
+                    out.push(FieldError {
+                        message: describe(&field, error),
+                        code: error.code.to_string(),
+                        field,
+                    });
+                }
+            }
+            ValidationErrorsKind::Struct(nested) => collect_errors(&path, nested, out),
+            ValidationErrorsKind::List(items) => {
+                for (index, nested) in items {
+                    collect_errors(&format!("{}[{}]", path, index), nested, out);
+                }
+            }
+        }
+    }
+}
+
+// Never echoes the submitted value back; it may be a password
+fn describe(field: &str, error: &ValidationError) -> String {
+    if let Some(message) = &error.message {
+        return format!("{} {}", field, message);
+    }
+    let min = error.params.get("min").and_then(|v| v.as_u64());
+    let max = error.params.get("max").and_then(|v| v.as_u64());
+    match (error.code.as_ref(), min, max) {
+        ("length", Some(min), Some(max)) => format!("{} must be between {} and {} characters", field, min, max),
+        ("length", None, Some(max)) => format!("{} must be at most {} characters", field, max),
+        ("length", Some(min), None) => format!("{} must be at least {} characters", field, min),
+        (code, _, _) => format!("{} is invalid ({})", field, code),
+    }
+}
+
+fn invalid(code: &'static str, message: &'static str) -> ValidationError {
+    ValidationError::new(code).with_message(Cow::Borrowed(message))
+}
+
+// Error for a struct-level rule, attributed to one field
+pub fn invalid_field(field: &'static str, code: &'static str, message: &'static str) -> ValidationError {
+    let mut error = invalid(code, message);
+    error.add_param(Cow::Borrowed("field"), &field);
+    error
+}
+
+pub fn not_blank(value: &str) -> Result<(), ValidationError> {
+    match value.trim().is_empty() {
+        true => Err(invalid("blank", "must not be blank")),
+        false => Ok(()),
+    }
+}
+
+pub fn single_line(value: &str) -> Result<(), ValidationError> {
+    match value.chars().any(char::is_control) {
+        true => Err(invalid("charset", "must be a single line without control characters")),
+        false => Ok(()),
+    }
+}
+
+// Multi-line text: newlines are fine, other control characters are not
+pub fn no_control_chars(value: &str) -> Result<(), ValidationError> {
+    match value.chars().any(|c| c.is_control() && c != '\n') {
+        true => Err(invalid("charset", "must not contain control characters")),
+        false => Ok(()),
+    }
+}
+
+pub fn username_chars(value: &str) -> Result<(), ValidationError> {
+    match value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        true => Ok(()),
+        false => Err(invalid("charset", "may only contain letters, digits, '_', '-' and '.'")),
+    }
+}
+
+pub fn http_url(value: &str) -> Result<(), ValidationError> {
+    let value = value.trim();
+    match (value.starts_with("https://") || value.starts_with("http://")) && !value.contains(char::is_whitespace) {
+        true => Ok(()),
+        false => Err(invalid("url", "must be an http(s) URL")),
+    }
+}
+
+pub fn room_type(value: &str) -> Result<(), ValidationError> {
+    match ROOM_TYPES.contains(&value) {
+        true => Ok(()),
+        false => Err(invalid("enum", "must be one of: public, private, protected")),
+    }
+}
+
+pub fn role(value: &str) -> Result<(), ValidationError> {
+    match Role::parse(value) {
+        Some(_) => Ok(()),
+        None => Err(invalid("enum", "must be one of: owner, admin, moderator, member, read_only")),
+    }
+}
+
+pub fn in_future(value: &DateTime<Utc>) -> Result<(), ValidationError> {
+    match *value > Utc::now() {
+        true => Ok(()),
+        false => Err(invalid("past", "must be in the future")),
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use crate::db::models::{ImportedMessage, RoomImport};
+use crate::settings::MessageSettings;
+use super::{message_text, read_json, single_line, unique_names, Archive, ImportError, ParseStats, ParsedRoom};
+
+#[derive(Deserialize)]
+struct DiscordExport {
+    channel: DiscordChannel,
+    messages: Vec<DiscordMessage>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DiscordChannel {
+    name: String,
+    // e.g. GuildTextChat, GuildPublicThread or DirectTextChat
+    #[serde(rename = "type", default)]
+    kind: String,
+    // The parent channel's name for threads
+    #[serde(default)]
+    category: Option<String>,
+    #[serde(default)]
+    topic: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DiscordMessage {
+    // Default, Reply, or an event such as GuildMemberJoin or ChannelPinnedMessage
+    #[serde(rename = "type")]
+    kind: String,
+    timestamp: DateTime<Utc>,
+    #[serde(default)]
+    content: String,
+    author: DiscordUser,
+    #[serde(default)]
+    attachments: Vec<DiscordAttachment>,
+    #[serde(default)]
+    reactions: Vec<DiscordReaction>,
+    #[serde(default)]
+    mentions: Vec<DiscordUser>,
+}
+
+#[derive(Deserialize)]
+struct DiscordUser {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DiscordAttachment {
+    #[serde(default)]
+    file_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DiscordReaction {
+    #[serde(default)]
+    count: usize,
+}
+
+// One or more channel exports in DiscordChatExporter's JSON format. Threads are merged into
+// their parent channel's room when it is part of the export.
+pub fn parse(entries: &HashMap<String, Vec<u8>>, settings: &MessageSettings) -> Result<Archive, ImportError> {
+    let mut archive = Archive::default();
+    let mut files: Vec<&String> = entries.keys().collect();
+    files.sort();
+
+    let mut rooms: Vec<ParsedRoom> = Vec::new();
+    // Author ids to names as the export gives them
+    let mut authors = HashMap::new();
+    for file in files {
+        let export: DiscordExport = match read_json(file, &entries[file]) {
+            Ok(export) => export,
+            // A lone JSON document must parse; inside a ZIP, other files are skipped
+            Err(e) if entries.len() > 1 => {
+                archive.warnings.push(format!("skipped {}", e));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let thread = export.channel.kind.contains("Thread");
+        let name = single_line(match (&export.channel.category, thread) {
+            (Some(parent), true) => parent,
+            _ => &export.channel.name,
+        });
+        if name.is_empty() {
+            archive.warnings.push(format!("skipped {}: the channel has no name", file));
+            continue;
+        }
+        let topic = export
+            .channel
+            .topic
+            .as_deref()
+            .map(single_line)
+            .filter(|topic| !topic.is_empty() && !thread);
+        let index = match rooms.iter().position(|parsed| parsed.room.name == name) {
+            // A thread may come before its channel
+            Some(index) => {
+                let room = &mut rooms[index].room;
+                room.topic = room.topic.take().or(topic);
+                index
+            }
+            None => {
+                let room_type = if export.channel.kind.starts_with("Direct") { "private" } else { "public" };
+                rooms.push(ParsedRoom {
+                    room: RoomImport { name, room_type, topic, members: Vec::new(), messages: Vec::new() },
+                    stats: ParseStats::default(),
+                });
+                rooms.len() - 1
+            }
+        };
+
+        let ParsedRoom { room, stats } = &mut rooms[index];
+        for message in export.messages {
+            if let Some(message) = convert(message, thread, &mut authors, settings, stats) {
+                room.messages.push(message);
+            }
+        }
+    }
+
+    for ParsedRoom { room, .. } in &mut rooms {
+        room.messages.sort_by_key(|m| m.created_at);
+    }
+    if rooms.is_empty() {
+        return Err(ImportError::Invalid("no Discord channel exports were found".to_string()));
+    }
+    archive.rooms = rooms;
+    archive.authors = unique_names(&authors);
+    Ok(archive)
+}
+
+fn convert(
+    message: DiscordMessage,
+    in_thread: bool,
+    authors: &mut HashMap<String, String>,
+    settings: &MessageSettings,
+    stats: &mut ParseStats,
+) -> Option<ImportedMessage> {
+    stats.messages += 1;
+    if message.kind != "Default" && message.kind != "Reply" {
+        stats.events_skipped += 1;
+        return None;
+    }
+    if in_thread || message.kind == "Reply" {
+        stats.replies += 1;
+    }
+    stats.reactions_skipped += message.reactions.iter().map(|r| r.count).sum::<usize>();
+
+    // Raw mentions look like <@123> or <@!123>
+    let mut text = message.content;
+    for user in &message.mentions {
+        for raw in [format!("<@{}>", user.id), format!("<@!{}>", user.id)] {
+            text = text.replace(&raw, &format!("@{}", user.name));
+        }
+    }
+    let files: Vec<String> = message
+        .attachments
+        .iter()
+        .map(|file| file.file_name.clone().unwrap_or_else(|| "file".to_string()))
+        .collect();
+    let content = message_text(&text, &files, settings, stats)?;
+    authors.entry(message.author.id.clone()).or_insert(message.author.name);
+    Some(ImportedMessage { sender: message.author.id, content, created_at: message.timestamp })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL: &str = r#"{
+        "guild": { "id": "1", "name": "Team" },
+        "channel": { "id": "2", "type": "GuildTextChat", "category": "Text", "name": "general", "topic": "Hello" },
+        "messages": [
+            { "id": "11", "type": "GuildMemberJoin", "timestamp": "2021-01-01T00:00:00+00:00",
+              "content": "", "author": { "id": "5", "name": "Bob" } },
+            { "id": "12", "type": "Reply", "timestamp": "2021-01-01T01:00:00.5+01:00",
+              "content": "hey <@!6>", "author": { "id": "5", "name": "Bob" },
+              "mentions": [ { "id": "6", "name": "carol" } ],
+              "reactions": [ { "emoji": { "name": "👍" }, "count": 3 } ],
+              "attachments": [ { "id": "7", "url": "https://cdn/x.png", "fileName": "x.png" } ] }
+        ]
+    }"#;
+
+    const THREAD: &str = r#"{
+        "channel": { "id": "3", "type": "GuildPublicThread", "category": "general", "name": "plans" },
+        "messages": [
+            { "id": "13", "type": "Default", "timestamp": "2020-12-31T23:00:00+00:00",
+              "content": "in the thread", "author": { "id": "6", "name": "carol" } }
+        ]
+    }"#;
+
+    #[test]
+    fn threads_join_their_parent_channel() {
+        let entries = HashMap::from([
+            ("general.json".to_string(), CHANNEL.as_bytes().to_vec()),
+            ("plans.json".to_string(), THREAD.as_bytes().to_vec()),
+        ]);
+        let archive = parse(&entries, &MessageSettings::default()).unwrap();
+        assert_eq!(archive.rooms.len(), 1);
+
+        let ParsedRoom { room, stats } = &archive.rooms[0];
+        assert_eq!((room.name.as_str(), room.topic.as_deref()), ("general", Some("Hello")));
+        let texts: Vec<&str> = room.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(texts, ["in the thread", "hey @carol\n[file: x.png]"]);
+        assert_eq!(room.messages[1].sender, "5");
+        assert_eq!(archive.authors["5"], "bob");
+        assert_eq!(room.messages[1].created_at.to_rfc3339(), "2021-01-01T00:00:00.500+00:00");
+        assert_eq!((stats.events_skipped, stats.replies, stats.reactions_skipped, stats.attachments_skipped), (1, 2, 3, 1));
+    }
+}
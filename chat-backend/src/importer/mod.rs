@@ -0,0 +1,400 @@
+pub mod discord;
+pub mod slack;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Cursor, Read};
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use tokio_postgres::Client;
+use zip::ZipArchive;
+use crate::db::models::{ImportAuthors, RoomImport, RoomImportResult, User};
+use crate::models::audit::{self, Change, RequestOrigin};
+use crate::models::content::{self, ContentError};
+use crate::settings::{ImportArgs, ImportSource, MessageSettings};
+
+// Room names and topics are cut to the column width
+const MAX_NAME_CHARS: usize = 255;
+
+#[derive(Debug)]
+pub enum ImportError {
+    Zip(zip::result::ZipError),
+    Json { file: String, error: serde_json::Error },
+    Invalid(String),
+    Database(tokio_postgres::Error),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Zip(e) => write!(f, "not a readable ZIP archive: {}", e),
+            ImportError::Json { file, error } => write!(f, "{}: {}", file, error),
+            ImportError::Invalid(message) => write!(f, "{}", message),
+            ImportError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl From<zip::result::ZipError> for ImportError {
+    fn from(error: zip::result::ZipError) -> Self {
+        ImportError::Zip(error)
+    }
+}
+
+impl From<tokio_postgres::Error> for ImportError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        ImportError::Database(error)
+    }
+}
+
+// What was read from an export, before anything is written
+#[derive(Debug, Clone, Default)]
+pub struct Archive {
+    pub rooms: Vec<ParsedRoom>,
+    // Author ids to names unique within the export
+    pub authors: HashMap<String, String>,
+    // Files or entries that were skipped, with the reason
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParsedRoom {
+    pub room: RoomImport,
+    pub stats: ParseStats,
+}
+
+// What the parser made of a room's messages. This server has no threads or reactions, so
+// thread replies are imported in time order and reactions are only counted.
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct ParseStats {
+    pub messages: usize,
+    pub replies: usize,
+    pub reactions_skipped: usize,
+    // Files are not copied; the message notes their names instead
+    pub attachments_skipped: usize,
+    // Joins, leaves, pins, topic changes and the like
+    pub events_skipped: usize,
+    pub empty_skipped: usize,
+    // Longer than `messages.max_length`, which live messages may not be either
+    pub too_long_skipped: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ImportReport {
+    pub source: ImportSource,
+    pub dry_run: bool,
+    pub rooms: Vec<RoomReport>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RoomReport {
+    pub name: String,
+    pub parsed: ParseStats,
+    // None when the room failed; nothing from it was written
+    pub result: Option<RoomImportResult>,
+    pub error: Option<String>,
+}
+
+impl ImportReport {
+    pub fn failed(&self) -> usize {
+        self.rooms.iter().filter(|room| room.error.is_some()).count()
+    }
+}
+
+// Slack exports are ZIP archives with users.json at the top; anything else that is a ZIP or a
+// JSON document is taken for Discord
+pub fn detect(data: &[u8]) -> Option<ImportSource> {
+    if data.starts_with(b"PK") {
+        let entries = entries(data).ok()?;
+        let slack = entries.keys().any(|name| name == "users.json" || name.ends_with("/users.json"));
+        Some(if slack { ImportSource::Slack } else { ImportSource::Discord })
+    } else if data.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{') {
+        Some(ImportSource::Discord)
+    } else {
+        None
+    }
+}
+
+// Messages are held to the same rules as live ones
+pub fn parse(source: ImportSource, data: &[u8], messages: &MessageSettings) -> Result<Archive, ImportError> {
+    match source {
+        ImportSource::Slack => slack::parse(&entries(data)?, messages),
+        ImportSource::Discord if data.starts_with(b"PK") => discord::parse(&entries(data)?, messages),
+        ImportSource::Discord => {
+            discord::parse(&HashMap::from([("export.json".to_string(), data.to_vec())]), messages)
+        }
+    }
+}
+
+// Placeholder accounts are named after the service the authors came from
+fn source_name(source: ImportSource) -> &'static str {
+    match source {
+        ImportSource::Slack => "slack",
+        ImportSource::Discord => "discord",
+    }
+}
+
+// Usernames for the authors of an export, keyed by their ids. Names that come out the same, as
+// "Zoë" and "Zo!" do, are told apart with a number: zo_, zo_2.
+fn unique_names(authors: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut ids: Vec<&String> = authors.keys().collect();
+    ids.sort();
+    let mut taken = HashSet::new();
+    let mut names = HashMap::new();
+    for id in ids {
+        let base = username(&authors[id]);
+        let mut name = base.clone();
+        let mut n = 1;
+        while !taken.insert(name.clone()) {
+            n += 1;
+            let suffix = n.to_string();
+            name = format!("{}{}", base.chars().take(32 - suffix.len()).collect::<String>(), suffix);
+        }
+        names.insert(id.clone(), name);
+    }
+    names
+}
+
+// "author=username" pairs an admin confirmed, keyed by the author's name in the import report
+pub fn parse_authors<'a>(pairs: impl IntoIterator<Item = &'a str>) -> Result<HashMap<String, String>, ImportError> {
+    let mut authors = HashMap::new();
+    for pair in pairs {
+        match pair.split_once('=') {
+            Some((author, local)) if !author.trim().is_empty() && !local.trim().is_empty() => {
+                authors.insert(username(author), local.trim().to_string());
+            }
+            _ => return Err(ImportError::Invalid(format!("{:?} is not an author=username pair", pair))),
+        }
+    }
+    Ok(authors)
+}
+
+// The accounts mapped authors will post as, by author id; every author must be in the export and
+// every account must exist
+pub async fn resolve_authors(
+    client: &Client,
+    archive: &Archive,
+    authors: &HashMap<String, String>,
+) -> Result<HashMap<String, i32>, ImportError> {
+    let mut ids = HashMap::new();
+    for (name, local) in authors {
+        let author = archive
+            .authors
+            .iter()
+            .find_map(|(author, author_name)| (author_name == name).then_some(author))
+            .ok_or_else(|| ImportError::Invalid(format!("the export has no author named {}", name)))?;
+        let id = User::find_by_username(client, local)
+            .await?
+            .and_then(|user| user.id)
+            .ok_or_else(|| ImportError::Invalid(format!("there is no user named {}", local)))?;
+        ids.insert(author.clone(), id);
+    }
+    Ok(ids)
+}
+
+// Every JSON file in a ZIP archive, by path
+fn entries(data: &[u8]) -> Result<HashMap<String, Vec<u8>>, ImportError> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+    let mut entries = HashMap::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if !file.is_file() || !file.name().ends_with(".json") {
+            continue;
+        }
+        let name = file.name().to_string();
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).map_err(|e| ImportError::Invalid(format!("{}: {}", name, e)))?;
+        entries.insert(name, contents);
+    }
+    Ok(entries)
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(file: &str, data: &[u8]) -> Result<T, ImportError> {
+    serde_json::from_slice(data).map_err(|error| ImportError::Json { file: file.to_string(), error })
+}
+
+// A username this server accepts: 3 to 32 letters, digits, '_', '-' or '.'
+fn username(name: &str) -> String {
+    let mut username: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') { c.to_ascii_lowercase() } else { '_' })
+        .take(32)
+        .collect();
+    while username.len() < 3 {
+        username.push('_');
+    }
+    username
+}
+
+// A room name or topic on one line, without control characters
+fn single_line(text: &str) -> String {
+    let line = content::normalize(text).split_whitespace().collect::<Vec<_>>().join(" ");
+    line.chars().take(MAX_NAME_CHARS).collect()
+}
+
+// Notes files that were not copied, then normalizes and checks the text like a live chat
+// message. None when it is empty or too long to post.
+fn message_text(text: &str, files: &[String], settings: &MessageSettings, stats: &mut ParseStats) -> Option<String> {
+    let mut text = text.trim().to_string();
+    for file in files {
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push_str(&format!("[file: {}]", single_line(file)));
+    }
+    stats.attachments_skipped += files.len();
+    match content::prepare_message(&text, false, settings) {
+        Ok(text) => Some(text),
+        Err(ContentError::Empty) => {
+            stats.empty_skipped += 1;
+            None
+        }
+        Err(ContentError::TooLong { .. }) => {
+            stats.too_long_skipped += 1;
+            None
+        }
+    }
+}
+
+// Writes each room as a new room in its own transaction; a room that fails is reported and the
+// rest go on. The rooms belong to `owner_id`, who is also recorded as the actor in the audit log.
+// Authors post as the accounts `mapped` gives for their ids, or as placeholders.
+pub async fn run(
+    client: &mut Client,
+    origin: &RequestOrigin,
+    owner_id: i32,
+    source: ImportSource,
+    archive: Archive,
+    mapped: HashMap<String, i32>,
+    dry_run: bool,
+) -> ImportReport {
+    let mut report = ImportReport { source, dry_run, rooms: Vec::new(), warnings: archive.warnings };
+    let authors = ImportAuthors { source: source_name(source), names: archive.authors, mapped };
+    for ParsedRoom { room, stats } in archive.rooms {
+        let mut room_report = RoomReport { name: room.name.clone(), parsed: stats, result: None, error: None };
+        match room.apply(client, owner_id, &authors, dry_run).await {
+            Ok(result) => {
+                if let (Some(room_id), false) = (result.room_id, dry_run) {
+                    let change = Change::new("room.import", "room", room_id)
+                        .room(room_id)
+                        .after(&json_summary(source, &authors.mapped, &result, &stats));
+                    audit::record(client, origin, owner_id, change).await;
+                }
+                room_report.result = Some(result);
+            }
+            Err(e) => {
+                log::error!("Failed to import room {}: {}", room.name, e);
+                room_report.error = Some(e.to_string());
+            }
+        }
+        report.rooms.push(room_report);
+    }
+    report
+}
+
+fn json_summary(
+    source: ImportSource,
+    authors: &HashMap<String, i32>,
+    result: &RoomImportResult,
+    stats: &ParseStats,
+) -> serde_json::Value {
+    serde_json::json!({
+        "source": source,
+        "authors_mapped": authors,
+        "users_created": result.users_created.len(),
+        "messages_imported": result.messages_imported,
+        "parsed": stats,
+    })
+}
+
+// `chat-backend import`: prints the report as JSON and fails if any room could not be imported
+pub async fn run_cli(pool: &Pool, messages: &MessageSettings, args: &ImportArgs) -> std::io::Result<()> {
+    let data = std::fs::read(&args.path)?;
+    let source = match args.source.or_else(|| detect(&data)) {
+        Some(source) => source,
+        None => return Err(std::io::Error::other("cannot tell the export format; pass --source")),
+    };
+    let archive = parse(source, &data, messages).map_err(|e| std::io::Error::other(e.to_string()))?;
+    let authors = parse_authors(args.map.iter().map(String::as_str)).map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    let mut client = pool.get().await.map_err(std::io::Error::other)?;
+    let authors = resolve_authors(&client, &archive, &authors).await.map_err(|e| std::io::Error::other(e.to_string()))?;
+    let owner = User::find_by_username(&client, &args.owner)
+        .await
+        .map_err(std::io::Error::other)?
+        .and_then(|user| user.id)
+        .ok_or_else(|| std::io::Error::other(format!("there is no user named {}", args.owner)))?;
+
+    let origin = RequestOrigin { ip: None, request_id: None };
+    let report = run(&mut client, &origin, owner, source, archive, authors, args.dry_run).await;
+    println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default());
+
+    match report.failed() {
+        0 => Ok(()),
+        failed => Err(std::io::Error::other(format!("{} of {} rooms failed to import", failed, report.rooms.len()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usernames_are_made_acceptable() {
+        assert_eq!(username("Alice Smith"), "alice_smith");
+        assert_eq!(username("jo"), "jo_");
+        assert_eq!(username("Zoë"), "zo_");
+        assert_eq!(username(&"x".repeat(40)).len(), 32);
+    }
+
+    #[test]
+    fn files_are_noted_and_empty_messages_dropped() {
+        let settings = MessageSettings::default();
+        let mut stats = ParseStats::default();
+        let files = ["a.png".to_string()];
+        assert_eq!(message_text(" hi ", &files, &settings, &mut stats).as_deref(), Some("hi\n[file: a.png]"));
+        assert_eq!(message_text("  ", &[], &settings, &mut stats), None);
+        assert_eq!((stats.attachments_skipped, stats.empty_skipped), (1, 1));
+    }
+
+    #[test]
+    fn messages_follow_the_live_rules() {
+        let settings = MessageSettings { max_length: 5, ..MessageSettings::default() };
+        let mut stats = ParseStats::default();
+        assert_eq!(message_text("a\u{0}b\u{202e}c", &[], &settings, &mut stats).as_deref(), Some("abc"));
+        assert_eq!(message_text("toolong", &[], &settings, &mut stats), None);
+        assert_eq!(stats.too_long_skipped, 1);
+    }
+
+    #[test]
+    fn names_are_kept_to_one_line() {
+        assert_eq!(single_line(" general\n\u{7}chat\t "), "general chat");
+        assert_eq!(single_line(&"x".repeat(300)).len(), MAX_NAME_CHARS);
+    }
+
+    #[test]
+    fn authors_whose_names_clash_are_numbered() {
+        let authors = HashMap::from([
+            ("1".to_string(), "Zoë".to_string()),
+            ("2".to_string(), "Zo!".to_string()),
+            ("3".to_string(), "zo_".to_string()),
+            ("4".to_string(), "x".repeat(40)),
+            ("5".to_string(), "x".repeat(32)),
+        ]);
+        let names = unique_names(&authors);
+        assert_eq!((names["1"].as_str(), names["2"].as_str(), names["3"].as_str()), ("zo_", "zo_2", "zo_3"));
+        assert_eq!(names["4"], "x".repeat(32));
+        assert_eq!(names["5"], format!("{}2", "x".repeat(31)));
+    }
+
+    #[test]
+    fn author_mappings_need_both_names() {
+        let authors = parse_authors(["Bob Smith=bob", "carol = carol2"]).unwrap();
+        assert_eq!(authors["bob_smith"], "bob");
+        assert_eq!(authors["carol"], "carol2");
+        assert!(parse_authors(["bob"]).is_err());
+        assert!(parse_authors(["=bob"]).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use crate::db::models::{ImportedMessage, RoomImport};
+use crate::settings::MessageSettings;
+use super::{message_text, read_json, single_line, unique_names, username, Archive, ImportError, ParseStats, ParsedRoom};
+
+// Message subtypes that carry something a person wrote; the rest are channel events
+const CONTENT_SUBTYPES: [&str; 5] = ["thread_broadcast", "me_message", "bot_message", "file_share", "reply_broadcast"];
+
+#[derive(Deserialize)]
+struct SlackUser {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct SlackChannel {
+    name: String,
+    #[serde(default)]
+    topic: Option<SlackText>,
+    #[serde(default)]
+    members: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct SlackText {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct SlackMessage {
+    #[serde(default)]
+    subtype: Option<String>,
+    #[serde(default)]
+    user: Option<String>,
+    // Set on bot messages instead of `user`
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    text: String,
+    ts: String,
+    #[serde(default)]
+    thread_ts: Option<String>,
+    #[serde(default)]
+    reactions: Vec<SlackReaction>,
+    #[serde(default)]
+    files: Vec<SlackFile>,
+}
+
+#[derive(Deserialize)]
+struct SlackReaction {
+    #[serde(default)]
+    count: usize,
+}
+
+#[derive(Deserialize)]
+struct SlackFile {
+    #[serde(default)]
+    name: Option<String>,
+}
+
+// A Slack workspace export: users.json, channels.json (public) and groups.json (private) at
+// the top, and a folder of daily message files per channel
+pub fn parse(entries: &HashMap<String, Vec<u8>>, settings: &MessageSettings) -> Result<Archive, ImportError> {
+    let users_file = entries
+        .keys()
+        .filter(|name| *name == "users.json" || name.ends_with("/users.json"))
+        .min_by_key(|name| name.len())
+        .ok_or_else(|| ImportError::Invalid("users.json is missing; is this a Slack export?".to_string()))?;
+    // Some tools wrap the export in a folder
+    let prefix = &users_file[..users_file.len() - "users.json".len()];
+
+    let users: Vec<SlackUser> = read_json(users_file, &entries[users_file])?;
+    // Author ids to names as the export gives them
+    let mut authors: HashMap<String, String> = users.into_iter().map(|user| (user.id, user.name)).collect();
+    // For mentions
+    let names: HashMap<String, String> = authors.iter().map(|(id, name)| (id.clone(), username(name))).collect();
+
+    let mut archive = Archive::default();
+    for (file, room_type) in [("channels.json", "public"), ("groups.json", "private")] {
+        let path = format!("{}{}", prefix, file);
+        let Some(data) = entries.get(&path) else {
+            continue;
+        };
+        let channels: Vec<SlackChannel> = read_json(&path, data)?;
+        for channel in channels {
+            let folder = format!("{}{}/", prefix, channel.name);
+            let mut days: Vec<&String> = entries.keys().filter(|name| name.starts_with(&folder)).collect();
+            days.sort();
+
+            let mut stats = ParseStats::default();
+            let mut messages = Vec::new();
+            for day in days {
+                let day_messages: Vec<SlackMessage> = match read_json(day, &entries[day]) {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        archive.warnings.push(format!("skipped {}", e));
+                        continue;
+                    }
+                };
+                messages.extend(day_messages.into_iter().filter_map(|m| convert(m, &names, &mut authors, settings, &mut stats)));
+            }
+            messages.sort_by_key(|m| m.created_at);
+
+            let members = channel.members.into_iter().filter(|id| names.contains_key(id)).collect();
+            let topic = channel.topic.map(|topic| single_line(&topic.value)).filter(|topic| !topic.is_empty());
+            let name = single_line(&channel.name);
+            archive.rooms.push(ParsedRoom {
+                room: RoomImport { name, room_type, topic, members, messages },
+                stats,
+            });
+        }
+    }
+    if archive.rooms.is_empty() {
+        return Err(ImportError::Invalid("the export has no channels".to_string()));
+    }
+    archive.authors = unique_names(&authors);
+    Ok(archive)
+}
+
+fn convert(
+    message: SlackMessage,
+    names: &HashMap<String, String>,
+    authors: &mut HashMap<String, String>,
+    settings: &MessageSettings,
+    stats: &mut ParseStats,
+) -> Option<ImportedMessage> {
+    stats.messages += 1;
+    if message.subtype.as_deref().is_some_and(|subtype| !CONTENT_SUBTYPES.contains(&subtype)) {
+        stats.events_skipped += 1;
+        return None;
+    }
+    let Some(created_at) = timestamp(&message.ts) else {
+        stats.events_skipped += 1;
+        return None;
+    };
+    if message.thread_ts.as_ref().is_some_and(|thread| *thread != message.ts) {
+        stats.replies += 1;
+    }
+    stats.reactions_skipped += message.reactions.iter().map(|r| r.count).sum::<usize>();
+
+    // Bots have no user id, only a name
+    let (sender, name) = match (&message.user, &message.username) {
+        (Some(id), _) => (id.clone(), id.clone()),
+        (None, Some(name)) => (format!("bot:{}", name), name.clone()),
+        (None, None) => ("USLACKBOT".to_string(), "slackbot".to_string()),
+    };
+    authors.entry(sender.clone()).or_insert(name);
+    let files: Vec<String> = message
+        .files
+        .iter()
+        .map(|file| file.name.clone().unwrap_or_else(|| "file".to_string()))
+        .collect();
+    let content = message_text(&plain_text(&message.text, names), &files, settings, stats)?;
+    Some(ImportedMessage { sender, content, created_at })
+}
+
+// "1609459200.000100": seconds and microseconds since the epoch
+fn timestamp(ts: &str) -> Option<DateTime<Utc>> {
+    let (secs, micros) = ts.split_once('.').unwrap_or((ts, "0"));
+    DateTime::from_timestamp(secs.parse().ok()?, micros.parse::<u32>().ok()?.checked_mul(1000)?)
+}
+
+// Slack's markup to plain text: <@U123> mentions, <#C123|general> channels, <!here> and
+// <https://…|label> links, and the three escaped HTML characters
+fn plain_text(text: &str, names: &HashMap<String, String>) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        plain.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+        let token = &rest[start + 1..start + end];
+        let (target, label) = token.split_once('|').unwrap_or((token, ""));
+        match target.chars().next() {
+            Some('@') => {
+                let name = names.get(&target[1..]).map(String::as_str).unwrap_or(if label.is_empty() { &target[1..] } else { label });
+                plain.push('@');
+                plain.push_str(name);
+            }
+            Some('#') => {
+                plain.push('#');
+                plain.push_str(if label.is_empty() { &target[1..] } else { label });
+            }
+            Some('!') => {
+                plain.push('@');
+                plain.push_str(if label.is_empty() { &target[1..] } else { label });
+            }
+            _ if label.is_empty() || label == target => plain.push_str(target),
+            _ => plain.push_str(&format!("{} ({})", label, target)),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    plain.push_str(rest);
+    plain.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markup_becomes_plain_text() {
+        let names = HashMap::from([("U1".to_string(), "alice".to_string())]);
+        assert_eq!(
+            plain_text("<@U1> see <#C9|general> &amp; <https://example.com|the docs> <!here> 1 &lt; 2", &names),
+            "@alice see #general & the docs (https://example.com) @here 1 < 2"
+        );
+        assert_eq!(plain_text("<https://example.com> <@U2>", &names), "https://example.com @U2");
+    }
+
+    #[test]
+    fn timestamps_keep_microseconds() {
+        let time = timestamp("1609459200.000100").unwrap();
+        assert_eq!(time.to_rfc3339(), "2021-01-01T00:00:00.000100+00:00");
+    }
+
+    #[test]
+    fn events_are_skipped_and_replies_counted() {
+        let names = HashMap::new();
+        let mut stats = ParseStats::default();
+        let message = |subtype: Option<&str>, thread_ts: Option<&str>| SlackMessage {
+            subtype: subtype.map(str::to_string),
+            user: Some("U1".to_string()),
+            username: None,
+            text: "hi".to_string(),
+            ts: "1609459200.000200".to_string(),
+            thread_ts: thread_ts.map(str::to_string),
+            reactions: vec![SlackReaction { count: 2 }],
+            files: Vec::new(),
+        };
+        let settings = MessageSettings::default();
+        let mut authors = HashMap::new();
+        assert!(convert(message(Some("channel_join"), None), &names, &mut authors, &settings, &mut stats).is_none());
+        let reply = convert(message(None, Some("1609459200.000100")), &names, &mut authors, &settings, &mut stats).unwrap();
+        assert_eq!(reply.sender, "U1");
+        assert_eq!(authors["U1"], "U1");
+        assert_eq!((stats.messages, stats.events_skipped, stats.replies, stats.reactions_skipped), (2, 1, 1, 2));
+    }
+}
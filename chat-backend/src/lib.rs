@@ -0,0 +1,12 @@
+// Shared by the server and the chat-admin tool
+pub mod models;
+pub mod handlers;
+pub mod utils;
+pub mod db;
+pub mod storage;
+pub mod error;
+pub mod settings;
+pub mod rate_limit;
+pub mod filters;
+pub mod importer;
+pub mod retention;
@@ -1,17 +1,16 @@
-mod models;
-mod handlers;
-mod utils;
-mod db;
-
-use actix_web::{web, App, HttpServer, HttpResponse, middleware::Logger};
+use actix_web::{web, App, HttpServer, HttpResponse, middleware::{from_fn, Logger}};
+use chat_backend::{db, error, filters, importer, rate_limit, retention, settings, storage};
+use chat_backend::error::AppError;
 use std::sync::{Arc, Mutex};
-use crate::handlers::http::chat_route;
-use crate::models::session::ChatSession;
-use crate::handlers::api::{create_user, get_rooms, create_room, join_room, get_room_messages};
-
+use std::time::Duration;
+use chat_backend::handlers::http::chat_route;
+use chat_backend::models::presence::{spawn_idle_sweeper, PresenceTracker};
+use chat_backend::models::session::Connections;
+use chat_backend::models::shutdown::{spawn_signal_handler, Shutdown};
+use chat_backend::handlers::{self, auth::logged_request_line};
 use actix_cors::Cors;
+use clap::Parser;
 use dotenv::dotenv;
-use std::env;
 use actix_web::http::header;
 
 async fn health_check() -> HttpResponse {
@@ -23,56 +22,123 @@ async fn health_check() -> HttpResponse {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // Load environment variables
+    // Load environment variables, then the settings layered on top of them
     dotenv().ok();
-    std::env::set_var("RUST_LOG", "debug");
-    env_logger::init();
+    let cli = settings::Cli::parse();
+    let settings = match settings::load(&cli) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(std::io::Error::other("invalid configuration"));
+        }
+    };
 
-    // Get configuration from environment
-    let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
-    let frontend_url = env::var("FRONTEND_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    if cli.print_config {
+        match settings.redacted().to_toml() {
+            Ok(toml) => print!("{}", toml),
+            Err(e) => eprintln!("Failed to render configuration: {}", e),
+        }
+    }
+    if let Err(e) = settings.validate() {
+        eprintln!("{}", e);
+        return Err(std::io::Error::other("invalid configuration"));
+    }
+    if cli.print_config {
+        return Ok(());
+    }
+
+    env_logger::Builder::new().parse_filters(&settings.log.level).init();
 
     println!("Creating database pool...");
-    let pool = match db::create_pool().await {
+    let pool = match db::create_pool(&settings.database) {
         Ok(pool) => {
             println!("Database pool created successfully");
             pool
         },
         Err(e) => {
             eprintln!("Failed to create database pool: {}", e);
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+            return Err(std::io::Error::other(e));
         }
     };
 
-    // Get a client from the pool and create tables if needed
+    // Wait for the database to come up, then create tables if needed
     println!("Initializing database schema...");
-    match pool.get().await {
+    let startup_timeout = Duration::from_secs(settings.database.startup_timeout_secs);
+    match db::wait_for_database(&pool, startup_timeout).await {
         Ok(client) => {
             match db::schema::create_tables(&client).await {
                 Ok(_) => println!("Database tables created or verified successfully"),
                 Err(e) => {
                     eprintln!("Failed to create database tables: {}", e);
-                    return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+                    return Err(std::io::Error::other(e));
+                }
+            }
+            // Server admins listed by id in the config; ids without a user are skipped
+            let admins = &settings.admin.user_ids;
+            if !admins.is_empty() {
+                match db::models::User::grant_admin(&client, admins).await {
+                    Ok(granted) => {
+                        for missing in admins.iter().filter(|id| !granted.contains(id)) {
+                            log::warn!("admin.user_ids: there is no user with id {}", missing);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to grant server admin rights: {}", e);
+                        return Err(std::io::Error::other(e));
+                    }
                 }
             }
         },
         Err(e) => {
             eprintln!("Failed to get database client: {}", e);
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+            return Err(std::io::Error::other(e));
         }
     };
 
+    if let Some(settings::Command::Import(args)) = &cli.command {
+        return importer::run_cli(&pool, &settings.messages, args).await;
+    }
+
     // Create shared state for WebSocket connections
-    let connections: Arc<Mutex<Vec<(String, i32, actix::Addr<ChatSession>)>>> = 
-        Arc::new(Mutex::new(Vec::new()));
+    let connections: Connections = Arc::new(Mutex::new(Vec::new()));
+
+    // Presence of registered users across all of their sessions
+    let presence = PresenceTracker::default();
+    spawn_idle_sweeper(presence.clone(), connections.clone());
 
-    println!("Starting server at http://{}:{}", host, port);
+    // Request and message rate limits
+    let limiter = web::Data::new(rate_limit::RateLimiter::new(settings.rate_limit.clone()));
+    rate_limit::spawn_sweeper(limiter.clone());
 
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allowed_origin(&frontend_url)
-            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
+    // Coordinates draining sessions when the process is asked to stop
+    let shutdown = Shutdown::default();
+    let shutdown_timeout = Duration::from_secs(settings.server.shutdown_timeout_secs);
+
+    // Attachment storage backend and upload limits
+    let storage = storage::from_settings(&settings.storage)?;
+    let upload_config = storage::UploadConfig::from(&settings.uploads);
+    let message_settings = settings.messages;
+    let account_settings = settings.accounts;
+    let import_max_bytes = settings.imports.max_bytes as usize;
+    let filters = filters::MessageFilters::new(&settings.filters);
+    filters::spawn_reload_handler(cli.clone(), filters.clone());
+
+    // Expires messages past their room's retention period
+    let retention_settings = settings.retention;
+    let retention_metrics = retention::RetentionMetrics::default();
+    retention::spawn_pruner(pool.clone(), storage.clone(), retention_settings, retention_metrics.clone());
+
+    let tls_config = settings.server.tls.server_config()?;
+    let address = (settings.server.host.clone(), settings.server.port);
+    let cors_origins = settings.server.cors_origins.clone();
+    let server_shutdown = shutdown.clone();
+    let draining_connections = connections.clone();
+
+    let server = HttpServer::new(move || {
+        let cors = cors_origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+            .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
             .allowed_headers(vec![
                 header::AUTHORIZATION,
                 header::ACCEPT,
@@ -81,22 +147,49 @@ async fn main() -> std::io::Result<()> {
             .supports_credentials();
 
         App::new()
+            .wrap(from_fn(error::request_id))
             .wrap(cors)
-            .wrap(Logger::default())
+            .wrap(
+                Logger::new("%a \"%{request_line}xi\" %s %b %{x-request-id}o %T")
+                    .custom_request_replace("request_line", logged_request_line),
+            )
+            // Malformed paths and query strings get the same error envelope as everything else
+            .app_data(web::PathConfig::default().error_handler(|e, _| AppError::BadRequest(e.to_string()).into()))
+            .app_data(web::QueryConfig::default().error_handler(|e, _| AppError::BadRequest(e.to_string()).into()))
             .app_data(web::Data::new(connections.clone()))
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(presence.clone()))
+            .app_data(web::Data::new(server_shutdown.clone()))
+            .app_data(limiter.clone())
+            .app_data(web::Data::from(storage.clone()))
+            .app_data(web::Data::new(upload_config.clone()))
+            .app_data(web::Data::new(message_settings))
+            .app_data(web::Data::new(account_settings))
+            .app_data(web::Data::new(filters.clone()))
+            .app_data(web::Data::new(retention_settings))
+            .app_data(web::Data::new(retention_metrics.clone()))
             .route("/ws", web::get().to(chat_route))
             .route("/health", web::get().to(health_check))
             .service(
                 web::scope("/api")
-                    .route("/users", web::post().to(create_user))
-                    .route("/rooms", web::get().to(get_rooms))
-                    .route("/rooms/join", web::post().to(join_room))
-                    .route("/rooms/create/{user_id}", web::post().to(create_room))
-                    .route("/rooms/{room_id}/messages", web::get().to(get_room_messages))
+                    .wrap(from_fn(rate_limit::limit_requests))
+                    .configure(handlers::api_routes(import_max_bytes)),
             )
     })
-    .bind(format!("{}:{}", host, port))?
-    .run()
-    .await
-}
\ No newline at end of file
+    // Signals are handled by spawn_signal_handler so sessions can be drained first
+    .disable_signals()
+    .shutdown_timeout(settings.server.shutdown_timeout_secs);
+
+    let server = match tls_config {
+        Some(tls_config) => {
+            println!("Starting server at https://{}:{}", address.0, address.1);
+            server.bind_rustls_0_23(address, tls_config)?.run()
+        }
+        None => {
+            println!("Starting server at http://{}:{}", address.0, address.1);
+            server.bind(address)?.run()
+        }
+    };
+    spawn_signal_handler(server.handle(), shutdown, draining_connections, shutdown_timeout);
+    server.await
+}
@@ -0,0 +1,96 @@
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use serde::Serialize;
+use std::future::{ready, Ready};
+use tokio_postgres::Client;
+use crate::db::models::AuditEntry;
+use crate::error::current_request_id;
+use crate::rate_limit::RateLimiter;
+
+// Where a request came from, for the audit log
+pub struct RequestOrigin {
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl FromRequest for RequestOrigin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // The rate limiter knows whether X-Forwarded-For can be trusted
+        let ip = match req.app_data::<web::Data<RateLimiter>>() {
+            Some(limiter) => Some(limiter.client_ip(req)),
+            None => req.peer_addr().map(|addr| addr.ip().to_string()),
+        };
+        ready(Ok(RequestOrigin {
+            ip: ip.filter(|ip| !ip.is_empty()),
+            request_id: current_request_id(),
+        }))
+    }
+}
+
+// One privileged change: what was done to which object, and its state before and after
+pub struct Change<'a> {
+    pub action: &'a str,
+    pub target_type: &'a str,
+    pub target_id: Option<String>,
+    pub room_id: Option<i32>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+impl<'a> Change<'a> {
+    pub fn new(action: &'a str, target_type: &'a str, target_id: impl ToString) -> Change<'a> {
+        Change {
+            action,
+            target_type,
+            target_id: Some(target_id.to_string()),
+            room_id: None,
+            before: None,
+            after: None,
+        }
+    }
+
+    pub fn room(mut self, room_id: i32) -> Self {
+        self.room_id = Some(room_id);
+        self
+    }
+
+    pub fn before(mut self, state: &impl Serialize) -> Self {
+        self.before = serde_json::to_value(state).ok();
+        self
+    }
+
+    pub fn after(mut self, state: &impl Serialize) -> Self {
+        self.after = serde_json::to_value(state).ok();
+        self
+    }
+}
+
+// Appends the change to the audit log. The change itself has already happened, so a
+// failure to record it is logged rather than failing the request. Changes made with
+// chat-admin have no actor.
+pub async fn record(client: &Client, origin: &RequestOrigin, actor_id: impl Into<Option<i32>>, change: Change<'_>) {
+    let entry = AuditEntry {
+        actor_id: actor_id.into(),
+        action: change.action.to_string(),
+        target_type: change.target_type.to_string(),
+        target_id: change.target_id,
+        room_id: change.room_id,
+        before: change.before,
+        after: change.after,
+        ip: origin.ip.clone(),
+        request_id: origin.request_id.clone(),
+    };
+    if let Err(e) = AuditEntry::create(client, &entry).await {
+        log::error!(
+            "[{}] Failed to record {} of {} {:?} in the audit log: {}",
+            origin.request_id.as_deref().unwrap_or("-"),
+            entry.action,
+            entry.target_type,
+            entry.target_id,
+            e
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use crate::error::AppError;
+use crate::handlers::validation::FieldError;
+use crate::settings::MessageSettings;
+
+// Upper bound for `messages.max_length`, matching the check constraint on messages.content
+pub const MAX_STORED_LENGTH: usize = 65_536;
+
+// Combining marks kept on one base character; enough for real scripts, not for "zalgo" text
+const MAX_COMBINING_MARKS: usize = 4;
+
+#[derive(Debug, PartialEq)]
+pub enum ContentError {
+    Empty,
+    // Length after normalization, in characters
+    TooLong { length: usize, max: usize },
+}
+
+impl fmt::Display for ContentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContentError::Empty => write!(f, "Message cannot be empty"),
+            ContentError::TooLong { length, max } => {
+                write!(f, "Message is too long ({} characters, at most {} allowed)", length, max)
+            }
+        }
+    }
+}
+
+impl From<ContentError> for AppError {
+    fn from(error: ContentError) -> Self {
+        let code = match error {
+            ContentError::Empty => "blank",
+            ContentError::TooLong { .. } => "length",
+        };
+        AppError::Validation(vec![FieldError {
+            field: "content".to_string(),
+            code: code.to_string(),
+            message: error.to_string(),
+        }])
+    }
+}
+
+// Normalizes a chat message and checks it against the configured limits. Messages that
+// carry attachments may have no text.
+pub fn prepare_message(text: &str, has_attachments: bool, settings: &MessageSettings) -> Result<String, ContentError> {
+    let content = normalize(text);
+    if content.is_empty() && !has_attachments {
+        return Err(ContentError::Empty);
+    }
+    let length = content.chars().count();
+    if length > settings.max_length {
+        return Err(ContentError::TooLong { length, max: settings.max_length });
+    }
+    Ok(content)
+}
+
+// NFC-normalizes the text, converts line breaks to \n, strips control and invisible formatting
+// characters, keeps zero-width joiners only between two visible characters, caps stacked
+// combining marks and trims surrounding whitespace
+pub fn normalize(text: &str) -> String {
+    let text: String = text.nfc().collect();
+    let mut normalized = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut previous: Option<char> = None;
+    let mut marks = 0;
+
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\r' if chars.peek() == Some(&'\n') => continue,
+            '\r' => '\n',
+            c => c,
+        };
+        if is_stripped(c) {
+            continue;
+        }
+        if is_joiner(c) {
+            let after_visible = previous.is_some_and(is_visible);
+            let before_visible = chars.peek().is_some_and(|&next| is_visible(next));
+            if !after_visible || !before_visible {
+                continue;
+            }
+        }
+        if is_combining_mark(c) {
+            marks += 1;
+            if marks > MAX_COMBINING_MARKS {
+                continue;
+            }
+        } else {
+            marks = 0;
+        }
+        normalized.push(c);
+        previous = Some(c);
+    }
+
+    normalized.trim().to_string()
+}
+
+// Zero-width joiner and non-joiner, which emoji sequences and some scripts need
+fn is_joiner(c: char) -> bool {
+    matches!(c, '\u{200C}' | '\u{200D}')
+}
+
+fn is_visible(c: char) -> bool {
+    !c.is_whitespace() && !is_joiner(c) && !is_stripped(c)
+}
+
+// Control characters other than newline and tab, plus invisible characters that are used to
+// pad "empty" messages, dodge filters or reorder displayed text
+fn is_stripped(c: char) -> bool {
+    (c.is_control() && c != '\n' && c != '\t')
+        || matches!(
+            c,
+            '\u{00AD}'                      // soft hyphen
+            | '\u{180E}'                    // Mongolian vowel separator
+            | '\u{200B}'                    // zero-width space
+            | '\u{200E}' | '\u{200F}'       // directional marks
+            | '\u{202A}'..='\u{202E}'       // directional embeddings and overrides
+            | '\u{2060}'..='\u{2064}'       // word joiner and invisible operators
+            | '\u{2066}'..='\u{2069}'       // directional isolates
+            | '\u{3164}' | '\u{FFA0}'       // Hangul fillers
+            | '\u{FEFF}'                    // byte order mark
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_cleans_up_text() {
+        // Decomposed "é" is composed, CRLF becomes LF, controls and bidi overrides go
+        assert_eq!(normalize("  cafe\u{301}\r\nok\u{7}\u{202E}!  "), "café\nok!");
+        assert_eq!(normalize("\u{200B}\u{FEFF}\u{3164}"), "");
+        // Joiners survive inside emoji sequences, not around whitespace or doubled up
+        assert_eq!(normalize("👩\u{200D}💻"), "👩\u{200D}💻");
+        assert_eq!(normalize("a\u{200D}\u{200D}b \u{200D}c\u{200C}"), "a\u{200D}b c");
+        assert_eq!(normalize("e\u{301}\u{302}\u{303}\u{304}\u{305}\u{306}").chars().count(), 5);
+    }
+
+    #[test]
+    fn prepare_message_enforces_limits() {
+        let settings = MessageSettings { max_length: 5, ..MessageSettings::default() };
+        assert_eq!(prepare_message(" hello ", false, &settings), Ok("hello".to_string()));
+        assert_eq!(prepare_message("\u{200B} ", false, &settings), Err(ContentError::Empty));
+        assert_eq!(prepare_message("", true, &settings), Ok(String::new()));
+        assert_eq!(
+            prepare_message("hello!", false, &settings),
+            Err(ContentError::TooLong { length: 6, max: 5 })
+        );
+    }
+}
@@ -1,2 +1,7 @@
+pub mod audit;
+pub mod content;
 pub mod message;
-pub mod session;
\ No newline at end of file
+pub mod permissions;
+pub mod presence;
+pub mod session;
+pub mod shutdown;
@@ -0,0 +1,182 @@
+use tokio_postgres::Client;
+use serde::{Deserialize, Serialize};
+use crate::db::models::{Room, RoomMember};
+
+// Room roles, ordered from least to most privileged
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    ReadOnly,
+    Member,
+    Moderator,
+    Admin,
+    Owner,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Post,
+    EditOthers,
+    DeleteOthers,
+    Kick,
+    Ban,
+    Mute,
+    Invite,
+    ChangeSettings,
+    ManageRoles,
+    ReviewReports,
+    ViewAuditLog,
+    DeleteRoom,
+}
+
+impl Role {
+    pub const ALL: [Role; 5] = [Role::ReadOnly, Role::Member, Role::Moderator, Role::Admin, Role::Owner];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::ReadOnly => "read_only",
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Role> {
+        Role::ALL.into_iter().find(|role| role.as_str() == value)
+    }
+
+    pub fn permissions(self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::ReadOnly => &[],
+            Role::Member => &[Post, Invite],
+            Role::Moderator => &[Post, Invite, EditOthers, DeleteOthers, Kick, Ban, Mute],
+            Role::Admin => &[
+                Post, Invite, EditOthers, DeleteOthers, Kick, Ban, Mute, ChangeSettings, ManageRoles, ReviewReports,
+                ViewAuditLog,
+            ],
+            Role::Owner => &[
+                Post, Invite, EditOthers, DeleteOthers, Kick, Ban, Mute, ChangeSettings, ManageRoles, ReviewReports,
+                ViewAuditLog, DeleteRoom,
+            ],
+        }
+    }
+
+    pub fn can(self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+
+    // Every role that grants the permission
+    pub fn with(permission: Permission) -> Vec<Role> {
+        Role::ALL.into_iter().filter(|role| role.can(permission)).collect()
+    }
+
+    // Whether a member with this role may act on (kick, ban, mute, re-role) a member with `other`.
+    // Owners may act on anyone; everyone else only on lower roles.
+    pub fn can_manage(self, other: Role) -> bool {
+        self == Role::Owner || self > other
+    }
+}
+
+impl Permission {
+    // Permissions that add or change content, which an archived room no longer allows
+    pub fn writes_content(self) -> bool {
+        matches!(self, Permission::Post | Permission::EditOthers | Permission::DeleteOthers | Permission::Invite)
+    }
+}
+
+impl RoomMember {
+    // Unknown role strings get the least privileged role
+    pub fn role(&self) -> Role {
+        Role::parse(&self.role).unwrap_or(Role::ReadOnly)
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthzError {
+    RoomNotFound,
+    NotMember,
+    Archived,
+    Forbidden(Permission),
+    Database(tokio_postgres::Error),
+}
+
+impl AuthzError {
+    pub fn message(&self) -> String {
+        match self {
+            AuthzError::RoomNotFound => "Room not found".to_string(),
+            AuthzError::NotMember => "You are not a member of this room".to_string(),
+            AuthzError::Archived => "This room is archived and read-only".to_string(),
+            AuthzError::Forbidden(permission) => format!(
+                "Your role in this room does not allow: {}",
+                serde_json::to_value(permission).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default()
+            ),
+            AuthzError::Database(_) => "Internal server error".to_string(),
+        }
+    }
+}
+
+// The single check for what a user may do in a room, shared by the REST
+// handlers and the websocket session. Returns the acting membership on success.
+pub async fn authorize(
+    client: &Client,
+    room_id: i32,
+    user_id: i32,
+    permission: Permission,
+) -> Result<RoomMember, AuthzError> {
+    let room = Room::find_by_id(client, room_id)
+        .await
+        .map_err(AuthzError::Database)?
+        .ok_or(AuthzError::RoomNotFound)?;
+    let member = Room::member(client, room_id, user_id)
+        .await
+        .map_err(AuthzError::Database)?
+        .ok_or(AuthzError::NotMember)?;
+
+    if !member.role().can(permission) {
+        Err(AuthzError::Forbidden(permission))
+    } else if room.archived_at.is_some() && permission.writes_content() {
+        Err(AuthzError::Archived)
+    } else {
+        Ok(member)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_grant_the_expected_permissions() {
+        use Permission::*;
+        use Role::*;
+        let matrix = [
+            (Post, vec![Member, Moderator, Admin, Owner]),
+            (Invite, vec![Member, Moderator, Admin, Owner]),
+            (EditOthers, vec![Moderator, Admin, Owner]),
+            (DeleteOthers, vec![Moderator, Admin, Owner]),
+            (Kick, vec![Moderator, Admin, Owner]),
+            (Ban, vec![Moderator, Admin, Owner]),
+            (Mute, vec![Moderator, Admin, Owner]),
+            (ChangeSettings, vec![Admin, Owner]),
+            (ManageRoles, vec![Admin, Owner]),
+            (ReviewReports, vec![Admin, Owner]),
+            (ViewAuditLog, vec![Admin, Owner]),
+            (DeleteRoom, vec![Owner]),
+        ];
+        for (permission, roles) in matrix {
+            assert_eq!(Role::with(permission), roles, "{:?}", permission);
+        }
+        assert!(ReadOnly.permissions().is_empty());
+    }
+
+    #[test]
+    fn only_owners_manage_their_equals() {
+        assert!(Role::Owner.can_manage(Role::Owner));
+        assert!(!Role::Admin.can_manage(Role::Admin));
+        assert!(Role::Moderator.can_manage(Role::Member));
+        assert!(!Role::Moderator.can_manage(Role::Admin));
+    }
+}
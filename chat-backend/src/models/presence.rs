@@ -0,0 +1,231 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use crate::models::session::{broadcast_to_room, Connections, WsMessage};
+
+// Users with no activity for this long are shown as idle
+pub const IDLE_AFTER_SECS: i64 = 5 * 60;
+// How often idle users are looked for
+pub const IDLE_SWEEP_SECS: u64 = 30;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    Idle,
+    Away,
+    #[serde(alias = "dnd")]
+    DoNotDisturb,
+    Offline,
+}
+
+impl PresenceStatus {
+    // States a user may pick with a `set_presence` frame
+    pub fn parse_selectable(value: &str) -> Option<PresenceStatus> {
+        match value {
+            "online" => Some(PresenceStatus::Online),
+            "away" => Some(PresenceStatus::Away),
+            "dnd" | "do_not_disturb" => Some(PresenceStatus::DoNotDisturb),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PresenceInfo {
+    pub user_id: i32,
+    pub username: String,
+    pub status: PresenceStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+struct UserPresence {
+    username: String,
+    // Number of open sockets across all rooms
+    sessions: usize,
+    // Online, Away or DoNotDisturb as last chosen by the user
+    chosen: PresenceStatus,
+    idle: bool,
+    last_active: DateTime<Utc>,
+    last_seen: Option<DateTime<Utc>>,
+    // Rooms that should hear about this user's presence changes
+    rooms: HashSet<i32>,
+}
+
+impl UserPresence {
+    fn status(&self) -> PresenceStatus {
+        if self.sessions == 0 {
+            PresenceStatus::Offline
+        } else if self.chosen != PresenceStatus::Online {
+            self.chosen
+        } else if self.idle {
+            PresenceStatus::Idle
+        } else {
+            PresenceStatus::Online
+        }
+    }
+}
+
+// Tracks each registered user's presence across all of their sessions
+#[derive(Clone, Default)]
+pub struct PresenceTracker {
+    users: Arc<Mutex<HashMap<i32, UserPresence>>>,
+}
+
+impl PresenceTracker {
+    // Applies a change to a user's entry and reports the new presence if the visible status changed
+    fn update<F>(&self, user_id: i32, change: F) -> Option<PresenceInfo>
+    where
+        F: FnOnce(&mut UserPresence),
+    {
+        let mut users = self.users.lock().ok()?;
+        let presence = users.get_mut(&user_id)?;
+        let before = presence.status();
+        change(presence);
+        let after = presence.status();
+
+        (before != after).then(|| PresenceInfo {
+            user_id,
+            username: presence.username.clone(),
+            status: after,
+            last_seen: presence.last_seen,
+        })
+    }
+
+    pub fn connect(&self, user_id: i32, username: &str, rooms: &[i32]) -> Option<PresenceInfo> {
+        if let Ok(mut users) = self.users.lock() {
+            users.entry(user_id).or_insert_with(|| UserPresence {
+                username: username.to_string(),
+                sessions: 0,
+                chosen: PresenceStatus::Online,
+                idle: false,
+                last_active: Utc::now(),
+                last_seen: None,
+                rooms: HashSet::new(),
+            });
+        }
+
+        self.update(user_id, |presence| {
+            presence.sessions += 1;
+            presence.idle = false;
+            presence.last_active = Utc::now();
+            presence.rooms.extend(rooms.iter().copied());
+        })
+    }
+
+    pub fn disconnect(&self, user_id: i32) -> Option<PresenceInfo> {
+        self.update(user_id, |presence| {
+            presence.sessions = presence.sessions.saturating_sub(1);
+            if presence.sessions == 0 {
+                presence.last_seen = Some(Utc::now());
+                // A chosen away/dnd state does not outlive the last session
+                presence.chosen = PresenceStatus::Online;
+            }
+        })
+    }
+
+    // Records activity, bringing an idle user back online
+    pub fn touch(&self, user_id: i32) -> Option<PresenceInfo> {
+        self.update(user_id, |presence| {
+            presence.idle = false;
+            presence.last_active = Utc::now();
+        })
+    }
+
+    pub fn set_status(&self, user_id: i32, status: PresenceStatus) -> Option<PresenceInfo> {
+        self.update(user_id, |presence| {
+            presence.chosen = status;
+            presence.idle = false;
+            presence.last_active = Utc::now();
+        })
+    }
+
+    pub fn add_room(&self, user_id: i32, room_id: i32) {
+        if let Ok(mut users) = self.users.lock() {
+            if let Some(presence) = users.get_mut(&user_id) {
+                presence.rooms.insert(room_id);
+            }
+        }
+    }
+
+    pub fn remove_room(&self, user_id: i32, room_id: i32) {
+        if let Ok(mut users) = self.users.lock() {
+            if let Some(presence) = users.get_mut(&user_id) {
+                presence.rooms.remove(&room_id);
+            }
+        }
+    }
+
+    // Marks users without recent activity as idle and returns the resulting changes
+    pub fn sweep_idle(&self, idle_after: Duration) -> Vec<PresenceInfo> {
+        let cutoff = Utc::now() - idle_after;
+        let idle_users: Vec<i32> = match self.users.lock() {
+            Ok(users) => users
+                .iter()
+                .filter(|(_, p)| p.sessions > 0 && !p.idle && p.last_active < cutoff)
+                .map(|(user_id, _)| *user_id)
+                .collect(),
+            Err(_) => return Vec::new(),
+        };
+
+        idle_users
+            .into_iter()
+            .filter_map(|user_id| self.update(user_id, |presence| presence.idle = true))
+            .collect()
+    }
+
+    // Current presence, or None if the user has not connected since startup
+    pub fn get(&self, user_id: i32) -> Option<PresenceInfo> {
+        let users = self.users.lock().ok()?;
+        users.get(&user_id).map(|presence| PresenceInfo {
+            user_id,
+            username: presence.username.clone(),
+            status: presence.status(),
+            last_seen: presence.last_seen,
+        })
+    }
+
+    // Number of members of the room with at least one live session
+    pub fn online_in_room(&self, room_id: i32) -> usize {
+        match self.users.lock() {
+            Ok(users) => users
+                .values()
+                .filter(|p| p.sessions > 0 && p.rooms.contains(&room_id))
+                .count(),
+            Err(_) => 0,
+        }
+    }
+
+    pub fn rooms_of(&self, user_id: i32) -> Vec<i32> {
+        match self.users.lock() {
+            Ok(users) => users
+                .get(&user_id)
+                .map(|presence| presence.rooms.iter().copied().collect())
+                .unwrap_or_default(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+// Delivers a presence change to every room the user belongs to
+pub fn broadcast_presence(connections: &Connections, presence: &PresenceTracker, info: &PresenceInfo) {
+    let text = serde_json::to_string(info).unwrap_or_default();
+    for room_id in presence.rooms_of(info.user_id) {
+        broadcast_to_room(connections, room_id, &WsMessage::new("presence", &info.username, text.clone(), room_id));
+    }
+}
+
+// Periodically moves inactive users to idle
+pub fn spawn_idle_sweeper(presence: PresenceTracker, connections: Connections) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(IDLE_SWEEP_SECS));
+        loop {
+            interval.tick().await;
+            for info in presence.sweep_idle(Duration::seconds(IDLE_AFTER_SECS)) {
+                broadcast_presence(&connections, &presence, &info);
+            }
+        }
+    });
+}
@@ -1,8 +1,42 @@
-use actix::{Actor, StreamHandler, Message, Handler, Running, ActorContext, AsyncContext};
+use actix::{Actor, StreamHandler, Message, Handler, Running, ActorContext, AsyncContext, ActorFutureExt, WrapFuture};
 use actix_web_actors::ws;
+use deadpool_postgres::{Client, Pool};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use crate::utils::avatar::generate_avatar_url;
+use crate::error::AppError;
+use crate::db::models::{self, Attachment, Report, Sanction, SanctionKind, User};
+use crate::filters::MessageFilters;
+use crate::models::content::prepare_message;
+use crate::models::permissions::{authorize, Permission};
+use crate::models::presence::{broadcast_presence, PresenceInfo, PresenceStatus, PresenceTracker};
+use crate::models::shutdown::{ServerShutdown, Shutdown};
+use crate::rate_limit::{SessionLimits, SessionVerdict};
+use crate::settings::MessageSettings;
+
+// Shared list of live sessions
+pub type Connections = Arc<Mutex<Vec<LiveSession>>>;
+
+// A session in the connection registry
+#[derive(Serialize, Clone)]
+pub struct LiveSession {
+    pub id: u64,
+    pub username: String,
+    pub user_id: Option<i32>,
+    pub room_id: i32,
+    pub ip: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub addr: actix::Addr<ChatSession>,
+}
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+// Session ids are unique for the life of the process
+pub fn next_session_id() -> u64 {
+    NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed)
+}
 
 // Message types
 #[derive(Message, Serialize, Deserialize, Clone)]
@@ -14,13 +48,56 @@ pub struct WsMessage {
     pub timestamp: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub room_id: Option<i32>,
+    // Id of the stored message, set by the server once a chat message is persisted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<i32>,
+    // Previously uploaded attachments to link to a chat message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment_ids: Option<Vec<i32>>,
+    // Set by the server on frames that ask the client to back off, e.g. `server_shutdown`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+impl WsMessage {
+    pub fn new(message_type: &str, user: &str, text: String, room_id: i32) -> WsMessage {
+        WsMessage {
+            message_type: message_type.to_string(),
+            user: user.to_string(),
+            text,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            room_id: Some(room_id),
+            message_id: None,
+            attachment_ids: None,
+            retry_after_ms: None,
+        }
+    }
+}
+
+// Asks a session to close, telling the client why first
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Disconnect {
+    pub reason: String,
 }
 
 pub struct ChatSession {
-    pub id: u32,
+    pub id: u64,
     pub username: String,
+    // Known for registered users; anonymous sessions may only read
+    pub user_id: Option<i32>,
     pub room_id: i32,
-    pub addr: Arc<Mutex<Vec<(String, i32, actix::Addr<ChatSession>)>>>,
+    pub ip: Option<String>,
+    // Rooms the user is a member of, used to fan out presence changes
+    pub member_rooms: Vec<i32>,
+    pub addr: Connections,
+    pub pool: Pool,
+    pub presence: PresenceTracker,
+    pub shutdown: Shutdown,
+    // None for sessions exempt from rate limiting
+    pub limits: Option<SessionLimits>,
+    pub messages: MessageSettings,
+    pub filters: MessageFilters,
 }
 
 impl Actor for ChatSession {
@@ -28,38 +105,70 @@ impl Actor for ChatSession {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         // Add self to the shared connections list
-        let addr = ctx.address();
-        self.addr.lock().unwrap().push((self.username.clone(), self.room_id, addr));
+        self.addr.lock().unwrap().push(LiveSession {
+            id: self.id,
+            username: self.username.clone(),
+            user_id: self.user_id,
+            room_id: self.room_id,
+            ip: self.ip.clone(),
+            connected_at: Utc::now(),
+            addr: ctx.address(),
+        });
         
         // Broadcast user join notification
-        let join_msg = WsMessage {
-            message_type: "join".to_string(),
-            user: self.username.clone(),
-            text: format!("{} has joined the chat", self.username),
-            timestamp: chrono::Utc::now().to_rfc3339(),
-            room_id: Some(self.room_id),
-        };
+        let join_msg = WsMessage::new(
+            "join",
+            &self.username,
+            format!("{} has joined the chat", self.username),
+            self.room_id,
+        );
         
         // Broadcast user list to all connected clients in the same room
         self.broadcast_user_list();
         
         // Broadcast the join message to the room
         self.broadcast_message(&join_msg);
+
+        if let Some(user_id) = self.user_id {
+            let mut rooms = self.member_rooms.clone();
+            rooms.push(self.room_id);
+            if let Some(info) = self.presence.connect(user_id, &self.username, &rooms) {
+                self.broadcast_presence(&info);
+            }
+        }
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         // Remove self from connections
         let mut connections = self.addr.lock().unwrap();
-        connections.retain(|(username, room_id, _)| !(username == &self.username && room_id == &self.room_id));
+        connections.retain(|session| session.id != self.id);
+        drop(connections);
+
+        if let Some(user_id) = self.user_id {
+            if let Some(info) = self.presence.disconnect(user_id) {
+                self.broadcast_presence(&info);
+                if let Some(last_seen) = info.last_seen {
+                    let pool = self.pool.clone();
+                    let pending = self.shutdown.track_write();
+                    actix::spawn(async move {
+                        let _pending = pending;
+                        if let Ok(client) = pool.get().await {
+                            if let Err(e) = User::update_last_seen(&client, user_id, last_seen).await {
+                                eprintln!("Failed to record last seen time: {}", e);
+                            }
+                        }
+                    });
+                }
+            }
+        }
         
         // Broadcast leave message
-        let leave_msg = WsMessage {
-            message_type: "leave".to_string(),
-            user: self.username.clone(),
-            text: format!("{} has left the chat", self.username),
-            timestamp: chrono::Utc::now().to_rfc3339(),
-            room_id: Some(self.room_id),
-        };
+        let leave_msg = WsMessage::new(
+            "leave",
+            &self.username,
+            format!("{} has left the chat", self.username),
+            self.room_id,
+        );
         
         self.broadcast_message(&leave_msg);
         
@@ -80,28 +189,49 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSession {
             Ok(ws::Message::Text(text)) => {
                 // Parse the incoming message
                 if let Ok(mut ws_message) = serde_json::from_str::<WsMessage>(&text) {
-                    // Ensure room_id is set
-                    if ws_message.room_id.is_none() {
-                        ws_message.room_id = Some(self.room_id);
+                    // A session only ever speaks for its own user in its own room
+                    ws_message.room_id = Some(self.room_id);
+                    ws_message.user = self.username.clone();
+                    ws_message.message_id = None;
+                    ws_message.retry_after_ms = None;
+
+                    if !self.within_limits(&ws_message.message_type, ctx) {
+                        return;
+                    }
+
+                    // Any frame counts as activity for presence
+                    if let Some(user_id) = self.user_id {
+                        if let Some(info) = self.presence.touch(user_id) {
+                            self.broadcast_presence(&info);
+                        }
                     }
                     
                     match ws_message.message_type.as_str() {
                         "chat" => {
-                            // Add avatar to the message
-                            let avatar = generate_avatar_url(&ws_message.user);
-                            
-                            // Store message in database (would be implemented in a real app)
-                            // For a complete implementation, we would:
-                            // 1. Get the user_id from the database based on username
-                            // 2. Create a new message in the database
-                            // 3. Return the created message with additional data
-                            
-                            // Broadcast to room
-                            self.broadcast_message(&ws_message);
+                            // Posting goes through the same checks as the REST endpoint, which
+                            // need a registered member
+                            let Some(user_id) = self.user_id else {
+                                self.send_error(ctx, "Sign in to post messages");
+                                return;
+                            };
+                            let has_attachments = ws_message.attachment_ids.as_ref().is_some_and(|ids| !ids.is_empty());
+                            match prepare_message(&ws_message.text, has_attachments, &self.messages) {
+                                Ok(content) => ws_message.text = content,
+                                Err(e) => {
+                                    self.send_error(ctx, &e.to_string());
+                                    return;
+                                }
+                            }
+                            self.store_and_broadcast(ws_message, user_id, ctx);
                         }
                         "typing" | "stop_typing" => {
-                            // Forward typing indicators to the room
-                            self.broadcast_message(&ws_message);
+                            // Forward typing indicators to the room; anonymous sessions can't post
+                            if self.user_id.is_some() {
+                                self.broadcast_message(&ws_message);
+                            }
+                        }
+                        "set_presence" => {
+                            self.set_presence(&ws_message.text, ctx);
                         }
                         _ => {
                             println!("Unknown message type: {}", ws_message.message_type);
@@ -121,6 +251,15 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSession {
                 ctx.close(reason);
                 ctx.stop();
             }
+            Err(ws::ProtocolError::Overflow) => {
+                let reason = format!("Message frame is too large (at most {} bytes)", self.messages.max_frame_bytes);
+                self.send_error(ctx, &reason);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Size,
+                    description: Some(reason),
+                }));
+                ctx.stop();
+            }
             _ => {
                 ctx.stop();
             }
@@ -138,51 +277,308 @@ impl Handler<WsMessage> for ChatSession {
     }
 }
 
+impl Handler<Disconnect> for ChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) {
+        let notice = WsMessage::new("disconnect", "system", msg.reason.clone(), self.room_id);
+        ctx.text(serde_json::to_string(&notice).unwrap());
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<ServerShutdown> for ChatSession {
+    type Result = ();
+
+    // Runs after any write queued with ctx.wait, so accepted messages are stored before the socket closes
+    fn handle(&mut self, msg: ServerShutdown, ctx: &mut Self::Context) {
+        let mut notice = WsMessage::new(
+            "server_shutdown",
+            "system",
+            "The server is restarting; please reconnect shortly".to_string(),
+            self.room_id,
+        );
+        notice.retry_after_ms = Some(msg.retry_after_ms);
+        ctx.text(serde_json::to_string(&notice).unwrap());
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Restart,
+            description: Some("Server restarting".to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
 impl ChatSession {
-    // Helper method to broadcast a message to all clients in the same room
-    fn broadcast_message(&self, message: &WsMessage) {
-        if let Ok(connections) = self.addr.lock() {
-            let room_id = message.room_id.unwrap_or(self.room_id);
-            
-            // Only broadcast to clients in the same room
-            for (_, client_room_id, addr) in connections.iter().filter(|(_, r, _)| r == &room_id) {
-                addr.do_send(WsMessage {
-                    message_type: message.message_type.clone(),
-                    user: message.user.clone(),
-                    text: message.text.clone(),
-                    timestamp: message.timestamp.clone(),
-                    room_id: Some(room_id),
-                });
+    // Persists the chat message (and links its attachments) before broadcasting it. Uses
+    // ctx.wait so messages from one session are stored and delivered in order.
+    fn store_and_broadcast(&self, mut ws_message: WsMessage, user_id: i32, ctx: &mut ws::WebsocketContext<Self>) {
+        let pool = self.pool.clone();
+        let room_id = self.room_id;
+        let content = ws_message.text.clone();
+        let attachment_ids = ws_message.attachment_ids.take().unwrap_or_default();
+        let filters = self.filters.clone();
+        let username = self.username.clone();
+        let pending = self.shutdown.track_write();
+
+        let store = async move {
+            let _pending = pending;
+            let client = pool.get().await?;
+            post_message(&client, &filters, room_id, user_id, &username, content, &attachment_ids).await
+        };
+
+        ctx.wait(store.into_actor(self).map(move |result, act, ctx| {
+            match result {
+                Ok(posted) => {
+                    ws_message.text = posted.message.content;
+                    ws_message.message_id = posted.message.id;
+                    if !posted.attachments.is_empty() {
+                        ws_message.attachment_ids = Some(posted.attachments.iter().filter_map(|a| a.id).collect());
+                    }
+                    match posted.shadow_hidden {
+                        true => ctx.text(serde_json::to_string(&ws_message).unwrap()),
+                        false => act.broadcast_message(&ws_message),
+                    }
+                }
+                // Messages that were not accepted only bounce back to the sender
+                Err(e) => {
+                    e.log();
+                    act.send_error(ctx, &e.client_message());
+                }
+            }
+        }));
+    }
+
+    // Checks the session's rate limits, telling the client when it has to slow down and
+    // closing the session after sustained abuse. Returns whether to process the frame.
+    fn within_limits(&mut self, message_type: &str, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        let Some(limits) = self.limits.as_mut() else {
+            return true;
+        };
+        match limits.check(message_type) {
+            SessionVerdict::Allow => true,
+            SessionVerdict::Drop => false,
+            SessionVerdict::Reject(wait) => {
+                let mut notice = WsMessage::new(
+                    "rate_limited",
+                    "system",
+                    "You are sending messages too quickly; slow down".to_string(),
+                    self.room_id,
+                );
+                notice.retry_after_ms = Some(wait.as_millis() as u64);
+                ctx.text(serde_json::to_string(&notice).unwrap());
+                false
+            }
+            SessionVerdict::Disconnect => {
+                log::warn!("Closing session of {} in room {}: rate limit exceeded", self.username, self.room_id);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("Rate limit exceeded".to_string()),
+                }));
+                ctx.stop();
+                false
             }
         }
     }
+
+    // Applies a user-chosen presence state (online, away or dnd)
+    fn set_presence(&self, status: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let Some(user_id) = self.user_id else {
+            self.send_error(ctx, "Only registered users have presence");
+            return;
+        };
+        match PresenceStatus::parse_selectable(status) {
+            Some(status) => {
+                if let Some(info) = self.presence.set_status(user_id, status) {
+                    self.broadcast_presence(&info);
+                }
+            }
+            None => self.send_error(ctx, "Presence must be one of: online, away, dnd"),
+        }
+    }
+
+    fn broadcast_presence(&self, info: &PresenceInfo) {
+        broadcast_presence(&self.addr, &self.presence, info);
+    }
+
+    // Sends an error frame to this client only
+    fn send_error(&self, ctx: &mut ws::WebsocketContext<Self>, text: &str) {
+        let error = WsMessage::new("error", "system", text.to_string(), self.room_id);
+        ctx.text(serde_json::to_string(&error).unwrap());
+    }
+
+    // Helper method to broadcast a message to all clients in the same room
+    fn broadcast_message(&self, message: &WsMessage) {
+        broadcast_to_room(&self.addr, message.room_id.unwrap_or(self.room_id), message);
+    }
     
     // Helper method to broadcast the list of online users in the same room
     fn broadcast_user_list(&self) {
-        if let Ok(connections) = self.addr.lock() {
-            // Filter users to only those in the same room
-            let users: Vec<String> = connections.iter()
-                .filter(|(_, room_id, _)| room_id == &self.room_id)
-                .map(|(username, _, _)| username.clone())
-                .collect();
-            
-            // Create a user list message
-            let user_list_msg = serde_json::json!({
-                "message_type": "user_list",
-                "users": users,
-                "room_id": self.room_id
-            });
-            
-            // Send to all clients in the same room
-            for (_, client_room_id, addr) in connections.iter().filter(|(_, r, _)| r == &self.room_id) {
-                addr.do_send(WsMessage {
-                    message_type: "user_list".to_string(),
-                    user: "system".to_string(),
-                    text: user_list_msg.to_string(),
-                    timestamp: chrono::Utc::now().to_rfc3339(),
-                    room_id: Some(self.room_id),
-                });
-            }
+        broadcast_user_list(&self.addr, self.room_id);
+    }
+}
+
+// A stored chat message; shadow-hidden ones go back to their sender only
+pub struct PostedMessage {
+    pub message: models::Message,
+    pub attachments: Vec<Attachment>,
+    pub shadow_hidden: bool,
+}
+
+// Checks the sender may post and is not muted, runs the message filters, then stores an
+// already normalized chat message and links its attachments. Used by sessions and the
+// REST endpoint alike.
+pub async fn post_message(
+    client: &Client,
+    filters: &MessageFilters,
+    room_id: i32,
+    user_id: i32,
+    username: &str,
+    content: String,
+    attachment_ids: &[i32],
+) -> Result<PostedMessage, AppError> {
+    authorize(client, room_id, user_id, Permission::Post).await?;
+    if let Some(mute) = Sanction::find_active(client, SanctionKind::Mute, room_id, user_id).await? {
+        return Err(AppError::Forbidden(muted_message(&mute)));
+    }
+
+    let outcome = filters.apply(room_id, username, &content);
+    if let Some(reason) = outcome.rejected {
+        return Err(AppError::Unprocessable(reason));
+    }
+
+    let message = models::Message {
+        id: None,
+        room_id,
+        sender_id: Some(user_id),
+        content: outcome.text,
+        created_at: None,
+    };
+    let stored = models::Message::create(client, &message, outcome.shadow_hidden).await?;
+
+    let Some(message_id) = stored.id else {
+        return Ok(PostedMessage { message: stored, attachments: Vec::new(), shadow_hidden: outcome.shadow_hidden });
+    };
+    // Flags go to the moderation queue as reports without a reporter
+    for (rule, reason) in &outcome.flags {
+        Report::create(client, &stored, None, &format!("Flagged by the {} filter: {}", rule, reason)).await?;
+    }
+    let attachments = match attachment_ids.is_empty() {
+        true => Vec::new(),
+        false => Attachment::link_to_message(client, attachment_ids, message_id, room_id, user_id).await?,
+    };
+    Ok(PostedMessage { message: stored, attachments, shadow_hidden: outcome.shadow_hidden })
+}
+
+// Error text shown to a muted user who tries to post
+pub fn muted_message(mute: &Sanction) -> String {
+    match mute.expires_at {
+        Some(at) => format!("You are muted in this room until {}", at.to_rfc3339()),
+        None => "You are muted in this room".to_string(),
+    }
+}
+
+// Sends a message to every session connected to the given room
+pub fn broadcast_to_room(connections: &Connections, room_id: i32, message: &WsMessage) {
+    if let Ok(connections) = connections.lock() {
+        let mut outgoing = message.clone();
+        outgoing.room_id = Some(room_id);
+        
+        // Only broadcast to clients in the same room
+        for session in connections.iter().filter(|session| session.room_id == room_id) {
+            session.addr.do_send(outgoing.clone());
+        }
+    }
+}
+
+// Sends the list of online users in a room to everyone in that room
+pub fn broadcast_user_list(connections: &Connections, room_id: i32) {
+    if let Ok(connections) = connections.lock() {
+        // Filter users to only those in the same room
+        let users: Vec<String> = connections.iter()
+            .filter(|session| session.room_id == room_id)
+            .map(|session| session.username.clone())
+            .collect();
+        
+        // Create a user list message
+        let user_list_msg = serde_json::json!({
+            "message_type": "user_list",
+            "users": users,
+            "room_id": room_id
+        });
+        
+        // Send to all clients in the same room
+        for session in connections.iter().filter(|session| session.room_id == room_id) {
+            session.addr.do_send(WsMessage::new("user_list", "system", user_list_msg.to_string(), room_id));
+        }
+    }
+}
+
+// Closes every session the user has open in the given room
+pub fn disconnect_from_room(connections: &Connections, user_id: i32, room_id: i32, reason: &str) {
+    if let Ok(connections) = connections.lock() {
+        for session in connections.iter().filter(|session| session.user_id == Some(user_id) && session.room_id == room_id) {
+            session.addr.do_send(Disconnect { reason: reason.to_string() });
+        }
+    }
+}
+
+// Closes every session connected to the given room
+pub fn disconnect_room(connections: &Connections, room_id: i32, reason: &str) {
+    if let Ok(connections) = connections.lock() {
+        for session in connections.iter().filter(|session| session.room_id == room_id) {
+            session.addr.do_send(Disconnect { reason: reason.to_string() });
+        }
+    }
+}
+
+// Closes every session of a registered user, in any room
+pub fn disconnect_user(connections: &Connections, user_id: i32, reason: &str) {
+    if let Ok(connections) = connections.lock() {
+        for session in connections.iter().filter(|session| session.user_id == Some(user_id)) {
+            session.addr.do_send(Disconnect { reason: reason.to_string() });
         }
     }
-}
\ No newline at end of file
+}
+
+// Closes one session; false if it is no longer connected
+pub fn disconnect_session(connections: &Connections, session_id: u64, reason: &str) -> bool {
+    let Ok(connections) = connections.lock() else {
+        return false;
+    };
+    match connections.iter().find(|session| session.id == session_id) {
+        Some(session) => {
+            session.addr.do_send(Disconnect { reason: reason.to_string() });
+            true
+        }
+        None => false,
+    }
+}
+
+// Sends a server-wide announcement to every live session; returns how many got it
+pub fn broadcast_announcement(connections: &Connections, text: &str) -> usize {
+    let Ok(connections) = connections.lock() else {
+        return 0;
+    };
+    for session in connections.iter() {
+        session.addr.do_send(WsMessage::new("announcement", "system", text.to_string(), session.room_id));
+    }
+    connections.len()
+}
+
+// Rooms in which the user currently has at least one live session
+pub fn rooms_of_user(connections: &Connections, user_id: i32) -> Vec<i32> {
+    let mut rooms: Vec<i32> = match connections.lock() {
+        Ok(connections) => connections.iter()
+            .filter(|session| session.user_id == Some(user_id))
+            .map(|session| session.room_id)
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    rooms.sort_unstable();
+    rooms.dedup();
+    rooms
+}
@@ -0,0 +1,158 @@
+use actix::Message;
+use actix_web::dev::ServerHandle;
+use actix_web::rt::time::{sleep, timeout};
+use rand::Rng;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::models::session::Connections;
+
+// Clients are told to wait a random 1-5s before reconnecting, so they don't all come back at once
+const RECONNECT_MIN_MS: u64 = 1_000;
+const RECONNECT_MAX_MS: u64 = 5_000;
+
+// Asks a session to close because the server is going away
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct ServerShutdown {
+    pub retry_after_ms: u64,
+}
+
+// Shared between the shutdown sequence, the /ws route and the sessions
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    draining: Arc<AtomicBool>,
+    pending_writes: Arc<AtomicUsize>,
+}
+
+// Held while a database write started by a session is in flight
+pub struct PendingWrite(Arc<AtomicUsize>);
+
+impl Drop for PendingWrite {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shutdown {
+    // True once shutdown has started; no new sessions are accepted from then on
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn track_write(&self) -> PendingWrite {
+        self.pending_writes.fetch_add(1, Ordering::SeqCst);
+        PendingWrite(self.pending_writes.clone())
+    }
+
+    fn pending_writes(&self) -> usize {
+        self.pending_writes.load(Ordering::SeqCst)
+    }
+}
+
+// Waits for SIGTERM or Ctrl-C, then shuts down within `deadline`: stops accepting WebSocket
+// upgrades, tells every session to go away, waits for their sockets and pending writes,
+// and finally stops the HTTP server
+pub fn spawn_signal_handler(server: ServerHandle, shutdown: Shutdown, connections: Connections, deadline: Duration) {
+    actix_web::rt::spawn(async move {
+        wait_for_signal().await;
+        log::info!("Shutting down; draining WebSocket sessions (deadline {:?})", deadline);
+        let started = Instant::now();
+        drain(&shutdown, &connections, deadline).await;
+
+        // Let in-flight HTTP requests finish in whatever time is left
+        let remaining = deadline.saturating_sub(started.elapsed());
+        if timeout(remaining, server.stop(true)).await.is_err() {
+            server.stop(false).await;
+        }
+    });
+}
+
+// Tells every session to go away, then waits until their sockets are closed and pending writes
+// are flushed, or until `deadline`. Returns whether everything drained in time.
+pub async fn drain(shutdown: &Shutdown, connections: &Connections, deadline: Duration) -> bool {
+    let started = Instant::now();
+    shutdown.start_draining();
+    let sessions = notify_sessions(connections);
+    log::info!("Asked {} session(s) to reconnect later", sessions);
+
+    loop {
+        let open = connections.lock().map(|c| c.len()).unwrap_or(0);
+        let pending = shutdown.pending_writes();
+        if open == 0 && pending == 0 {
+            log::info!("All sessions closed and writes flushed after {:?}", started.elapsed());
+            return true;
+        }
+        if started.elapsed() >= deadline {
+            log::warn!("Shutdown deadline reached with {} session(s) open and {} write(s) pending", open, pending);
+            return false;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+}
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                let ctrl_c = actix_web::rt::signal::ctrl_c();
+                futures_util::future::select(Box::pin(terminate.recv()), Box::pin(ctrl_c)).await;
+                return;
+            }
+            Err(e) => log::error!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = actix_web::rt::signal::ctrl_c().await {
+        log::error!("Failed to listen for Ctrl-C: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+fn notify_sessions(connections: &Connections) -> usize {
+    let Ok(connections) = connections.lock() else {
+        return 0;
+    };
+    let mut rng = rand::thread_rng();
+    for session in connections.iter() {
+        session.addr.do_send(ServerShutdown { retry_after_ms: rng.gen_range(RECONNECT_MIN_MS..=RECONNECT_MAX_MS) });
+    }
+    connections.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[actix_web::test]
+    async fn drain_waits_for_pending_writes() {
+        let shutdown = Shutdown::default();
+        let connections: Connections = Arc::new(Mutex::new(Vec::new()));
+        let write = shutdown.track_write();
+        actix_web::rt::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            drop(write);
+        });
+
+        assert!(drain(&shutdown, &connections, Duration::from_secs(5)).await);
+        assert!(shutdown.is_draining());
+        assert_eq!(shutdown.pending_writes(), 0);
+    }
+
+    #[actix_web::test]
+    async fn drain_gives_up_at_the_deadline() {
+        let shutdown = Shutdown::default();
+        let connections: Connections = Arc::new(Mutex::new(Vec::new()));
+        let _write = shutdown.track_write();
+
+        let started = Instant::now();
+        assert!(!drain(&shutdown, &connections, Duration::from_millis(200)).await);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,285 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpRequest};
+use deadpool_postgres::Pool;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::db::models::AuthToken;
+use crate::error::AppError;
+use crate::handlers::auth::token_from_request;
+use crate::settings::{RateLimit, RateLimitSettings};
+
+const SWEEP_SECS: u64 = 60;
+// Bounds the token cache between sweeps, e.g. against floods of made-up tokens
+const MAX_CACHED_TOKENS: usize = 10_000;
+
+// Sign-up and login are limited per client IP with the stricter `auth` limit
+const AUTH_ROUTES: [&str; 2] = ["post /api/login", "post /api/users"];
+
+// Refills continuously at `per_minute` and holds at most `burst` tokens
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    per_sec: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> TokenBucket {
+        TokenBucket {
+            capacity: f64::from(limit.burst),
+            per_sec: f64::from(limit.per_minute) / 60.0,
+            tokens: f64::from(limit.burst),
+            updated: Instant::now(),
+        }
+    }
+
+    // Takes a token, or says how long until the next one is available
+    pub fn try_take(&mut self) -> Result<(), Duration> {
+        self.try_take_at(Instant::now())
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.per_sec))
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.updated = now;
+    }
+
+    // A full bucket behaves like a fresh one, so it can be forgotten
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+// The user behind a bearer token, as far as rate limiting is concerned
+#[derive(Clone, Copy)]
+struct TokenUser {
+    id: i32,
+    exempt: bool,
+}
+
+// Buckets for HTTP clients, keyed by limit name and client
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    buckets: Mutex<HashMap<(String, String), TokenBucket>>,
+    // Token lookups (None for unknown tokens), kept until the next sweep
+    token_users: Mutex<HashMap<String, Option<TokenUser>>>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> RateLimiter {
+        RateLimiter {
+            settings,
+            buckets: Mutex::new(HashMap::new()),
+            token_users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_exempt_user(&self, username: &str) -> bool {
+        self.settings.exempt_users.iter().any(|exempt| exempt == username)
+    }
+
+    // The limit for a route: an override from `routes`, `auth` for sign-up and login, `api` otherwise
+    fn limit_for(&self, route: &str) -> (String, RateLimit) {
+        match self.settings.routes.iter().find(|(key, _)| key.to_lowercase() == route) {
+            Some((key, limit)) => (key.to_lowercase(), *limit),
+            None if AUTH_ROUTES.contains(&route) => ("auth".to_string(), self.settings.auth),
+            None => ("api".to_string(), self.settings.api),
+        }
+    }
+
+    fn take(&self, name: String, client: String, limit: RateLimit) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets
+            .entry((name, client))
+            .or_insert_with(|| TokenBucket::new(limit))
+            .try_take()
+    }
+
+    async fn check_request(&self, req: &ServiceRequest) -> Result<(), Duration> {
+        if !self.settings.enabled {
+            return Ok(());
+        }
+
+        let pattern = req.match_pattern().unwrap_or_else(|| req.path().to_string());
+        let route = format!("{} {}", req.method().as_str().to_lowercase(), pattern);
+        let (name, limit) = self.limit_for(&route);
+
+        // Signed-in users get their own bucket; anonymous clients, unknown tokens and
+        // everyone on the auth routes share one per IP
+        let token = token_from_request(req.request()).filter(|_| name != "auth");
+        let user = match &token {
+            Some(token) => self.token_user(req, token).await,
+            None => None,
+        };
+        let client = match user {
+            Some(user) if user.exempt => return Ok(()),
+            Some(user) => format!("user:{}", user.id),
+            None => format!("ip:{}", self.client_ip(req.request())),
+        };
+        self.take(name, client, limit)
+    }
+
+    pub fn client_ip(&self, req: &HttpRequest) -> String {
+        // X-Forwarded-For can be forged unless a trusted proxy sets it
+        if self.settings.trust_forwarded_for {
+            if let Some(ip) = req.connection_info().realip_remote_addr() {
+                return ip.to_string();
+            }
+        }
+        req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()
+    }
+
+    async fn token_user(&self, req: &ServiceRequest, token: &str) -> Option<TokenUser> {
+        if let Some(user) = self.token_users.lock().unwrap().get(token) {
+            return *user;
+        }
+        let pool = req.app_data::<web::Data<Pool>>()?;
+        // Without a database the client is limited by IP, and the token is looked up again next time
+        let client = pool.get().await.ok()?;
+        let user = AuthToken::find_user(&client, token).await.ok()?.and_then(|user| {
+            Some(TokenUser { id: user.id?, exempt: user.is_admin || self.is_exempt_user(&user.username) })
+        });
+
+        let mut token_users = self.token_users.lock().unwrap();
+        if token_users.len() >= MAX_CACHED_TOKENS {
+            token_users.clear();
+        }
+        token_users.insert(token.to_string(), user);
+        user
+    }
+
+    // Limits for one WebSocket session, or None when it is exempt
+    pub fn session_limits(&self, exempt: bool) -> Option<SessionLimits> {
+        if !self.settings.enabled || exempt {
+            return None;
+        }
+        Some(SessionLimits {
+            messages: TokenBucket::new(self.settings.ws_messages),
+            typing: TokenBucket::new(self.settings.ws_typing),
+            strikes: 0,
+            disconnect_after: self.settings.ws_disconnect_after,
+        })
+    }
+
+    fn sweep(&self) {
+        let now = Instant::now();
+        self.buckets.lock().unwrap().retain(|_, bucket| !bucket.is_full(now));
+        // Forget cached tokens so revoked ones stop counting as their user
+        self.token_users.lock().unwrap().clear();
+    }
+}
+
+// Applied to the /api scope; over-limit requests get a 429 with Retry-After. The response is
+// built here rather than returned as an error so it still carries the request id.
+pub async fn limit_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>().cloned() {
+        if let Err(wait) = limiter.check_request(&req).await {
+            return Ok(req.error_response(AppError::TooManyRequests(wait.as_secs_f64().ceil().max(1.0) as u64)));
+        }
+    }
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
+
+// Periodically forgets idle clients
+pub fn spawn_sweeper(limiter: web::Data<RateLimiter>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(SWEEP_SECS));
+        loop {
+            interval.tick().await;
+            limiter.sweep();
+        }
+    });
+}
+
+pub enum SessionVerdict {
+    Allow,
+    // Over the limit for typing indicators, which are simply not forwarded
+    Drop,
+    Reject(Duration),
+    Disconnect,
+}
+
+// Per-session message limits. Rejected frames count as strikes; an accepted one clears them.
+pub struct SessionLimits {
+    messages: TokenBucket,
+    typing: TokenBucket,
+    strikes: u32,
+    disconnect_after: u32,
+}
+
+impl SessionLimits {
+    pub fn check(&mut self, message_type: &str) -> SessionVerdict {
+        if matches!(message_type, "typing" | "stop_typing") {
+            return match self.typing.try_take() {
+                Ok(()) => SessionVerdict::Allow,
+                Err(_) => SessionVerdict::Drop,
+            };
+        }
+        match self.messages.try_take() {
+            Ok(()) => {
+                self.strikes = 0;
+                SessionVerdict::Allow
+            }
+            Err(_) if self.strikes + 1 >= self.disconnect_after => SessionVerdict::Disconnect,
+            Err(wait) => {
+                self.strikes += 1;
+                SessionVerdict::Reject(wait)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_bursts_then_refills() {
+        let mut bucket = TokenBucket::new(RateLimit { per_minute: 60, burst: 3 });
+        let start = bucket.updated;
+        for _ in 0..3 {
+            assert!(bucket.try_take_at(start).is_ok());
+        }
+        let wait = bucket.try_take_at(start).unwrap_err();
+        assert_eq!(wait.as_millis(), 1000);
+
+        assert!(bucket.try_take_at(start + Duration::from_millis(500)).is_err());
+        assert!(bucket.try_take_at(start + Duration::from_millis(1000)).is_ok());
+        assert!(bucket.is_full(start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn sessions_are_disconnected_after_repeated_rejections() {
+        let limit = RateLimit { per_minute: 1, burst: 1 };
+        let mut limits = SessionLimits {
+            messages: TokenBucket::new(limit),
+            typing: TokenBucket::new(limit),
+            strikes: 0,
+            disconnect_after: 3,
+        };
+        assert!(matches!(limits.check("chat"), SessionVerdict::Allow));
+        assert!(matches!(limits.check("typing"), SessionVerdict::Allow));
+        assert!(matches!(limits.check("typing"), SessionVerdict::Drop));
+        assert!(matches!(limits.check("chat"), SessionVerdict::Reject(_)));
+        assert!(matches!(limits.check("chat"), SessionVerdict::Reject(_)));
+        assert!(matches!(limits.check("chat"), SessionVerdict::Disconnect));
+    }
+}
@@ -0,0 +1,296 @@
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_postgres::{Client, Error};
+use crate::db::models::{Message, Room};
+use crate::settings::{RetentionMode, RetentionSettings};
+use crate::storage::Storage;
+
+// What one prune removed
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct PruneOutcome {
+    pub messages: u64,
+    // Attachment blobs removed from storage; nothing is removed when archiving
+    pub files_deleted: u64,
+}
+
+// The time `days` days ago; None when that is before the earliest time chrono can represent
+pub fn cutoff(days: i64) -> Option<DateTime<Utc>> {
+    chrono::Duration::try_days(days).and_then(|age| Utc::now().checked_sub_signed(age))
+}
+
+// Deletes or archives, as `settings.mode` says, the messages sent before `before` in one room or
+// everywhere. Works through them `batch_size` at a time so no statement holds locks for long.
+pub async fn prune(
+    client: &Client,
+    storage: &dyn Storage,
+    before: DateTime<Utc>,
+    room_id: Option<i32>,
+    settings: &RetentionSettings,
+) -> Result<PruneOutcome, Error> {
+    let batch_size = i64::from(settings.batch_size);
+    let archive = settings.mode == RetentionMode::Archive;
+    let mut outcome = PruneOutcome::default();
+    loop {
+        let pruned = Message::prune(client, before, room_id, batch_size, archive).await?;
+        outcome.messages += pruned.messages;
+        for key in &pruned.storage_keys {
+            match storage.delete(key).await {
+                Ok(()) => outcome.files_deleted += 1,
+                Err(e) => log::error!("Failed to delete attachment {}: {}", key, e),
+            }
+        }
+        if pruned.messages < batch_size as u64 {
+            return Ok(outcome);
+        }
+    }
+}
+
+// One pass of the pruning task
+#[derive(Serialize, Debug, Clone)]
+pub struct RetentionRun {
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    // Rooms with a retention period, whether or not anything in them had expired
+    pub rooms_checked: usize,
+    pub messages: u64,
+    pub files_deleted: u64,
+    // Messages removed per room, for rooms where any were
+    pub by_room: BTreeMap<i32, u64>,
+    // The pass stopped early; what it removed before that still counts
+    pub error: Option<String>,
+}
+
+// Totals since the server started, for GET /api/admin/retention
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RetentionStats {
+    pub runs: u64,
+    pub failed_runs: u64,
+    pub messages_deleted: u64,
+    pub messages_archived: u64,
+    pub files_deleted: u64,
+    pub last_run: Option<RetentionRun>,
+}
+
+#[derive(Clone, Default)]
+pub struct RetentionMetrics {
+    stats: Arc<Mutex<RetentionStats>>,
+}
+
+impl RetentionMetrics {
+    pub fn snapshot(&self) -> RetentionStats {
+        self.stats.lock().map(|stats| stats.clone()).unwrap_or_default()
+    }
+
+    fn record(&self, mode: RetentionMode, run: RetentionRun) {
+        let Ok(mut stats) = self.stats.lock() else {
+            return;
+        };
+        stats.runs += 1;
+        if run.error.is_some() {
+            stats.failed_runs += 1;
+        }
+        match mode {
+            RetentionMode::Delete => stats.messages_deleted += run.messages,
+            RetentionMode::Archive => stats.messages_archived += run.messages,
+        }
+        stats.files_deleted += run.files_deleted;
+        stats.last_run = Some(run);
+    }
+}
+
+// Prunes every room with a retention period, room by room
+async fn run_once(pool: &Pool, storage: &dyn Storage, settings: &RetentionSettings) -> RetentionRun {
+    let started = Instant::now();
+    let mut run = RetentionRun {
+        started_at: Utc::now(),
+        duration_ms: 0,
+        rooms_checked: 0,
+        messages: 0,
+        files_deleted: 0,
+        by_room: BTreeMap::new(),
+        error: None,
+    };
+
+    let result: Result<(), String> = async {
+        let client = pool.get().await.map_err(|e| e.to_string())?;
+        let default_days = settings.default_days.map(|days| i32::try_from(days).unwrap_or(i32::MAX));
+        let periods = Room::retention_periods(&client, default_days).await.map_err(|e| e.to_string())?;
+        run.rooms_checked = periods.len();
+        for (room_id, days) in periods {
+            // Nothing can be older than that
+            let Some(before) = cutoff(days.into()) else {
+                continue;
+            };
+            let outcome = prune(&client, storage, before, Some(room_id), settings)
+                .await
+                .map_err(|e| format!("room {}: {}", room_id, e))?;
+            run.messages += outcome.messages;
+            run.files_deleted += outcome.files_deleted;
+            if outcome.messages > 0 {
+                run.by_room.insert(room_id, outcome.messages);
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    run.error = result.err();
+    run.duration_ms = started.elapsed().as_millis() as u64;
+    run
+}
+
+// Periodically removes messages older than their room's retention period
+pub fn spawn_pruner(pool: Pool, storage: Arc<dyn Storage>, settings: RetentionSettings, metrics: RetentionMetrics) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(settings.interval_secs));
+        // A slow pass pushes the next one back instead of starting a burst of catch-up passes
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let run = run_once(&pool, storage.as_ref(), &settings).await;
+            match &run.error {
+                Some(e) => log::error!("Message retention pass failed: {}", e),
+                None if run.messages > 0 => log::info!(
+                    "Message retention: {} {} messages from {} rooms in {} ms",
+                    if settings.mode == RetentionMode::Archive { "archived" } else { "deleted" },
+                    run.messages,
+                    run.by_room.len(),
+                    run.duration_ms
+                ),
+                None => {}
+            }
+            metrics.record(settings.mode, run);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::Attachment;
+    use crate::handlers::test_support;
+    use crate::storage::local::LocalStorage;
+
+    fn run(messages: u64, files_deleted: u64, error: Option<&str>) -> RetentionRun {
+        RetentionRun {
+            started_at: Utc::now(),
+            duration_ms: 0,
+            rooms_checked: 1,
+            messages,
+            files_deleted,
+            by_room: BTreeMap::new(),
+            error: error.map(str::to_string),
+        }
+    }
+
+    // A room with `old` messages from two years ago and one from today
+    async fn room_with_history(pool: &Pool, old: usize) -> (i32, Vec<i32>) {
+        let (user_id, _) = test_support::user(pool, "author").await;
+        let room_id = test_support::room(pool, user_id, "public").await;
+        let mut ids = Vec::new();
+        for i in 0..old {
+            ids.push(test_support::message(pool, room_id, user_id, &format!("old {}", i)).await);
+        }
+        let client = pool.get().await.unwrap();
+        client
+            .execute("UPDATE messages SET created_at = NOW() - INTERVAL '2 years' WHERE id = ANY($1)", &[&ids])
+            .await
+            .unwrap();
+        test_support::message(pool, room_id, user_id, "new").await;
+        (room_id, ids)
+    }
+
+    async fn remaining(pool: &Pool, room_id: i32) -> Vec<String> {
+        let client = pool.get().await.unwrap();
+        let rows = client.query("SELECT content FROM messages WHERE room_id = $1", &[&room_id]).await.unwrap();
+        rows.iter().map(|row| row.get(0)).collect()
+    }
+
+    #[test]
+    fn cutoffs_out_of_range_are_none() {
+        assert!(cutoff(36_500).is_some());
+        assert!(cutoff(i32::MAX.into()).is_none());
+        assert!(cutoff(i64::MAX).is_none());
+    }
+
+    #[test]
+    fn metrics_count_deletes_and_archives_apart() {
+        let metrics = RetentionMetrics::default();
+        metrics.record(RetentionMode::Delete, run(3, 2, None));
+        metrics.record(RetentionMode::Archive, run(5, 0, Some("room 1: connection reset")));
+
+        let stats = metrics.snapshot();
+        assert_eq!((stats.runs, stats.failed_runs), (2, 1));
+        assert_eq!((stats.messages_deleted, stats.messages_archived, stats.files_deleted), (3, 5, 2));
+        assert_eq!(stats.last_run.unwrap().messages, 5);
+    }
+
+    #[actix_web::test]
+    async fn pruning_works_through_every_batch() {
+        let Some(pool) = test_support::pool().await else { return };
+        let storage = LocalStorage::new(std::env::temp_dir().join("chat-backend-tests")).unwrap();
+        let (room_id, _) = room_with_history(&pool, 5).await;
+        let settings = RetentionSettings { batch_size: 2, ..RetentionSettings::default() };
+
+        let client = pool.get().await.unwrap();
+        let before = cutoff(365).unwrap();
+        let outcome = prune(&client, &storage, before, Some(room_id), &settings).await.unwrap();
+        assert_eq!(outcome.messages, 5);
+        assert_eq!(remaining(&pool, room_id).await, ["new"]);
+
+        let outcome = prune(&client, &storage, before, Some(room_id), &settings).await.unwrap();
+        assert_eq!(outcome.messages, 0);
+    }
+
+    #[actix_web::test]
+    async fn archiving_replaces_earlier_copies() {
+        let Some(pool) = test_support::pool().await else { return };
+        let storage = LocalStorage::new(std::env::temp_dir().join("chat-backend-tests")).unwrap();
+        let (room_id, ids) = room_with_history(&pool, 3).await;
+        let settings = RetentionSettings { batch_size: 2, mode: RetentionMode::Archive, ..RetentionSettings::default() };
+
+        let client = pool.get().await.unwrap();
+        client
+            .execute(
+                "INSERT INTO messages_archive (id, room_id, content) VALUES ($1, $2, 'stale copy')",
+                &[&ids[0], &room_id],
+            )
+            .await
+            .unwrap();
+        let row = client
+            .query_one(
+                "INSERT INTO attachments (room_id, uploader_id, message_id, file_name, content_type, size_bytes, storage_key)
+                 SELECT room_id, sender_id, id, 'a.txt', 'text/plain', 1, 'archived-test-key' FROM messages WHERE id = $1
+                 RETURNING id",
+                &[&ids[1]],
+            )
+            .await
+            .unwrap();
+        let attachment_id: i32 = row.get(0);
+        let outcome = prune(&client, &storage, cutoff(365).unwrap(), Some(room_id), &settings).await.unwrap();
+        assert_eq!(outcome.messages, 3);
+        assert_eq!(remaining(&pool, room_id).await, ["new"]);
+
+        let rows = client
+            .query("SELECT content FROM messages_archive WHERE id = ANY($1) ORDER BY id", &[&ids])
+            .await
+            .unwrap();
+        let archived: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+        assert_eq!(archived, ["old 0", "old 1", "old 2"]);
+        let row = client
+            .query_one("SELECT message_id, archived_message_id FROM attachments WHERE id = $1", &[&attachment_id])
+            .await
+            .unwrap();
+        assert_eq!((row.get::<_, Option<i32>>(0), row.get::<_, Option<i32>>(1)), (None, Some(ids[1])));
+        // and can't be attached to a new message
+        let row = client.query_one("SELECT sender_id FROM messages_archive WHERE id = $1", &[&ids[1]]).await.unwrap();
+        let sender: i32 = row.get(0);
+        let new = test_support::message(&pool, room_id, sender, "reuse").await;
+        let linked = Attachment::link_to_message(&client, &[attachment_id], new, room_id, sender).await.unwrap();
+        assert!(linked.is_empty());
+    }
+}
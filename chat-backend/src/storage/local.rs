@@ -0,0 +1,107 @@
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures_util::stream;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+use super::{BlobStream, Storage};
+
+// Bytes read from disk at a time when streaming a file
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Stores attachments as plain files below a root directory
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<LocalStorage> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        Ok(LocalStorage { root })
+    }
+
+    // Keys are generated server-side, but never let one escape the root
+    fn path_for(&self, key: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(key);
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid storage key"));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, source: &Path, _content_type: &str) -> io::Result<()> {
+        let target = self.path_for(key)?;
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Rename is cheap when the temp dir shares a filesystem; fall back to copying
+        if tokio::fs::rename(source, &target).await.is_err() {
+            tokio::fs::copy(source, &target).await?;
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> io::Result<BlobStream> {
+        let file = tokio::fs::File::open(self.path_for(key)?).await?;
+        Ok(Box::pin(stream::try_unfold(file, |mut file| async move {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let read = file.read(&mut chunk).await?;
+            chunk.truncate(read);
+            Ok((read > 0).then(|| (Bytes::from(chunk), file)))
+        })))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path_for(key)?).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::TryStreamExt;
+
+    fn scratch_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("chat-storage-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[actix_web::test]
+    async fn round_trips_files_larger_than_a_chunk() {
+        let root = scratch_dir();
+        let storage = LocalStorage::new(root.join("blobs")).unwrap();
+        let source = root.join("upload");
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 17).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+
+        storage.put("attachments/a", &source, "application/octet-stream").await.unwrap();
+        assert!(!source.exists());
+        let chunks: Vec<Bytes> = storage.get("attachments/a").await.unwrap().try_collect().await.unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.concat(), data);
+
+        storage.delete("attachments/a").await.unwrap();
+        assert_eq!(storage.get("attachments/a").await.err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+        // Deleting twice is not an error
+        storage.delete("attachments/a").await.unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn keys_cannot_leave_the_root() {
+        let root = scratch_dir();
+        let storage = LocalStorage::new(&root).unwrap();
+        for key in ["../outside", "/etc/passwd", "a/../../b"] {
+            assert_eq!(storage.get(key).await.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+            assert_eq!(storage.delete(key).await.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
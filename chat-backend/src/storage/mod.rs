@@ -0,0 +1,69 @@
+pub mod local;
+pub mod s3;
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures_util::Stream;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use self::local::LocalStorage;
+use self::s3::S3Storage;
+use crate::settings::{StorageSettings, UploadSettings};
+
+// A stored blob, read in chunks as it is sent on so large files are never held in memory
+pub type BlobStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+// Backend-agnostic blob storage for uploaded attachments
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // Stores the file at `source` under `key`; the source file is consumed
+    async fn put(&self, key: &str, source: &Path, content_type: &str) -> io::Result<()>;
+    // Fails with NotFound before streaming anything if there is no such blob
+    async fn get(&self, key: &str) -> io::Result<BlobStream>;
+    async fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+// Limits and scratch space used while receiving uploads
+#[derive(Clone, Debug)]
+pub struct UploadConfig {
+    pub max_bytes: u64,
+    pub temp_dir: PathBuf,
+}
+
+impl From<&UploadSettings> for UploadConfig {
+    fn from(settings: &UploadSettings) -> Self {
+        UploadConfig {
+            max_bytes: settings.max_bytes,
+            temp_dir: settings.temp_dir.clone(),
+        }
+    }
+}
+
+// Builds the configured backend ("local" or "s3")
+pub fn from_settings(settings: &StorageSettings) -> io::Result<Arc<dyn Storage>> {
+    match settings.backend.as_str() {
+        "local" => Ok(Arc::new(LocalStorage::new(&settings.local_dir)?)),
+        "s3" => {
+            let s3 = &settings.s3;
+            let required = |name: &str, value: &Option<String>| {
+                value.clone().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("storage.s3.{} must be set for the s3 storage backend", name))
+                })
+            };
+            Ok(Arc::new(S3Storage::new(
+                &required("endpoint", &s3.endpoint)?,
+                required("bucket", &s3.bucket)?,
+                s3.region.clone(),
+                required("access_key", &s3.access_key)?,
+                required("secret_key", &s3.secret_key)?,
+            )?))
+        }
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown storage backend: {}", other),
+        )),
+    }
+}
@@ -12,6 +12,7 @@ function App() {
   );
   const [username, setUsername] = useState(localStorage.getItem("username") || "");
   const [userId, setUserId] = useState(parseInt(localStorage.getItem("userId") || "0", 10) || null);
+  const [token, setToken] = useState(localStorage.getItem("token") || "");
   const [selectedRoom, setSelectedRoom] = useState(null);
   const [registrationUsername, setRegistrationUsername] = useState("");
   const [error, setError] = useState("");
@@ -61,9 +62,11 @@ function App() {
         // Save user information to local storage
         localStorage.setItem("username", registrationUsername.trim());
         localStorage.setItem("userId", data.data.id.toString());
+        localStorage.setItem("token", data.data.token);
         
         setUsername(registrationUsername.trim());
         setUserId(data.data.id);
+        setToken(data.data.token);
         setRegistrationUsername("");
       } else {
         setError(data.message || "Failed to register");
@@ -79,8 +82,10 @@ function App() {
   const handleLogout = () => {
     localStorage.removeItem("username");
     localStorage.removeItem("userId");
+    localStorage.removeItem("token");
     setUsername("");
     setUserId(null);
+    setToken("");
     setSelectedRoom(null);
   };
 
@@ -93,7 +98,7 @@ function App() {
   };
 
   // If user not registered, show registration form
-  if (!username || !userId) {
+  if (!username || !userId || !token) {
     return (
       <ThemeProvider theme={theme}>
         <CssBaseline />
@@ -206,7 +211,7 @@ function App() {
           
           <RoomList 
             username={username}
-            userId={userId}
+            token={token}
             onSelectRoom={handleRoomSelect}
           />
         </Box>
@@ -221,6 +226,7 @@ function App() {
       <Chat 
         toggleTheme={toggleTheme} 
         username={username}
+        token={token}
         roomId={selectedRoom.id}
         roomName={selectedRoom.name}
         onBackToRooms={handleBackToRooms}
@@ -24,7 +24,7 @@ import {
   UserChip,
 } from "./StyledComponents";
 
-function Chat({ toggleTheme, username, roomId, roomName, onBackToRooms }) {
+function Chat({ toggleTheme, username, token, roomId, roomName, onBackToRooms }) {
   const [messages, setMessages] = useState([]);
   const [messageInput, setMessageInput] = useState("");
   const [connected, setConnected] = useState(false);
@@ -47,9 +47,9 @@ function Chat({ toggleTheme, username, roomId, roomName, onBackToRooms }) {
         ? "wss://mismatch-production.up.railway.app"
         : "ws://127.0.0.1:8080";
 
-    // Connect to WebSocket with username and roomId
+    // Connect to WebSocket with the session token and roomId
     wsRef.current = new WebSocket(
-      `${WS_URL}/ws?username=${encodeURIComponent(username)}&roomId=${roomId}`
+      `${WS_URL}/ws?token=${encodeURIComponent(token)}&roomId=${roomId}`
     );
 
     wsRef.current.onopen = () => {
@@ -122,7 +122,9 @@ function Chat({ toggleTheme, username, roomId, roomName, onBackToRooms }) {
           ? "https://mismatch-production.up.railway.app/api"
           : "http://localhost:8080/api";
         
-        const response = await fetch(`${API_URL}/rooms/${roomId}/messages`);
+        const response = await fetch(`${API_URL}/rooms/${roomId}/messages`, {
+          headers: { Authorization: `Bearer ${token}` }
+        });
         const data = await response.json();
         
         if (data.success && data.data) {
@@ -145,7 +147,7 @@ function Chat({ toggleTheme, username, roomId, roomName, onBackToRooms }) {
         wsRef.current.close();
       }
     };
-  }, [username, roomId]);
+  }, [username, token, roomId]);
 
   const handleTyping = () => {
     if (wsRef.current) {
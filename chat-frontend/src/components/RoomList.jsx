@@ -28,7 +28,7 @@ const API_URL = process.env.NODE_ENV === 'production'
   ? 'https://mismatch-production.up.railway.app/api'
   : 'http://localhost:8080/api';
 
-function RoomList({ username, userId, onSelectRoom }) {
+function RoomList({ username, token, onSelectRoom }) {
   const [rooms, setRooms] = useState([]);
   const [createDialogOpen, setCreateDialogOpen] = useState(false);
   const [joinDialogOpen, setJoinDialogOpen] = useState(false);
@@ -53,7 +53,7 @@ function RoomList({ username, userId, onSelectRoom }) {
       const data = await response.json();
       
       if (data.success) {
-        setRooms(data.data || []);
+        setRooms(data.data?.items || []);
       } else {
         setError(data.message || 'Failed to fetch rooms');
       }
@@ -72,10 +72,11 @@ function RoomList({ username, userId, onSelectRoom }) {
     setError(null);
     
     try {
-      const response = await fetch(`${API_URL}/rooms/create/${userId}`, {
+      const response = await fetch(`${API_URL}/rooms`, {
         method: 'POST',
         headers: {
-          'Content-Type': 'application/json'
+          'Content-Type': 'application/json',
+          'Authorization': `Bearer ${token}`
         },
         body: JSON.stringify({
           name: newRoomName.trim(),
@@ -117,10 +118,10 @@ function RoomList({ username, userId, onSelectRoom }) {
       const response = await fetch(`${API_URL}/rooms/join`, {
         method: 'POST',
         headers: {
-          'Content-Type': 'application/json'
+          'Content-Type': 'application/json',
+          'Authorization': `Bearer ${token}`
         },
         body: JSON.stringify({
-          user_id: userId,
           room_id: selectedRoom.id,
           password: selectedRoom.room_type === 'protected' ? joinPassword : null
         })
@@ -162,10 +163,10 @@ function RoomList({ username, userId, onSelectRoom }) {
       const response = await fetch(`${API_URL}/rooms/join`, {
         method: 'POST',
         headers: {
-          'Content-Type': 'application/json'
+          'Content-Type': 'application/json',
+          'Authorization': `Bearer ${token}`
         },
         body: JSON.stringify({
-          user_id: userId,
           room_id: room.id
         })
       });